@@ -0,0 +1,22 @@
+-- Allow the 'audit' permission which grants read access to the listing endpoints.
+-- SQLite cannot alter a CHECK constraint, so the permissions table is rebuilt.
+-- Dropping it cascades into user_permissions, so the grants are kept aside and restored.
+CREATE TABLE user_permissions_backup AS SELECT * FROM user_permissions;
+
+CREATE TABLE permissions_new (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    subject    TEXT NOT NULL,
+    permission TEXT NOT NULL CHECK(permission IN ('pull','push','audit')),
+    UNIQUE(subject, permission)
+);
+
+INSERT INTO permissions_new (id, subject, permission)
+SELECT id, subject, permission FROM permissions;
+
+DROP TABLE permissions;
+ALTER TABLE permissions_new RENAME TO permissions;
+
+INSERT INTO user_permissions (user_id, permission_id)
+SELECT user_id, permission_id FROM user_permissions_backup;
+
+DROP TABLE user_permissions_backup;
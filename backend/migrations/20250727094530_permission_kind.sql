@@ -0,0 +1,23 @@
+-- Permissions carry the docker resource type they apply to (repository, plugin, ...).
+-- The unique constraint has to include the kind, so the permissions table is rebuilt
+-- the same way as for the audit permission, keeping the grants aside meanwhile.
+CREATE TABLE user_permissions_backup AS SELECT * FROM user_permissions;
+
+CREATE TABLE permissions_new (
+    id         INTEGER PRIMARY KEY AUTOINCREMENT,
+    kind       TEXT NOT NULL DEFAULT 'repository',
+    subject    TEXT NOT NULL,
+    permission TEXT NOT NULL CHECK(permission IN ('pull','push','audit')),
+    UNIQUE(kind, subject, permission)
+);
+
+INSERT INTO permissions_new (id, subject, permission)
+SELECT id, subject, permission FROM permissions;
+
+DROP TABLE permissions;
+ALTER TABLE permissions_new RENAME TO permissions;
+
+INSERT INTO user_permissions (user_id, permission_id)
+SELECT user_id, permission_id FROM user_permissions_backup;
+
+DROP TABLE user_permissions_backup;
@@ -0,0 +1,7 @@
+-- Tokens carry the epoch of their user, bumping it invalidates every token issued before.
+-- Users without a row are at epoch 0.
+CREATE TABLE token_epochs (
+    user_id INTEGER PRIMARY KEY,
+    epoch   INTEGER NOT NULL DEFAULT 0,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
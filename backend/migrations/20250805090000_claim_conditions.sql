@@ -0,0 +1,11 @@
+-- Conditions on the OIDC claims a service account's CI job must present to use an action.
+-- Every condition of an action has to hold, actions without conditions are unaffected.
+CREATE TABLE claim_conditions (
+    id          INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id     INTEGER NOT NULL,
+    permission  TEXT NOT NULL CHECK (permission IN ('pull', 'push')),
+    claim       TEXT NOT NULL,
+    pattern     TEXT NOT NULL,
+    UNIQUE(user_id, permission, claim, pattern),
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,3 @@
+-- An optional stable id of a user, e.g. from an IdP, that TOKEN_SUBJECT=external_id puts in `sub`.
+ALTER TABLE users ADD COLUMN external_id TEXT;
+CREATE UNIQUE INDEX users_external_id ON users(external_id);
@@ -0,0 +1,7 @@
+-- Pre-shared API keys of service accounts for CI systems without OIDC.
+-- Only the argon2 hash is stored, at most one key per service account.
+CREATE TABLE svc_api_keys (
+    user_id  INTEGER PRIMARY KEY,
+    key_hash TEXT NOT NULL,
+    FOREIGN KEY(user_id) REFERENCES users(id) ON DELETE CASCADE
+);
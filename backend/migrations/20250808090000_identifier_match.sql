@@ -0,0 +1,5 @@
+-- How an identifier is compared to the OIDC claim: exact, prefix (`owner/*`) or regex.
+-- Stored `owner/*` wildcards keep matching every repository of the owner.
+ALTER TABLE user_identifiers ADD COLUMN match_strategy TEXT NOT NULL DEFAULT 'exact'
+    CHECK (match_strategy IN ('exact', 'prefix', 'regex'));
+UPDATE user_identifiers SET match_strategy = 'prefix' WHERE identifier LIKE '%/*';
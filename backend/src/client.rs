@@ -0,0 +1,71 @@
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::routes::user::{
+    AddIdentifierBody, AddIdentifierResponse, CreateServiceAccountBody,
+    CreateServiceAccountResponse, CreateUserBody, CreateUserResponse, GrantAccessBody,
+    GrantAccessResponse,
+};
+
+// Typed client for the admin api, authenticates with basic auth like docker does
+#[derive(Debug, Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    name: String,
+    password: String,
+}
+
+impl Client {
+    pub fn new(base_url: &str, name: &str, password: &str) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            name: name.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    pub async fn create_user(&self, body: &CreateUserBody) -> crate::Result<CreateUserResponse> {
+        self.post("/api/user", body).await
+    }
+
+    pub async fn grant_access(&self, body: &GrantAccessBody) -> crate::Result<GrantAccessResponse> {
+        self.post("/api/user/access", body).await
+    }
+
+    pub async fn create_service_account(
+        &self,
+        body: &CreateServiceAccountBody,
+    ) -> crate::Result<CreateServiceAccountResponse> {
+        self.post("/api/service_account", body).await
+    }
+
+    pub async fn add_identifier(
+        &self,
+        body: &AddIdentifierBody,
+    ) -> crate::Result<AddIdentifierResponse> {
+        self.post("/api/service_account/identifier", body).await
+    }
+
+    async fn post<B: Serialize, R: DeserializeOwned>(
+        &self,
+        path: &str,
+        body: &B,
+    ) -> crate::Result<R> {
+        let response = self
+            .http
+            .post(format!("{}{path}", self.base_url))
+            .basic_auth(&self.name, Some(&self.password))
+            .json(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let text = response.text().await?;
+            return Err(crate::Error::Any(format!("{status}: {text}")));
+        }
+
+        Ok(response.json().await?)
+    }
+}
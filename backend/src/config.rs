@@ -0,0 +1,821 @@
+use std::{collections::HashMap, time::Duration};
+
+use axum::http::{HeaderName, HeaderValue};
+
+use crate::{
+    extractors::IdentifierClaim,
+    models::permission::{Permission, PermissionType, normalize_subject},
+    routes::token::DEFAULT_RESOURCE_TYPES,
+    server::{ServerConfig, TlsFiles},
+};
+
+// What the `sub` claim of registry tokens carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenSubject {
+    Name,
+    // users without an external id still get their name
+    ExternalId,
+}
+
+impl TokenSubject {
+    pub fn from_env_value(value: &str) -> crate::Result<Self> {
+        match value {
+            "name" => Ok(Self::Name),
+            "external_id" => Ok(Self::ExternalId),
+            _ => Err(crate::Error::Opaque("Unknown TOKEN_SUBJECT")),
+        }
+    }
+}
+
+// What /api/token does with requested scopes the user is not granted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnauthorizedScopes {
+    // no token at all
+    Deny,
+    // a token with the granted actions, like lenient registries
+    Drop,
+}
+
+impl UnauthorizedScopes {
+    pub fn from_env_value(value: &str) -> crate::Result<Self> {
+        match value {
+            "deny" => Ok(Self::Deny),
+            "drop" => Ok(Self::Drop),
+            _ => Err(crate::Error::Opaque("Unknown UNAUTHORIZED_SCOPES")),
+        }
+    }
+}
+
+// Every setting of augesty, read from the env and optionally a TOML file at CONFIG_FILE
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_path: String,
+    pub database_key: Option<String>,
+    pub own_url: String,
+    // every registry tokens can be requested for, comma separated in DOCKER_URL
+    pub docker_urls: Vec<String>,
+    // in minutes
+    pub token_duration: u64,
+    // in minutes, the longest lifetime a client may request with requested_ttl
+    pub max_token_duration: u64,
+    pub request_timeout: Duration,
+    pub argon_concurrency: usize,
+    pub argon_algorithm: argon2::Algorithm,
+    pub argon_version: argon2::Version,
+    // memory, iterations and parallelism, weaker hashes are upgraded on login
+    pub argon_params: argon2::Params,
+    // in bytes, longer passwords are rejected before hashing
+    pub max_password_length: usize,
+    // per service account, bounds the identifiers identify has to match against
+    pub max_identifiers: usize,
+    pub oidc_claim: IdentifierClaim,
+    pub oidc_debug: bool,
+    // serves /api/metrics without authentication
+    pub metrics: bool,
+    // hashes once and fetches the JWKS at startup instead of on the first login
+    pub warm_up: bool,
+    pub pull_implies_catalog: bool,
+    pub push_implies_pull: bool,
+    // compare and store repository subjects lowercased
+    pub normalize_subjects: bool,
+    // a grant on `org` also covers `org/team/service`
+    pub subject_inheritance: bool,
+    pub svc_issuer: String,
+    pub svc_audience: String,
+    pub token_subject: TokenSubject,
+    pub unauthorized_scopes: UnauthorizedScopes,
+    // aud of registry tokens, defaults to the requested service
+    pub token_audience: Option<String>,
+    pub iat_backdate: u64,
+    pub base_path: String,
+    pub swagger_path: Option<String>,
+    pub webhook_url: Option<String>,
+    // opt-in, lets admins sync service account identifiers from a GitHub org
+    pub github_org_sync: bool,
+    pub github_api_url: String,
+    pub jwks_ttl: Duration,
+    pub allowed_actions: Vec<PermissionType>,
+    // resource types a scope may name, e.g. `repository`
+    pub resource_types: Vec<String>,
+    // repository grants every new service account starts with
+    pub default_permissions: Vec<Permission>,
+    pub write_cert_file: bool,
+    // where release builds put a generated admin password instead of the logs
+    pub admin_password_file: String,
+    // rejects every mutating admin call, tokens are still issued
+    pub read_only: bool,
+    // cert of the signing instance, when set augesty only verifies tokens
+    pub verify_only_cert: Option<String>,
+    // the signing key itself instead of one generated at startup, e.g. from a secrets manager
+    pub jwt_private_key_pem: Option<String>,
+    // PEM file with intermediate and CA certs served after the leaf
+    pub cert_chain: Option<String>,
+    // added to every response, see SECURITY_HEADERS
+    pub security_headers: Vec<(HeaderName, HeaderValue)>,
+    // realm of the Basic challenge on 401s of the admin api
+    pub basic_realm: String,
+    // extra claims of docker tokens, `{user}` in a value is replaced with the user name
+    pub custom_claims: Vec<(String, String)>,
+    // catalog_max claim of tokens for `registry:catalog:*`
+    pub catalog_max: Option<u64>,
+    pub server: ServerConfig,
+}
+
+const DEFAULT_SECURITY_HEADERS: &str =
+    "x-content-type-options=nosniff,x-frame-options=DENY,referrer-policy=no-referrer";
+
+// Splits `name=value` pairs separated by commas, an empty string yields no pairs
+fn parse_pairs(value: &str, error: &'static str) -> crate::Result<Vec<(String, String)>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (name, value) = pair.split_once('=').ok_or(crate::Error::Opaque(error))?;
+            Ok((name.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn parse_headers(headers: &str) -> crate::Result<Vec<(HeaderName, HeaderValue)>> {
+    parse_pairs(headers, "Error parsing SECURITY_HEADERS")?
+        .into_iter()
+        .map(|(name, value)| {
+            let name = HeaderName::try_from(name)
+                .map_err(|_| crate::Error::Opaque("Invalid header name in SECURITY_HEADERS"))?;
+            let value = HeaderValue::try_from(value)
+                .map_err(|_| crate::Error::Opaque("Invalid header value in SECURITY_HEADERS"))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+// Registered claims and the ones augesty sets itself cannot be overwritten, a custom `epoch`
+// would let a token outlive expire_tokens
+const RESERVED_CLAIMS: [&str; 10] = [
+    "access",
+    "epoch",
+    "iss",
+    "sub",
+    "aud",
+    "exp",
+    "nbf",
+    "iat",
+    "jti",
+    "catalog_max",
+];
+
+fn parse_custom_claims(claims: &str) -> crate::Result<Vec<(String, String)>> {
+    let claims = parse_pairs(claims, "Error parsing TOKEN_CUSTOM_CLAIMS")?;
+    if claims
+        .iter()
+        .any(|(name, _)| RESERVED_CLAIMS.contains(&name.as_str()))
+    {
+        return Err(crate::Error::Opaque(
+            "TOKEN_CUSTOM_CLAIMS cannot overwrite registered claims",
+        ));
+    }
+    Ok(claims)
+}
+
+// `subject:action` pairs, checked like a grant so a typo fails at startup
+fn parse_default_permissions(
+    permissions: &str,
+    allowed: &[PermissionType],
+    lowercase: bool,
+) -> crate::Result<Vec<Permission>> {
+    permissions
+        .split(',')
+        .map(str::trim)
+        .filter(|permission| !permission.is_empty())
+        .map(|permission| {
+            let (subject, action) = permission
+                .rsplit_once(':')
+                .filter(|(subject, _)| !subject.is_empty())
+                .ok_or(crate::Error::Opaque("Error parsing DEFAULT_PERMISSIONS"))?;
+            let action = PermissionType::from_actions(action)
+                .ok()
+                .filter(|action| *action != PermissionType::Wildcard && allowed.contains(action))
+                .ok_or(crate::Error::Opaque(
+                    "DEFAULT_PERMISSIONS can only grant allowed pull or push",
+                ))?;
+            Ok(Permission {
+                id: None,
+                kind: "repository".to_string(),
+                subject: normalize_subject(subject, lowercase),
+                permission: action,
+            })
+        })
+        .collect()
+}
+
+// Looks a key up in the env first and falls back to the file, where keys are lowercase
+struct Source {
+    env: fn(&str) -> Option<String>,
+    file: HashMap<String, String>,
+}
+
+impl Source {
+    fn load() -> crate::Result<Self> {
+        let file = match std::env::var("CONFIG_FILE") {
+            Ok(path) => parse_file(&std::fs::read_to_string(path)?)?,
+            Err(_) => HashMap::new(),
+        };
+
+        Ok(Self {
+            env: |key| std::env::var(key).ok(),
+            file,
+        })
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        (self.env)(key).or_else(|| self.file.get(&key.to_lowercase()).cloned())
+    }
+
+    fn require(&self, key: &str) -> crate::Result<String> {
+        self.get(key)
+            .ok_or(crate::Error::MissingEnvVar(std::env::VarError::NotPresent))
+    }
+
+    fn flag(&self, key: &str, default: bool) -> bool {
+        self.get(key).map_or(default, |value| value == "true")
+    }
+
+    fn parse<T: std::str::FromStr>(
+        &self,
+        key: &str,
+        default: T,
+        error: &'static str,
+    ) -> crate::Result<T> {
+        match self.get(key) {
+            Some(value) => value.parse::<T>().map_err(|_| crate::Error::Opaque(error)),
+            None => Ok(default),
+        }
+    }
+}
+
+// Flattens the top level of a TOML file into strings so it reads like the env
+fn parse_file(content: &str) -> crate::Result<HashMap<String, String>> {
+    let table: toml::Table = content
+        .parse()
+        .map_err(|_| crate::Error::Opaque("Error parsing CONFIG_FILE"))?;
+
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                toml::Value::Array(values) => values
+                    .into_iter()
+                    .map(|value| match value {
+                        toml::Value::String(value) => Ok(value),
+                        _ => Err(crate::Error::Opaque("Only string lists are supported")),
+                    })
+                    .collect::<crate::Result<Vec<_>>>()?
+                    .join(","),
+                _ => return Err(crate::Error::Opaque("Unsupported value in CONFIG_FILE")),
+            };
+            Ok((key.to_lowercase(), value))
+        })
+        .collect()
+}
+
+// Only what --migrate-only needs, an init container does not have to carry the whole config
+pub struct DatabaseConfig {
+    pub path: String,
+    pub key: Option<String>,
+}
+
+impl DatabaseConfig {
+    pub fn load() -> crate::Result<Self> {
+        Self::from_source(&Source::load()?)
+    }
+
+    fn from_source(source: &Source) -> crate::Result<Self> {
+        Ok(Self {
+            path: source.require("DATABASE_PATH")?,
+            key: source.get("DATABASE_KEY"),
+        })
+    }
+}
+
+impl Config {
+    pub fn load() -> crate::Result<Self> {
+        Self::from_source(&Source::load()?)
+    }
+
+    fn from_source(source: &Source) -> crate::Result<Self> {
+        let own_url = source.require("OWN_URL")?;
+        let token_duration = source
+            .require("TOKEN_DURATION")?
+            .parse::<u64>()
+            .map_err(|_| crate::Error::Opaque("Error parsing TOKEN_DURATION"))?;
+        let max_token_duration = source.parse(
+            "MAX_TOKEN_DURATION",
+            token_duration,
+            "Error parsing MAX_TOKEN_DURATION",
+        )?;
+        if max_token_duration < token_duration {
+            return Err(crate::Error::Opaque(
+                "MAX_TOKEN_DURATION cannot be shorter than TOKEN_DURATION",
+            ));
+        }
+        let oidc_claim = match source.get("OIDC_IDENTIFIER_CLAIM") {
+            Some(claim) => IdentifierClaim::from_env_value(&claim)?,
+            None => IdentifierClaim::Repository,
+        };
+        let token_subject = match source.get("TOKEN_SUBJECT") {
+            Some(subject) => TokenSubject::from_env_value(&subject)?,
+            None => TokenSubject::Name,
+        };
+        let unauthorized_scopes = match source.get("UNAUTHORIZED_SCOPES") {
+            Some(mode) => UnauthorizedScopes::from_env_value(&mode)?,
+            None => UnauthorizedScopes::Deny,
+        };
+        let base_path = source
+            .get("BASE_PATH")
+            .unwrap_or_default()
+            .trim_end_matches('/')
+            .to_string();
+        if !base_path.is_empty() && !base_path.starts_with('/') {
+            return Err(crate::Error::Opaque("BASE_PATH has to start with /"));
+        }
+        let swagger_path = if source.get("SWAGGER_UI").is_none_or(|ui| ui != "false") {
+            let path = source
+                .get("SWAGGER_PATH")
+                .unwrap_or("/api/swagger".to_string());
+            if !path.starts_with('/') {
+                return Err(crate::Error::Opaque("SWAGGER_PATH has to start with /"));
+            }
+            Some(path)
+        } else {
+            None
+        };
+        let allowed_actions = match source.get("ALLOWED_ACTIONS") {
+            Some(actions) => actions
+                .split(',')
+                .map(|action| PermissionType::from_actions(action.trim()))
+                .collect::<crate::Result<Vec<_>>>()
+                .map_err(|_| crate::Error::Opaque("Error parsing ALLOWED_ACTIONS"))?,
+            None => PermissionType::ACTIONS.to_vec(),
+        };
+        let resource_types: Vec<String> = match source.get("RESOURCE_TYPES") {
+            Some(types) => types
+                .split(',')
+                .map(|kind| kind.trim().to_string())
+                .filter(|kind| !kind.is_empty())
+                .collect(),
+            None => DEFAULT_RESOURCE_TYPES.map(str::to_string).to_vec(),
+        };
+        if resource_types.is_empty() {
+            return Err(crate::Error::Opaque("RESOURCE_TYPES must not be empty"));
+        }
+        let normalize_subjects = source.flag("NORMALIZE_SUBJECTS", false);
+        let default_permissions = parse_default_permissions(
+            &source.get("DEFAULT_PERMISSIONS").unwrap_or_default(),
+            &allowed_actions,
+            normalize_subjects,
+        )?;
+        let keep_alive =
+            match source.get("HTTP2_KEEP_ALIVE_SECONDS") {
+                Some(interval) => Some(Duration::from_secs(interval.parse::<u64>().map_err(
+                    |_| crate::Error::Opaque("Error parsing HTTP2_KEEP_ALIVE_SECONDS"),
+                )?)),
+                None => None,
+            };
+        let max_concurrent_streams =
+            match source.get("HTTP2_MAX_CONCURRENT_STREAMS") {
+                Some(max) => Some(max.parse::<u32>().map_err(|_| {
+                    crate::Error::Opaque("Error parsing HTTP2_MAX_CONCURRENT_STREAMS")
+                })?),
+                None => None,
+            };
+        let tls = match (source.get("TLS_CERT_FILE"), source.get("TLS_KEY_FILE")) {
+            (Some(cert), Some(key)) => Some(TlsFiles {
+                cert: cert.into(),
+                key: key.into(),
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(crate::Error::Opaque(
+                    "TLS_CERT_FILE and TLS_KEY_FILE have to be set together",
+                ));
+            }
+        };
+
+        let verify_only_cert = source.get("VERIFY_ONLY_CERT");
+        let jwt_private_key_pem = source.get("JWT_PRIVATE_KEY_PEM");
+        if verify_only_cert.is_some() && jwt_private_key_pem.is_some() {
+            return Err(crate::Error::Opaque(
+                "VERIFY_ONLY_CERT and JWT_PRIVATE_KEY_PEM cannot be set together",
+            ));
+        }
+
+        let argon_concurrency = source.parse(
+            "ARGON2_CONCURRENCY",
+            std::thread::available_parallelism().map_or(1, |n| n.get()),
+            "Error parsing ARGON2_CONCURRENCY",
+        )?;
+        // no permits would leave every password check waiting forever
+        if argon_concurrency < 1 {
+            return Err(crate::Error::Opaque(
+                "ARGON2_CONCURRENCY has to be at least 1",
+            ));
+        }
+
+        let docker_urls: Vec<String> = source
+            .require("DOCKER_URL")?
+            .split(',')
+            .map(|url| url.trim().to_string())
+            .filter(|url| !url.is_empty())
+            .collect();
+        // every token request would be for an unknown registry
+        if docker_urls.is_empty() {
+            return Err(crate::Error::Opaque("DOCKER_URL must not be empty"));
+        }
+
+        Ok(Self {
+            database_path: source.require("DATABASE_PATH")?,
+            database_key: source.get("DATABASE_KEY"),
+            docker_urls,
+            token_duration,
+            max_token_duration,
+            request_timeout: Duration::from_secs(source.parse(
+                "REQUEST_TIMEOUT_SECONDS",
+                30,
+                "Error parsing REQUEST_TIMEOUT_SECONDS",
+            )?),
+            argon_concurrency,
+            argon_algorithm: source.parse(
+                "ARGON2_VARIANT",
+                argon2::Algorithm::Argon2id,
+                "Error parsing ARGON2_VARIANT, use argon2i, argon2d or argon2id",
+            )?,
+            argon_version: match source.get("ARGON2_VERSION") {
+                Some(version) => version
+                    .parse::<u32>()
+                    .ok()
+                    .and_then(|version| argon2::Version::try_from(version).ok())
+                    .ok_or(crate::Error::Opaque(
+                        "Error parsing ARGON2_VERSION, use 16 or 19",
+                    ))?,
+                None => argon2::Version::V0x13,
+            },
+            argon_params: argon2::Params::new(
+                source.parse(
+                    "ARGON2_MEMORY_KIB",
+                    argon2::Params::DEFAULT_M_COST,
+                    "Error parsing ARGON2_MEMORY_KIB",
+                )?,
+                source.parse(
+                    "ARGON2_ITERATIONS",
+                    argon2::Params::DEFAULT_T_COST,
+                    "Error parsing ARGON2_ITERATIONS",
+                )?,
+                source.parse(
+                    "ARGON2_PARALLELISM",
+                    argon2::Params::DEFAULT_P_COST,
+                    "Error parsing ARGON2_PARALLELISM",
+                )?,
+                None,
+            )
+            .map_err(|_| crate::Error::Opaque("Invalid Argon2 parameters"))?,
+            max_password_length: source.parse(
+                "MAX_PASSWORD_LENGTH",
+                1024,
+                "Error parsing MAX_PASSWORD_LENGTH",
+            )?,
+            max_identifiers: source.parse(
+                "MAX_IDENTIFIERS",
+                1000,
+                "Error parsing MAX_IDENTIFIERS",
+            )?,
+            oidc_claim,
+            token_subject,
+            unauthorized_scopes,
+            oidc_debug: source.flag("OIDC_DEBUG", false),
+            metrics: source.flag("METRICS", false),
+            warm_up: source.flag("WARM_UP", true),
+            pull_implies_catalog: source.flag("PULL_IMPLIES_CATALOG", false),
+            push_implies_pull: source.flag("PUSH_IMPLIES_PULL", false),
+            normalize_subjects,
+            subject_inheritance: source.flag("SUBJECT_INHERITANCE", false),
+            svc_issuer: source.get("SVC_TOKEN_ISSUER").unwrap_or(own_url.clone()),
+            svc_audience: source
+                .get("SVC_TOKEN_AUDIENCE")
+                .unwrap_or("augesty-service-account".to_string()),
+            token_audience: source.get("TOKEN_AUDIENCE"),
+            iat_backdate: source.parse(
+                "TOKEN_IAT_BACKDATE_SECONDS",
+                5,
+                "Error parsing TOKEN_IAT_BACKDATE_SECONDS",
+            )?,
+            base_path,
+            swagger_path,
+            webhook_url: source.get("PERMISSION_WEBHOOK_URL"),
+            github_org_sync: source.flag("GITHUB_ORG_SYNC", false),
+            github_api_url: source
+                .get("GITHUB_API_URL")
+                .unwrap_or("https://api.github.com".to_string()),
+            jwks_ttl: Duration::from_secs(source.parse(
+                "JWKS_CACHE_SECONDS",
+                300,
+                "Error parsing JWKS_CACHE_SECONDS",
+            )?),
+            allowed_actions,
+            resource_types,
+            default_permissions,
+            write_cert_file: source
+                .get("WRITE_CERT_FILE")
+                .is_none_or(|write| write != "false"),
+            admin_password_file: source
+                .get("ADMIN_PASSWORD_FILE")
+                .unwrap_or("/config/admin_password".to_string()),
+            verify_only_cert,
+            jwt_private_key_pem,
+            cert_chain: source.get("CERT_CHAIN"),
+            read_only: source.flag("READ_ONLY", false),
+            security_headers: parse_headers(
+                &source
+                    .get("SECURITY_HEADERS")
+                    .unwrap_or(DEFAULT_SECURITY_HEADERS.to_string()),
+            )?,
+            basic_realm: source
+                .get("BASIC_AUTH_REALM")
+                .unwrap_or("augesty".to_string()),
+            custom_claims: parse_custom_claims(
+                &source.get("TOKEN_CUSTOM_CLAIMS").unwrap_or_default(),
+            )?,
+            catalog_max: source
+                .get("CATALOG_MAX_ENTRIES")
+                .map(|max| max.parse())
+                .transpose()
+                .map_err(|_| crate::Error::Opaque("Error parsing CATALOG_MAX_ENTRIES"))?,
+            server: ServerConfig {
+                http2: source.flag("HTTP2", false),
+                keep_alive,
+                max_concurrent_streams,
+                tls,
+            },
+            own_url,
+        })
+    }
+}
+
+// Secrets are only logged as set or unset
+fn redact(secret: &Option<String>) -> &'static str {
+    match secret {
+        Some(_) => "<redacted>",
+        None => "<unset>",
+    }
+}
+
+// How clients authenticate, reported by /api/config and the startup log. A verify only
+// instance cannot sign, so only Basic auth on the admin api is left
+pub fn providers(verify_only: bool) -> Vec<&'static str> {
+    if verify_only {
+        return vec!["basic"];
+    }
+    // passwords and svc tokens over basic auth, /api/identify/api_key, /api/admin/login and
+    // GitHub actions via /api/identify
+    vec!["basic", "api_key", "admin_token", "github_oidc"]
+}
+
+impl Config {
+    // One structured event with the resolved settings so operators can spot misconfiguration
+    pub fn log_startup(&self) {
+        tracing::info!(
+            event = "startup_config",
+            port = crate::PORT,
+            own_url = %self.own_url,
+            docker_urls = ?self.docker_urls,
+            token_duration_mins = self.token_duration,
+            max_token_duration_mins = self.max_token_duration,
+            signing_alg = "ES384",
+            argon2_variant = %self.argon_algorithm,
+            argon2_version = u32::from(self.argon_version),
+            argon2_memory_kib = self.argon_params.m_cost(),
+            argon2_iterations = self.argon_params.t_cost(),
+            argon2_parallelism = self.argon_params.p_cost(),
+            providers = %providers(self.verify_only_cert.is_some()).join(","),
+            oidc_claim = ?self.oidc_claim,
+            token_subject = ?self.token_subject,
+            unauthorized_scopes = ?self.unauthorized_scopes,
+            token_audience = ?self.token_audience,
+            oidc_debug = self.oidc_debug,
+            metrics = self.metrics,
+            warm_up = self.warm_up,
+            base_path = %self.base_path,
+            swagger_path = ?self.swagger_path,
+            allowed_actions = ?self.allowed_actions,
+            resource_types = ?self.resource_types,
+            default_permissions = self.default_permissions.len(),
+            http2 = self.server.http2,
+            tls = self.server.tls.is_some(),
+            verify_only = self.verify_only_cert.is_some(),
+            read_only = self.read_only,
+            database_path = %self.database_path,
+            database_key = redact(&self.database_key),
+            jwt_private_key = redact(&self.jwt_private_key_pem),
+            webhook_url = redact(&self.webhook_url),
+            github_org_sync = self.github_org_sync,
+            "{:<12}- Configuration loaded",
+            "Config"
+        );
+    }
+}
+
+#[test]
+fn test_database_config_needs_only_the_database() -> crate::Result<()> {
+    let source = Source {
+        env: |key| match key {
+            "DATABASE_PATH" => Some("/data/augesty.db".to_string()),
+            "DATABASE_KEY" => Some("secret".to_string()),
+            _ => None,
+        },
+        file: HashMap::new(),
+    };
+
+    let database = DatabaseConfig::from_source(&source)?;
+    assert_eq!(database.path, "/data/augesty.db");
+    assert_eq!(database.key.as_deref(), Some("secret"));
+    // the server itself also needs OWN_URL, DOCKER_URL and TOKEN_DURATION
+    assert!(Config::from_source(&source).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_load_config_file() -> crate::Result<()> {
+    let file = parse_file(
+        r#"
+        database_path = "/data/augesty.db"
+        own_url = "augesty.example.com"
+        docker_url = "registry.example.com"
+        token_duration = 10
+        push_implies_pull = true
+        allowed_actions = ["pull", "push"]
+        http2 = true
+        "#,
+    )?;
+
+    let config = Config::from_source(&Source {
+        env: |_| None,
+        file: file.clone(),
+    })?;
+    assert_eq!(config.own_url, "augesty.example.com");
+    assert_eq!(config.token_duration, 10);
+    assert!(config.push_implies_pull);
+    assert!(!config.pull_implies_catalog);
+    assert_eq!(
+        config.allowed_actions,
+        vec![PermissionType::Pull, PermissionType::Push]
+    );
+    assert!(config.server.http2);
+    assert_eq!(config.swagger_path.as_deref(), Some("/api/swagger"));
+
+    // env wins over the file
+    let config = Config::from_source(&Source {
+        env: |key| (key == "TOKEN_DURATION").then(|| "1".to_string()),
+        file: file.clone(),
+    })?;
+    assert_eq!(config.token_duration, 1);
+
+    let zero_concurrency = Config::from_source(&Source {
+        env: |key| (key == "ARGON2_CONCURRENCY").then(|| "0".to_string()),
+        file,
+    });
+    assert!(zero_concurrency.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_docker_url_needs_a_registry() -> crate::Result<()> {
+    let file = parse_file(
+        r#"
+        database_path = "/data/augesty.db"
+        own_url = "augesty.example.com"
+        docker_url = "registry.example.com, mirror.example.com"
+        token_duration = 10
+        "#,
+    )?;
+
+    let config = Config::from_source(&Source {
+        env: |_| None,
+        file: file.clone(),
+    })?;
+    assert_eq!(
+        config.docker_urls,
+        vec!["registry.example.com", "mirror.example.com"]
+    );
+
+    let commas = Config::from_source(&Source {
+        env: |key| (key == "DOCKER_URL").then(|| ",".to_string()),
+        file: file.clone(),
+    });
+    assert!(commas.is_err());
+    let blank = Config::from_source(&Source {
+        env: |key| (key == "DOCKER_URL").then(|| " ".to_string()),
+        file,
+    });
+    assert!(blank.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_security_headers() -> crate::Result<()> {
+    let headers = parse_headers(DEFAULT_SECURITY_HEADERS)?;
+    assert_eq!(headers.len(), 3);
+    assert_eq!(headers[0].0, "x-content-type-options");
+    assert_eq!(headers[0].1, "nosniff");
+
+    assert!(parse_headers("")?.is_empty());
+    assert!(parse_headers("x-frame-options").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_custom_claims() -> crate::Result<()> {
+    let claims = parse_custom_claims("tenant=acme, email={user}@example.com")?;
+    assert_eq!(
+        claims,
+        vec![
+            ("tenant".to_string(), "acme".to_string()),
+            ("email".to_string(), "{user}@example.com".to_string())
+        ]
+    );
+
+    assert!(parse_custom_claims("")?.is_empty());
+    assert!(parse_custom_claims("access=everything").is_err());
+    assert!(parse_custom_claims("sub=admin").is_err());
+    assert!(parse_custom_claims("epoch=999").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_default_permissions() -> crate::Result<()> {
+    let permissions = parse_default_permissions(
+        "Library/Base:pull, example/tools:push",
+        &PermissionType::ACTIONS,
+        true,
+    )?;
+    assert_eq!(
+        permissions
+            .iter()
+            .map(|perm| (perm.subject.as_str(), &perm.permission))
+            .collect::<Vec<_>>(),
+        vec![
+            ("library/base", &PermissionType::Pull),
+            ("example/tools", &PermissionType::Push)
+        ]
+    );
+    assert!(permissions.iter().all(|perm| perm.kind == "repository"));
+
+    assert!(parse_default_permissions("", &PermissionType::ACTIONS, false)?.is_empty());
+    assert!(parse_default_permissions("library/base", &PermissionType::ACTIONS, false).is_err());
+    assert!(parse_default_permissions(":pull", &PermissionType::ACTIONS, false).is_err());
+    assert!(parse_default_permissions("library/base:*", &PermissionType::ACTIONS, false).is_err());
+    assert!(
+        parse_default_permissions("library/base:push", &[PermissionType::Pull], false).is_err()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_startup_log_redacts_secrets() -> crate::Result<()> {
+    let config = Config::from_source(&Source {
+        env: |key| match key {
+            "DATABASE_PATH" => Some("/data/augesty.db".to_string()),
+            "DATABASE_KEY" => Some("database-secret".to_string()),
+            "PERMISSION_WEBHOOK_URL" => Some("https://hook.example.com/webhook-secret".to_string()),
+            "OWN_URL" => Some("augesty.example.com".to_string()),
+            "DOCKER_URL" => Some("registry.example.com".to_string()),
+            "TOKEN_DURATION" => Some("10".to_string()),
+            _ => None,
+        },
+        file: HashMap::new(),
+    })?;
+
+    let buffer = crate::LogBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_writer(move || writer.clone())
+        .finish();
+    tracing::subscriber::with_default(subscriber, || config.log_startup());
+
+    let logs = buffer.contents();
+    assert!(logs.contains("startup_config"));
+    assert!(logs.contains("augesty.example.com"));
+    assert!(!logs.contains("database-secret"));
+    assert!(!logs.contains("webhook-secret"));
+
+    Ok(())
+}
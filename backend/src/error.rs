@@ -1,5 +1,6 @@
 use axum::{
-    http::StatusCode,
+    extract::rejection::JsonRejection,
+    http::{HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
 use axum_extra::extract::QueryRejection;
@@ -10,6 +11,10 @@ pub enum Error {
     BadRequest(&'static str),
     Unauthorized(&'static str),
     NotFound(&'static str),
+    NotImplemented(&'static str),
+    Unavailable(&'static str),
+    // a request body that is not JSON or does not fit the expected shape
+    MalformedBody(&'static str),
     #[from]
     Io(tokio::io::Error),
     #[from]
@@ -26,12 +31,16 @@ pub enum Error {
     Argon(argon2::Error),
     #[from]
     Opaque(&'static str),
+    // input that does not parse, e.g. a malformed scope
+    Parse(&'static str),
     #[from]
     Jwt(jwt_simple::Error),
     #[from]
     Ssl(openssl::error::ErrorStack),
     #[from]
     Utf8(std::str::Utf8Error),
+    #[from]
+    Http(reqwest::Error),
     Any(String),
 }
 
@@ -43,20 +52,70 @@ impl IntoResponse for Error {
     fn into_response(self) -> Response {
         tracing::warn!("{:<12}- Error occurred: {}", "Request", self);
         let status = match self {
-            Error::BadRequest(_) => axum::http::StatusCode::BAD_REQUEST,
+            Error::BadRequest(_) | Error::MalformedBody(_) | Error::Parse(_) => {
+                axum::http::StatusCode::BAD_REQUEST
+            }
             Error::Unauthorized(_) => axum::http::StatusCode::UNAUTHORIZED,
             Error::NotFound(_) => axum::http::StatusCode::NOT_FOUND,
+            Error::NotImplemented(_) => axum::http::StatusCode::NOT_IMPLEMENTED,
+            Error::Unavailable(_) => axum::http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::Db(ref e) if db_unavailable(e) => axum::http::StatusCode::SERVICE_UNAVAILABLE,
             _ => axum::http::StatusCode::INTERNAL_SERVER_ERROR,
         };
 
-        let body = self.to_string();
-        Response::builder()
-            .status(status)
-            .body(body.into())
-            .unwrap()
+        // internal failures are logged above, their details stay out of the response
+        let body = if status == StatusCode::INTERNAL_SERVER_ERROR {
+            "Internal Server Error".to_string()
+        } else {
+            self.to_string()
+        };
+        let mut response = text_response(status, body);
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from_static("5"));
+        }
+        response
+    }
+}
+
+// Errors where the database itself is gone or busy, retrying later can succeed
+fn db_unavailable(error: &sqlx::Error) -> bool {
+    match error {
+        sqlx::Error::Io(_) | sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed => true,
+        sqlx::Error::Database(e) => {
+            // sqlite reports the extended result code, the primary code is the lowest byte
+            let code = e.code().and_then(|code| code.parse::<i32>().ok());
+            matches!(
+                code.map(|code| code & 0xff),
+                // BUSY, LOCKED, IOERR, FULL, CANTOPEN
+                Some(5 | 6 | 10 | 13 | 14)
+            )
+        }
+        _ => false,
     }
 }
 
+// Turns a UNIQUE or primary key conflict into a 400 with the message, e.g. for duplicate names
+pub(crate) fn unique_violation(message: &'static str) -> impl Fn(sqlx::Error) -> Error {
+    move |error| match error {
+        sqlx::Error::Database(ref e) if e.is_unique_violation() => Error::BadRequest(message),
+        error => Error::Db(error),
+    }
+}
+
+// Building the error response must not panic, fall back to a bare 500 instead
+fn text_response(status: StatusCode, body: String) -> Response {
+    Response::builder()
+        .status(status)
+        .body(body.into())
+        .unwrap_or_else(|_| {
+            let mut response = Response::new("Internal Server Error".into());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        })
+}
+
 pub struct LoggedRejection(StatusCode, &'static str);
 
 impl From<QueryRejection> for LoggedRejection {
@@ -70,11 +129,168 @@ impl From<QueryRejection> for LoggedRejection {
     }
 }
 
+// Used with WithRejection on JSON bodies, axum would answer 415, 422 or 400 with its own text
+impl From<JsonRejection> for Error {
+    fn from(value: JsonRejection) -> Self {
+        tracing::warn!(
+            "{:<12}- JSON body deserialization failed: {}",
+            "Request",
+            value.body_text()
+        );
+        Error::MalformedBody(match value {
+            JsonRejection::MissingJsonContentType(_) => "Expected a JSON body",
+            JsonRejection::JsonSyntaxError(_) => "Malformed JSON body",
+            _ => "JSON body does not match the expected fields",
+        })
+    }
+}
+
 impl IntoResponse for LoggedRejection {
     fn into_response(self) -> Response {
-        Response::builder()
-            .status(self.0)
-            .body(self.1.into())
-            .unwrap()
+        text_response(self.0, self.1.to_string())
     }
 }
+
+#[test]
+fn test_str_converts_to_opaque_error() {
+    let error: Error = "Error fetching github jwks".into();
+    assert!(matches!(error, Error::Opaque(_)));
+    assert_eq!(
+        error.into_response().status(),
+        axum::http::StatusCode::INTERNAL_SERVER_ERROR
+    );
+    assert_eq!(
+        Error::Parse("Scope is missing the resource type")
+            .into_response()
+            .status(),
+        axum::http::StatusCode::BAD_REQUEST
+    );
+}
+
+#[test]
+fn test_error_responses_do_not_panic() {
+    let errors = [
+        Error::BadRequest("bad"),
+        Error::Unauthorized("no"),
+        Error::NotFound("missing"),
+        Error::Opaque("opaque"),
+        Error::Any("line\nbreak \u{0}".to_string()),
+    ];
+    for error in errors {
+        let _ = error.into_response();
+    }
+
+    let response = LoggedRejection(StatusCode::BAD_REQUEST, "Bad Request").into_response();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn test_internal_errors_are_opaque() {
+    let body = |error: Error| async move {
+        let response = error.into_response();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap_or_default();
+        (status, String::from_utf8_lossy(&bytes).to_string())
+    };
+
+    assert_eq!(
+        body(Error::Any(
+            "DATABASE_PATH /data is not writable".to_string()
+        ))
+        .await,
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Internal Server Error".to_string()
+        )
+    );
+    assert_eq!(
+        body(Error::BadRequest("Scope is missing the actions")).await,
+        (
+            StatusCode::BAD_REQUEST,
+            "Scope is missing the actions".to_string()
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_unavailable_db_returns_503() -> Result<()> {
+    let pool = sqlx::SqlitePool::connect("sqlite::memory:").await?;
+    pool.close().await;
+
+    let error: Error = sqlx::query("SELECT 1")
+        .execute(&pool)
+        .await
+        .unwrap_err()
+        .into();
+    let response = error.into_response();
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+
+    // query errors stay a 500
+    let response = Error::Db(sqlx::Error::RowNotFound).into_response();
+    assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_malformed_json_body_returns_400() {
+    use axum::{Json, Router, body::Body, http::Request, routing::post};
+    use axum_extra::extract::WithRejection;
+    use tower::ServiceExt;
+
+    #[derive(serde::Deserialize)]
+    struct NameBody {
+        name: String,
+    }
+
+    let app: Router = Router::new().route(
+        "/",
+        post(
+            |WithRejection(Json(body), _): WithRejection<Json<NameBody>, Error>| async move {
+                body.name
+            },
+        ),
+    );
+    let post = |content_type: &'static str, body: &'static str| {
+        let app = app.clone();
+        async move {
+            let response = app
+                .oneshot(
+                    Request::post("/")
+                        .header(header::CONTENT_TYPE, content_type)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, String::from_utf8_lossy(&body).to_string())
+        }
+    };
+
+    assert_eq!(
+        post("application/json", r#"{"name": "dev"}"#).await.0,
+        StatusCode::OK
+    );
+    assert_eq!(
+        post("application/json", r#"{"name": "#).await,
+        (StatusCode::BAD_REQUEST, "Malformed JSON body".to_string())
+    );
+    assert_eq!(
+        post("application/json", r#"{"other": "dev"}"#).await,
+        (
+            StatusCode::BAD_REQUEST,
+            "JSON body does not match the expected fields".to_string()
+        )
+    );
+    assert_eq!(
+        post("text/plain", r#"{"name": "dev"}"#).await,
+        (StatusCode::BAD_REQUEST, "Expected a JSON body".to_string())
+    );
+}
@@ -1,4 +1,4 @@
-use std::ops::Deref;
+use std::{collections::BTreeMap, time::Instant};
 
 use axum::{
     RequestPartsExt,
@@ -13,8 +13,10 @@ use axum_extra::{
 };
 
 use crate::{
+    metrics::AuthProvider,
     models::{permission::Permission, user::User},
-    state::AppState,
+    state::{AppState, INVALID_LOGIN},
+    trace::Principal,
 };
 
 pub struct PermissionExtractor {
@@ -37,30 +39,123 @@ where
             .await
             .map_err(|_| crate::Error::Opaque("Internal Server Error"))?;
 
+        let start = Instant::now();
+        let (provider, result) = Self::authenticate(parts, &state).await;
+        state
+            .auth_metrics()
+            .record(provider, result.is_ok(), start.elapsed());
+        result
+    }
+}
+
+impl PermissionExtractor {
+    async fn authenticate(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> (AuthProvider, crate::Result<Self>) {
+        // an admin token from /api/admin/login stands in for the admin credentials on the admin
+        // api. Any token minted with it, including a fresh admin token, would outlive it
+        if let Ok(TypedHeader(Authorization(bearer))) =
+            parts.extract::<TypedHeader<Authorization<Bearer>>>().await
+        {
+            let result = if crate::TOKEN_PATHS.contains(&parts.uri.path()) {
+                Err(crate::Error::Unauthorized(
+                    "Admin tokens are only accepted by the admin api",
+                ))
+            } else {
+                Self::admin_token(parts, state, bearer.token()).await
+            };
+            return (AuthProvider::AdminToken, result);
+        }
+
+        (AuthProvider::Basic, Self::basic(parts, state).await)
+    }
+
+    async fn admin_token(
+        parts: &axum::http::request::Parts,
+        state: &AppState,
+        token: &str,
+    ) -> crate::Result<Self> {
+        let user = state.verify_admin_jwt(token).await?;
+        Principal::record(&parts.extensions, &user.name);
+        let permissions = user.list_permissions(state.db()).await?;
+        Ok(PermissionExtractor { user, permissions })
+    }
+
+    async fn basic(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> crate::Result<Self> {
         let basic = parts
             .extract::<TypedHeader<Authorization<Basic>>>()
             .await
             .map_err(|_| crate::Error::Unauthorized("Not Basic Auth"))?
             .0;
-        let user = User::find_by_name(basic.username(), state.db())
-            .await
-            .map_err(|_| crate::Error::Unauthorized("User does not exist"))?;
+        state.verify_password_length(basic.password())?;
+        let user = match User::find_by_name(basic.username(), state.db()).await {
+            Ok(user) => user,
+            // an unknown user takes as long as a wrong password and gets the same answer, so
+            // logins cant be used to find out which users exist
+            Err(crate::Error::Db(sqlx::Error::RowNotFound)) => {
+                state
+                    .verify_credentials(basic.username(), basic.password())
+                    .await?;
+                return Err(crate::Error::Unauthorized(INVALID_LOGIN));
+            }
+            // an unreachable database should not look like a wrong username
+            Err(e) => return Err(e),
+        };
         let permissions = state
             .get_permissions(user.clone(), basic.password())
             .await?;
+        Principal::record(&parts.extensions, &user.name);
 
         Ok(PermissionExtractor { user, permissions })
     }
 }
 
-pub struct GithubExtractor(pub GithubRepo);
+pub struct GithubExtractor(pub GithubIdentity);
+
+pub struct GithubIdentity {
+    pub repository: String,
+    pub repository_owner: String,
+    pub sub: String,
+    pub job_workflow_ref: String,
+    // unique id of the OIDC token, used to detect replays
+    pub jti: String,
+    // every string claim of the token, e.g. `ref` or `event_name`, for claim conditions
+    pub claims: BTreeMap<String, String>,
+}
 
-pub struct GithubRepo(String);
+// Which OIDC claim is compared against the identifiers of a service account
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentifierClaim {
+    Repository,
+    RepositoryOwner,
+    // matches either the repository or the whole org
+    RepositoryOrOwner,
+}
+
+impl IdentifierClaim {
+    pub fn from_env_value(value: &str) -> crate::Result<Self> {
+        match value {
+            "repository" => Ok(Self::Repository),
+            "repository_owner" => Ok(Self::RepositoryOwner),
+            "repository_or_owner" => Ok(Self::RepositoryOrOwner),
+            _ => Err(crate::Error::Opaque("Unknown OIDC_IDENTIFIER_CLAIM")),
+        }
+    }
+}
 
-impl Deref for GithubRepo {
-    type Target = String;
-    fn deref(&self) -> &Self::Target {
-        &self.0
+impl GithubIdentity {
+    pub fn identifiers(&self, claim: IdentifierClaim) -> Vec<&str> {
+        match claim {
+            IdentifierClaim::Repository => vec![&self.repository],
+            IdentifierClaim::RepositoryOwner => vec![&self.repository_owner],
+            IdentifierClaim::RepositoryOrOwner => {
+                vec![&self.repository, &self.repository_owner]
+            }
+        }
     }
 }
 
@@ -75,6 +170,25 @@ where
         parts: &mut axum::http::request::Parts,
         state: &S,
     ) -> Result<Self, Self::Rejection> {
+        let State(state): State<AppState> = State::from_request_parts(parts, state)
+            .await
+            .map_err(|_| crate::Error::Opaque("Internal Server Error"))?;
+
+        // includes fetching the JWKS when the cache is cold
+        let start = Instant::now();
+        let result = Self::authenticate(parts, &state).await;
+        state
+            .auth_metrics()
+            .record(AuthProvider::GithubOidc, result.is_ok(), start.elapsed());
+        result
+    }
+}
+
+impl GithubExtractor {
+    async fn authenticate(
+        parts: &mut axum::http::request::Parts,
+        state: &AppState,
+    ) -> crate::Result<Self> {
         let oidc_token = parts
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
@@ -82,25 +196,76 @@ where
             .0
             .token()
             .to_string();
-        let jwks = github_oidc::fetch_jwks(github_oidc::DEFAULT_GITHUB_OIDC_URL)
-            .await
-            .map_err(|_| crate::Error::Opaque("Error fetching github jwks"))?;
-
-        let State(state): State<AppState> = State::from_request_parts(parts, state)
-            .await
-            .map_err(|_| crate::Error::Opaque("Internal Server Error"))?;
+        let jwks = state.github_jwks().await?;
 
         let claims = jwks
             .validate_github_token(
                 &oidc_token,
                 &github_oidc::GitHubOIDCConfig {
-                    audience: Some(format!("https://{}",state.own_url())),
+                    audience: Some(format!("https://{}", state.own_url())),
                     ..Default::default()
                 },
             )
             .map_err(|_| crate::Error::Unauthorized("Invalid OIDC Token"))?;
+        let jti = claims
+            .additional_claims
+            .get("jti")
+            .and_then(|jti| jti.as_str())
+            .ok_or(crate::Error::Unauthorized("OIDC Token has no jti"))?
+            .to_string();
 
-        Ok(GithubExtractor(GithubRepo(claims.repository)))
+        let mut string_claims: BTreeMap<String, String> = claims
+            .additional_claims
+            .iter()
+            .filter_map(|(name, value)| Some((name.clone(), value.as_str()?.to_string())))
+            .collect();
+        string_claims.insert("repository".to_string(), claims.repository.clone());
+        string_claims.insert(
+            "repository_owner".to_string(),
+            claims.repository_owner.clone(),
+        );
+        string_claims.insert("sub".to_string(), claims.sub.clone());
+        string_claims.insert(
+            "job_workflow_ref".to_string(),
+            claims.job_workflow_ref.clone(),
+        );
+
+        // the service account is only resolved by the handler
+        Principal::record(&parts.extensions, &format!("github:{}", claims.repository));
+
+        Ok(GithubExtractor(GithubIdentity {
+            repository: claims.repository,
+            repository_owner: claims.repository_owner,
+            sub: claims.sub,
+            job_workflow_ref: claims.job_workflow_ref,
+            jti,
+            claims: string_claims,
+        }))
     }
 }
 
+#[test]
+fn test_org_level_identifier() {
+    let identity = GithubIdentity {
+        repository: "example/repo".to_string(),
+        repository_owner: "example".to_string(),
+        sub: "repo:example/repo:ref:refs/heads/main".to_string(),
+        job_workflow_ref: "example/repo/.github/workflows/ci.yaml@refs/heads/main".to_string(),
+        jti: "example-jti".to_string(),
+        claims: BTreeMap::new(),
+    };
+
+    assert_eq!(
+        identity.identifiers(IdentifierClaim::Repository),
+        vec!["example/repo"]
+    );
+    assert_eq!(
+        identity.identifiers(IdentifierClaim::RepositoryOwner),
+        vec!["example"]
+    );
+    assert!(
+        identity
+            .identifiers(IdentifierClaim::RepositoryOrOwner)
+            .contains(&"example")
+    );
+}
@@ -0,0 +1,299 @@
+use std::time::Duration;
+
+use axum::http::{HeaderMap, StatusCode, header};
+use serde::Deserialize;
+
+// GitHub caps the page size at 100
+const PER_PAGE: u32 = 100;
+// Longer rate limit waits are reported to the admin instead of holding the request
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(10);
+// 10000 repositories, a Link header that never ends would otherwise keep the request going
+const MAX_PAGES: usize = 100;
+
+#[derive(Debug, Deserialize)]
+struct Repository {
+    full_name: String,
+}
+
+// Lists the repositories of a GitHub org with a token supplied by the admin
+#[derive(Debug, Clone)]
+pub struct GithubApi {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl GithubApi {
+    pub fn new(url: String) -> crate::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .user_agent("augesty")
+            .build()
+            .map_err(|_| crate::Error::Opaque("Error building the GitHub client"))?;
+
+        Ok(Self {
+            http,
+            url: url.trim_end_matches('/').to_string(),
+        })
+    }
+
+    // Every repository of the org as `org/repo`, following the pages GitHub links to
+    pub async fn org_repos(&self, org: &str, token: &str) -> crate::Result<Vec<String>> {
+        validate_org(org)?;
+
+        let mut repos = Vec::new();
+        let mut next = Some(format!("{}/orgs/{org}/repos?per_page={PER_PAGE}", self.url));
+        let mut pages = 0;
+        while let Some(url) = next {
+            pages += 1;
+            if pages > MAX_PAGES {
+                return Err(crate::Error::BadRequest(
+                    "GitHub org has too many repositories",
+                ));
+            }
+            let response = self.get(&url, token).await?;
+            next = next_page(response.headers(), &self.url)?;
+            let page: Vec<Repository> = response.json().await?;
+            repos.extend(page.into_iter().map(|repo| repo.full_name));
+        }
+
+        Ok(repos)
+    }
+
+    // Waits out a short rate limit once, a second one is returned as 503
+    async fn get(&self, url: &str, token: &str) -> crate::Result<reqwest::Response> {
+        let mut waited = false;
+        loop {
+            let response = self
+                .http
+                .get(url)
+                .bearer_auth(token)
+                .header(header::ACCEPT, "application/vnd.github+json")
+                .header("x-github-api-version", "2022-11-28")
+                .send()
+                .await?;
+
+            match response.status() {
+                status if status.is_success() => return Ok(response),
+                StatusCode::UNAUTHORIZED => {
+                    return Err(crate::Error::BadRequest("GitHub rejected the token"));
+                }
+                StatusCode::NOT_FOUND => {
+                    return Err(crate::Error::NotFound("GitHub org not found"));
+                }
+                _ => {}
+            }
+
+            match rate_limit_wait(response.status(), response.headers()) {
+                Some(wait) if !waited && wait <= MAX_RATE_LIMIT_WAIT => {
+                    tracing::warn!(
+                        "{:<12}- Rate limited, retrying in {} s",
+                        "GitHub",
+                        wait.as_secs()
+                    );
+                    tokio::time::sleep(wait).await;
+                    waited = true;
+                }
+                Some(_) => {
+                    return Err(crate::Error::Unavailable("GitHub API rate limit exceeded"));
+                }
+                None => {
+                    tracing::warn!(
+                        "{:<12}- Listing repositories returned {}",
+                        "GitHub",
+                        response.status()
+                    );
+                    return Err(crate::Error::Opaque("GitHub API request failed"));
+                }
+            }
+        }
+    }
+}
+
+// Org logins are alphanumeric with dashes, anything else could escape the api path
+fn validate_org(org: &str) -> crate::Result<()> {
+    if org.is_empty() || !org.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(crate::Error::BadRequest("Invalid GitHub org"));
+    }
+    Ok(())
+}
+
+// The url of rel="next" in the Link header, missing on the last page. The token goes along
+// with the request, so a link leaving the api origin is refused
+fn next_page(headers: &HeaderMap, api_url: &str) -> crate::Result<Option<String>> {
+    let Some(link) = headers
+        .get(header::LINK)
+        .and_then(|link| link.to_str().ok())
+    else {
+        return Ok(None);
+    };
+    let next = link.split(',').find_map(|part| {
+        let (url, params) = part.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| param.trim() == r#"rel="next""#)
+            .then(|| url.trim().trim_start_matches('<').trim_end_matches('>'))
+    });
+    let Some(next) = next else {
+        return Ok(None);
+    };
+
+    let origin = |url: &str| reqwest::Url::parse(url).ok().map(|url| url.origin());
+    match (origin(next), origin(api_url)) {
+        (Some(next_origin), Some(api_origin)) if next_origin == api_origin => {
+            Ok(Some(next.to_string()))
+        }
+        _ => {
+            tracing::warn!("{:<12}- Refused a next page outside the api", "GitHub");
+            Err(crate::Error::Opaque("GitHub API request failed"))
+        }
+    }
+}
+
+// GitHub signals both primary and secondary rate limits with 403 or 429
+fn rate_limit_wait(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    if status != StatusCode::FORBIDDEN && status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let number = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+
+    if let Some(seconds) = number("retry-after") {
+        return Some(Duration::from_secs(seconds));
+    }
+    if number("x-ratelimit-remaining") != Some(0) {
+        return None;
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    number("x-ratelimit-reset").map(|reset| Duration::from_secs(reset.saturating_sub(now)))
+}
+
+#[tokio::test]
+async fn test_org_repos_follows_pages_and_rate_limits() -> crate::Result<()> {
+    use std::sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{
+        Json, Router,
+        extract::{Path, Query},
+        response::{IntoResponse, Response},
+        routing::get,
+    };
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let mock = {
+        let calls = calls.clone();
+        Router::new().route(
+            "/orgs/{org}/repos",
+            get(
+                move |Path(org): Path<String>,
+                      Query(query): Query<std::collections::HashMap<String, String>>,
+                      headers: HeaderMap| async move {
+                    if headers[header::AUTHORIZATION] != "Bearer gh-token" {
+                        return StatusCode::UNAUTHORIZED.into_response();
+                    }
+                    if org != "example" {
+                        return StatusCode::NOT_FOUND.into_response();
+                    }
+                    // the first call is rate limited to exercise the retry
+                    if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                        return (StatusCode::TOO_MANY_REQUESTS, [("retry-after", "0")])
+                            .into_response();
+                    }
+                    let repos = |names: &[&str]| {
+                        Json(
+                            names
+                                .iter()
+                                .map(|name| serde_json::json!({ "full_name": name }))
+                                .collect::<Vec<_>>(),
+                        )
+                    };
+                    let response: Response = match query.get("page").map(String::as_str) {
+                        None => (
+                            [(
+                                header::LINK,
+                                format!(
+                                    r#"<http://{addr}/orgs/example/repos?per_page=100&page=2>; rel="next", <http://{addr}/orgs/example/repos?per_page=100&page=2>; rel="last""#
+                                ),
+                            )],
+                            repos(&["example/api", "example/web"]),
+                        )
+                            .into_response(),
+                        _ => repos(&["example/worker"]).into_response(),
+                    };
+                    response
+                },
+            ),
+        )
+    };
+    tokio::spawn(async move { axum::serve(listener, mock).await });
+
+    let github = GithubApi::new(format!("http://{addr}/"))?;
+    assert_eq!(
+        github.org_repos("example", "gh-token").await?,
+        vec!["example/api", "example/web", "example/worker"]
+    );
+    assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+    assert!(matches!(
+        github.org_repos("example", "wrong").await,
+        Err(crate::Error::BadRequest(_))
+    ));
+    assert!(matches!(
+        github.org_repos("missing", "gh-token").await,
+        Err(crate::Error::NotFound(_))
+    ));
+    assert!(matches!(
+        github.org_repos("../user", "gh-token").await,
+        Err(crate::Error::BadRequest(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_next_page_stays_on_the_api_origin() -> crate::Result<()> {
+    let link = |value: &str| {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::LINK, value.parse().unwrap());
+        headers
+    };
+    let api = "https://api.github.com";
+
+    assert_eq!(
+        next_page(
+            &link(r#"<https://api.github.com/orgs/example/repos?page=2>; rel="next""#),
+            api
+        )?
+        .as_deref(),
+        Some("https://api.github.com/orgs/example/repos?page=2")
+    );
+    assert_eq!(
+        next_page(
+            &link(r#"<https://api.github.com/orgs/example/repos?page=1>; rel="first""#),
+            api
+        )?,
+        None
+    );
+    assert_eq!(next_page(&HeaderMap::new(), api)?, None);
+    // the token must not be sent to another host, scheme or port
+    for url in [
+        "https://attacker.example.com/repos",
+        "http://api.github.com/orgs/example/repos?page=2",
+        "https://api.github.com:8443/orgs/example/repos?page=2",
+        "https://api.github.com.attacker.example.com/repos",
+    ] {
+        assert!(next_page(&link(&format!(r#"<{url}>; rel="next""#)), api).is_err());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,742 @@
+use axum::Router;
+pub use error::{Error, Result};
+use serde::Serialize;
+use sqlx::migrate::{Migrate, Migrator};
+use tower_http::timeout::TimeoutLayer;
+use utoipa::{
+    Modify, OpenApi,
+    openapi::security::{HttpBuilder, SecurityScheme},
+};
+use utoipa_axum::{router::OpenApiRouter, routes};
+use utoipa_swagger_ui::SwaggerUi;
+
+use crate::state::AppState;
+
+pub mod config;
+mod error;
+mod extractors;
+pub mod github;
+mod metrics;
+pub mod models;
+pub mod routes;
+pub mod server;
+pub mod state;
+pub mod webhook;
+
+#[cfg(feature = "client")]
+pub mod client;
+#[cfg(feature = "otel")]
+mod otel;
+
+pub const PORT: u16 = 8080;
+
+pub static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+// A migrated database for unit tests, every connection to :memory: is its own database
+#[cfg(test)]
+pub(crate) async fn test_pool() -> Result<sqlx::SqlitePool> {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await?;
+    MIGRATOR.run(&pool).await?;
+    Ok(pool)
+}
+
+// Collects what a test subscriber writes, e.g. to check secrets stay out of the logs
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl LogBuffer {
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap_or_else(|e| e.into_inner())).to_string()
+    }
+}
+
+#[cfg(test)]
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+const USER_TAG: &str = "user";
+const TOKEN_TAG: &str = "token";
+
+#[derive(Debug, Serialize)]
+struct Modifier;
+
+impl Modify for Modifier {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(schema) = openapi.components.as_mut() {
+            schema.add_security_scheme(
+                "github_oidc",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(utoipa::openapi::security::HttpAuthScheme::Bearer)
+                        .bearer_format("JWT")
+                        .description(Some(
+                            "A GitHub Actions OIDC ID token. Request it inside the workflow \
+                            (needs `id-token: write`) with the augesty url as audience. \
+                            The `repository` claim must be an identifier of the service account.",
+                        ))
+                        .build(),
+                ),
+            );
+            schema.add_security_scheme(
+                "docker_basic",
+                SecurityScheme::Http(
+                    HttpBuilder::new()
+                        .scheme(utoipa::openapi::security::HttpAuthScheme::Basic)
+                        .bearer_format("name:JWT")
+                        .build(),
+                ),
+            );
+        }
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    tags(
+        (name = USER_TAG, description = "User API endpoints"),
+        (name = TOKEN_TAG, description = "Token API endpoints")
+    ),
+    modifiers(&Modifier),
+    security(
+        ("github_oidc" = [], "docker_basic" = [])
+    )
+)]
+struct ApiDoc;
+
+// Runs the embedded migrations and logs the ones that were not applied before
+pub async fn migrate(db: &sqlx::SqlitePool) -> Result<()> {
+    let mut conn = db.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    let applied: Vec<i64> = conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+    drop(conn);
+
+    MIGRATOR.run(db).await?;
+
+    for migration in MIGRATOR
+        .iter()
+        .filter(|migration| !applied.contains(&migration.version))
+    {
+        tracing::info!(
+            "{:<12}- Applied {} {}",
+            "Migration",
+            migration.version,
+            migration.description
+        );
+    }
+
+    Ok(())
+}
+
+// Builds the api router including the swagger ui
+pub fn app(state: AppState) -> Router {
+    let mut router = OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .routes(routes!(routes::token::token, routes::token::identify))
+        .routes(routes!(routes::token::identify_api_key))
+        .routes(routes!(routes::token::check))
+        .routes(routes!(routes::token::validate))
+        .routes(routes!(routes::cert::cert))
+        .routes(routes!(routes::config::config))
+        .routes(routes!(
+            routes::user::grant_access,
+            routes::user::revoke_access
+        ))
+        .routes(routes!(
+            routes::user::create_user,
+            routes::user::delete_user,
+            routes::user::rename_user,
+            routes::user::list_users
+        ))
+        .routes(routes!(routes::user::clear_access))
+        .routes(routes!(routes::user::admin_login))
+        .routes(routes!(routes::user::get_user))
+        .routes(routes!(routes::user::expire_tokens))
+        .routes(routes!(routes::user::set_external_id))
+        .routes(routes!(routes::user::verify))
+        .routes(routes!(
+            routes::user::create_service_account,
+            routes::user::list_service_accounts
+        ))
+        .routes(routes!(routes::user::add_identifier))
+        .routes(routes!(routes::user::sync_github_org))
+        .routes(routes!(
+            routes::user::add_condition,
+            routes::user::remove_condition
+        ))
+        .routes(routes!(routes::user::set_api_key))
+        .routes(routes!(routes::user::list_all_permissions))
+        .routes(routes!(routes::user::list_subjects))
+        .routes(routes!(routes::user::set_read_only));
+
+    // exposes claim details, so only for debugging CI integrations
+    if state.oidc_debug() {
+        router = router.routes(routes!(routes::oidc::debug));
+    }
+    if state.metrics_enabled() {
+        router = router.routes(routes!(routes::metrics::metrics));
+    }
+
+    let (router, mut api): (axum::Router<AppState>, utoipa::openapi::OpenApi) =
+        router.with_state(state.clone()).split_for_parts();
+    if !state.base_path().is_empty() {
+        api.servers = Some(vec![utoipa::openapi::Server::new(state.base_path())]);
+    }
+
+    let router = router
+        .fallback(not_found)
+        .layer(axum::middleware::from_fn_with_state(
+            state.security_headers(),
+            security_headers,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            state.admin_challenge(),
+            admin_challenge,
+        ))
+        .layer(timeout_layer(state.request_timeout()))
+        .layer(
+            tower_http::trace::TraceLayer::new_for_http()
+                .make_span_with(trace::request_span)
+                .on_request(())
+                .on_response(trace::record_response)
+                .on_failure(()),
+        )
+        .layer(axum::middleware::from_fn(trace::logging_layer))
+        .with_state(state.clone());
+
+    mount_swagger(
+        nest_under(router, state.base_path()),
+        state.base_path(),
+        state.swagger_path(),
+        api,
+    )
+}
+
+// Moves all routes below the base path, e.g. /auth/api/token
+fn nest_under(router: Router, base_path: &str) -> Router {
+    if base_path.is_empty() {
+        return router;
+    }
+    Router::new().nest(base_path, router).fallback(not_found)
+}
+
+// Unknown paths answer with the same error body as every handler instead of an empty 404
+async fn not_found() -> Error {
+    Error::NotFound("Route does not exist")
+}
+
+// Without a path neither the ui nor the openapi.json are served
+fn mount_swagger(
+    router: Router,
+    base_path: &str,
+    path: Option<&str>,
+    api: utoipa::openapi::OpenApi,
+) -> Router {
+    match path {
+        Some(path) => router.merge(
+            SwaggerUi::new(format!("{base_path}{path}"))
+                .url(format!("{base_path}/api/openapi.json"), api),
+        ),
+        None => router,
+    }
+}
+
+// Admin login issues a token too, but is part of the admin api
+const ADMIN_LOGIN_PATH: &str = "/api/admin/login";
+// The routes issuing tokens. Caches and proxies must never store their responses and an admin
+// token cant be exchanged on them for a token that would outlive it
+pub(crate) const TOKEN_PATHS: [&str; 4] = [
+    "/api/token",
+    "/api/identify",
+    "/api/identify/api_key",
+    ADMIN_LOGIN_PATH,
+];
+
+async fn security_headers(
+    axum::extract::State(headers): axum::extract::State<
+        std::sync::Arc<[(axum::http::HeaderName, axum::http::HeaderValue)]>,
+    >,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let no_store = TOKEN_PATHS.contains(&request.uri().path());
+    let mut response = next.run(request).await;
+
+    let response_headers = response.headers_mut();
+    for (name, value) in headers.iter() {
+        if !response_headers.contains_key(name) {
+            response_headers.insert(name.clone(), value.clone());
+        }
+    }
+    if no_store {
+        response_headers.insert(
+            axum::http::header::CACHE_CONTROL,
+            axum::http::HeaderValue::from_static("no-store"),
+        );
+    }
+
+    response
+}
+
+// Admin routes authenticate with Basic auth or an admin token, without the challenge nothing
+// prompts for them. Registries answer 401s of the token protocol with a Bearer challenge themselves
+async fn admin_challenge(
+    axum::extract::State(challenge): axum::extract::State<axum::http::HeaderValue>,
+    request: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    let path = request.uri().path();
+    let token_route = TOKEN_PATHS.contains(&path) && path != ADMIN_LOGIN_PATH;
+    let mut response = next.run(request).await;
+
+    if !token_route && response.status() == axum::http::StatusCode::UNAUTHORIZED {
+        response
+            .headers_mut()
+            .entry(axum::http::header::WWW_AUTHENTICATE)
+            .or_insert(challenge);
+    }
+
+    response
+}
+
+// Keep the timeout above the argon2 cost so slow but legitimate logins still pass
+fn timeout_layer(timeout: std::time::Duration) -> TimeoutLayer {
+    TimeoutLayer::with_status_code(axum::http::StatusCode::GATEWAY_TIMEOUT, timeout)
+}
+
+pub mod trace {
+    use std::sync::{Arc, Mutex};
+
+    use axum::{extract::Request, http::Extensions, middleware::Next, response::Response};
+    use tokio::time::Instant;
+    use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
+
+    #[cfg(feature = "otel")]
+    use crate::otel::layer as otel_layer;
+
+    // Without the otel feature there is nothing to export the spans to
+    #[cfg(not(feature = "otel"))]
+    fn otel_layer() -> crate::Result<Option<tracing_subscriber::layer::Identity>> {
+        Ok(None)
+    }
+
+    // The id the request is logged with, also the request_id attribute of its span
+    #[derive(Debug, Clone, Copy)]
+    pub struct RequestId(pub uuid::Uuid);
+
+    // Who made the request, the extractors fill it in once they authenticated it.
+    // Shared with the logging layer, which only sees the request before the handler runs
+    #[derive(Debug, Clone, Default)]
+    pub struct Principal(Arc<Mutex<Option<String>>>);
+
+    impl Principal {
+        // Only ever pass a name, never the credentials it was authenticated with
+        pub fn record(extensions: &Extensions, name: &str) {
+            if let Some(principal) = extensions.get::<Principal>()
+                && let Ok(mut slot) = principal.0.lock()
+            {
+                *slot = Some(name.to_string());
+            }
+        }
+
+        fn name(&self) -> String {
+            self.0
+                .lock()
+                .ok()
+                .and_then(|slot| slot.clone())
+                .unwrap_or_else(|| "anonymous".to_string())
+        }
+    }
+
+    pub async fn logging_layer(mut request: Request, next: Next) -> Response {
+        let method = request.method().to_string();
+        let route = request.uri().path().to_string();
+        let uuid = uuid::Uuid::new_v4();
+        let principal = Principal::default();
+        request.extensions_mut().insert(RequestId(uuid));
+        request.extensions_mut().insert(principal.clone());
+        tracing::info!(
+            "{:<12}- Handling {method} on {route} with id {uuid}",
+            "REQUEST"
+        );
+
+        let now = Instant::now();
+        let response = next.run(request).await;
+        let elapsed = now.elapsed().as_millis();
+
+        let status = response.status().to_string();
+        tracing::info!(
+            "{:<12}- {uuid} returned {status} in {elapsed} ms for {}",
+            "RESPONSE",
+            principal.name()
+        );
+
+        response
+    }
+
+    // Span names and attributes follow the OpenTelemetry HTTP server conventions
+    pub fn request_span(request: &Request) -> tracing::Span {
+        let request_id = request
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.0.to_string())
+            .unwrap_or_default();
+        let span = tracing::info_span!(
+            "request",
+            otel.name = %format!("{} {}", request.method(), request.uri().path()),
+            otel.kind = "server",
+            http.request.method = %request.method(),
+            url.path = %request.uri().path(),
+            request_id = %request_id,
+            http.response.status_code = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+        #[cfg(feature = "otel")]
+        crate::otel::set_remote_parent(&span, request.headers());
+
+        span
+    }
+
+    pub fn record_response(
+        response: &Response,
+        latency: std::time::Duration,
+        span: &tracing::Span,
+    ) {
+        span.record("http.response.status_code", response.status().as_u16());
+        span.record("latency_ms", latency.as_millis() as u64);
+    }
+
+    // The layer to add and the error to log once the subscriber is up
+    fn split<L>(layer: crate::Result<Option<L>>) -> (Option<L>, Option<crate::Error>) {
+        match layer {
+            Ok(layer) => (layer, None),
+            Err(e) => (None, Some(e)),
+        }
+    }
+
+    pub fn init_tracing() {
+        let sub = tracing_subscriber::fmt()
+            .with_target(false)
+            .with_env_filter(EnvFilter::from_default_env());
+
+        let otel_error = if cfg!(debug_assertions) {
+            let (otel, otel_error) = split(otel_layer());
+            sub.without_time()
+                .with_file(false)
+                .with_line_number(false)
+                .finish()
+                .with(otel)
+                .init();
+            otel_error
+        } else {
+            let (otel, otel_error) = split(otel_layer());
+            sub.json().finish().with(otel).init();
+            otel_error
+        };
+
+        // exporting spans is optional, so the server still starts and only logs why it cant
+        if let Some(e) = otel_error {
+            tracing::warn!("{:<12}- {}", "Tracing", e);
+        }
+    }
+
+    // Exports the spans still buffered, call before the process exits
+    pub fn shutdown_tracing() {
+        #[cfg(feature = "otel")]
+        crate::otel::shutdown();
+    }
+}
+
+#[tokio::test]
+async fn test_slow_handler_times_out() {
+    use axum::{body::Body, http::Request, routing::get};
+    use tower::ServiceExt;
+
+    let app: Router = Router::new()
+        .route(
+            "/",
+            get(|| async { tokio::time::sleep(std::time::Duration::from_secs(5)).await }),
+        )
+        .layer(timeout_layer(std::time::Duration::from_millis(50)));
+
+    let response = app
+        .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), axum::http::StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[tokio::test]
+async fn test_swagger_can_be_disabled() {
+    use axum::{body::Body, http::Request};
+    use tower::ServiceExt;
+
+    let status = |app: Router, uri: &'static str| async move {
+        app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    };
+
+    let enabled = mount_swagger(Router::new(), "", Some("/docs"), ApiDoc::openapi());
+    assert!(status(enabled.clone(), "/docs/").await.is_success());
+    assert!(status(enabled, "/api/openapi.json").await.is_success());
+
+    let disabled = mount_swagger(Router::new(), "", None, ApiDoc::openapi());
+    assert_eq!(
+        status(disabled.clone(), "/api/swagger/").await,
+        axum::http::StatusCode::NOT_FOUND
+    );
+    assert_eq!(
+        status(disabled, "/api/openapi.json").await,
+        axum::http::StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_routes_under_base_path() {
+    use axum::{body::Body, http::Request, routing::get};
+    use tower::ServiceExt;
+
+    let status = |app: Router, uri: &'static str| async move {
+        app.oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+            .status()
+    };
+
+    let router = Router::new().route("/api/cert.pem", get(|| async { "cert" }));
+    let app = mount_swagger(
+        nest_under(router, "/auth"),
+        "/auth",
+        Some("/api/swagger"),
+        ApiDoc::openapi(),
+    );
+
+    assert!(status(app.clone(), "/auth/api/cert.pem").await.is_success());
+    assert!(
+        status(app.clone(), "/auth/api/openapi.json")
+            .await
+            .is_success()
+    );
+    assert!(status(app.clone(), "/auth/api/swagger/").await.is_success());
+    assert_eq!(
+        status(app, "/api/cert.pem").await,
+        axum::http::StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn test_unknown_routes_return_not_found() {
+    use axum::{body::Body, http::Request, routing::get};
+    use tower::ServiceExt;
+
+    let body = |app: Router, uri: &'static str| async move {
+        let response = app
+            .oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let bytes = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(bytes.to_vec()).unwrap())
+    };
+    let expected = (
+        axum::http::StatusCode::NOT_FOUND,
+        "Route does not exist".to_string(),
+    );
+
+    let router = Router::new()
+        .route("/api/cert.pem", get(|| async { "cert" }))
+        .fallback(not_found);
+    assert_eq!(body(router.clone(), "/api/unknown").await, expected);
+
+    // inside and outside of the base path
+    let nested = nest_under(router, "/auth");
+    assert_eq!(body(nested.clone(), "/auth/api/unknown").await, expected);
+    assert_eq!(body(nested, "/unknown").await, expected);
+}
+
+#[tokio::test]
+async fn test_token_responses_are_no_store() {
+    use axum::{
+        body::Body,
+        http::{Request, header},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    let headers: std::sync::Arc<[_]> = vec![(
+        header::X_CONTENT_TYPE_OPTIONS,
+        axum::http::HeaderValue::from_static("nosniff"),
+    )]
+    .into();
+    let app: Router = Router::new()
+        .route("/api/token", get(|| async { "token" }))
+        .route(ADMIN_LOGIN_PATH, get(|| async { "admin token" }))
+        .route("/api/user", get(|| async { "users" }))
+        .layer(axum::middleware::from_fn_with_state(
+            headers,
+            security_headers,
+        ));
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri("/api/token")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+    assert_eq!(
+        response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+        "nosniff"
+    );
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(ADMIN_LOGIN_PATH)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.headers()[header::CACHE_CONTROL], "no-store");
+
+    let response = app
+        .oneshot(
+            Request::builder()
+                .uri("/api/user")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(!response.headers().contains_key(header::CACHE_CONTROL));
+    assert_eq!(
+        response.headers()[header::X_CONTENT_TYPE_OPTIONS],
+        "nosniff"
+    );
+}
+
+#[tokio::test]
+async fn test_admin_challenge_only_on_admin_routes() {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode, header},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    let unauthorized = || async { crate::Error::Unauthorized("no") };
+    let app: Router = Router::new()
+        .route("/api/token", get(unauthorized))
+        .route("/api/user", get(unauthorized))
+        .route(ADMIN_LOGIN_PATH, get(unauthorized))
+        .route("/api/users", get(|| async { "users" }))
+        .layer(axum::middleware::from_fn_with_state(
+            axum::http::HeaderValue::from_static(
+                "Basic realm=\"augesty\", charset=\"UTF-8\", Bearer realm=\"augesty\"",
+            ),
+            admin_challenge,
+        ));
+    let request = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+    let response = app.clone().oneshot(request("/api/user")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        response.headers()[header::WWW_AUTHENTICATE],
+        "Basic realm=\"augesty\", charset=\"UTF-8\", Bearer realm=\"augesty\""
+    );
+
+    // admin login issues a token but still prompts for Basic auth
+    let response = app
+        .clone()
+        .oneshot(request(ADMIN_LOGIN_PATH))
+        .await
+        .unwrap();
+    assert!(response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+    let response = app.clone().oneshot(request("/api/token")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert!(!response.headers().contains_key(header::WWW_AUTHENTICATE));
+
+    let response = app.oneshot(request("/api/users")).await.unwrap();
+    assert!(!response.headers().contains_key(header::WWW_AUTHENTICATE));
+}
+
+#[tokio::test]
+async fn test_response_log_names_the_principal() {
+    use axum::{
+        body::Body,
+        http::{Request, request::Parts},
+        routing::get,
+    };
+    use tower::ServiceExt;
+
+    let captured = crate::LogBuffer::default();
+    let writer = captured.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .finish();
+    // the test runtime is single threaded, so the handler logs to this subscriber too
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let app: Router = Router::new()
+        .route(
+            "/authenticated",
+            get(|parts: Parts| async move {
+                trace::Principal::record(&parts.extensions, "dev");
+            }),
+        )
+        .route("/public", get(|| async {}))
+        .layer(axum::middleware::from_fn(trace::logging_layer));
+    let request = |uri: &str| {
+        Request::builder()
+            .uri(uri)
+            .header("authorization", "Basic ZGV2OnNlY3JldA==")
+            .body(Body::empty())
+            .unwrap()
+    };
+    app.clone()
+        .oneshot(request("/authenticated"))
+        .await
+        .unwrap();
+    app.oneshot(request("/public")).await.unwrap();
+
+    let log = captured.contents();
+    let responses: Vec<&str> = log
+        .lines()
+        .filter(|line| line.contains("RESPONSE"))
+        .collect();
+    assert_eq!(responses.len(), 2);
+    assert!(responses[0].ends_with("for dev"));
+    assert!(responses[1].ends_with("for anonymous"));
+    assert!(!log.contains("ZGV2OnNlY3JldA=="));
+}
@@ -0,0 +1,172 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+// Upper bounds in seconds, argon2 lands in the tens of milliseconds and a JWKS fetch above
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthProvider {
+    // passwords and svc tokens
+    Basic,
+    // admin tokens from /api/admin/login
+    AdminToken,
+    GithubOidc,
+}
+
+impl AuthProvider {
+    const ALL: [Self; 3] = [Self::Basic, Self::AdminToken, Self::GithubOidc];
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Basic => "basic",
+            Self::AdminToken => "admin_token",
+            Self::GithubOidc => "github_oidc",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Series {
+    count: AtomicU64,
+    // in microseconds
+    sum: AtomicU64,
+    // not cumulative, summed up when rendering
+    buckets: [AtomicU64; BUCKETS.len()],
+}
+
+impl Series {
+    fn observe(&self, elapsed: Duration) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let micros = u64::try_from(elapsed.as_micros()).unwrap_or(u64::MAX);
+        self.sum.fetch_add(micros, Ordering::Relaxed);
+        let seconds = elapsed.as_secs_f64();
+        if let Some(index) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+// Outcome and latency of every authentication, by provider. Lock free so recording never
+// waits on a scrape
+#[derive(Default)]
+pub struct AuthMetrics {
+    // indexed like AuthProvider::ALL, then failure and success
+    series: [[Series; 2]; AuthProvider::ALL.len()],
+}
+
+impl AuthMetrics {
+    pub fn record(&self, provider: AuthProvider, success: bool, elapsed: Duration) {
+        self.series(provider, success).observe(elapsed);
+    }
+
+    fn series(&self, provider: AuthProvider, success: bool) -> &Series {
+        let index = AuthProvider::ALL
+            .iter()
+            .position(|known| *known == provider)
+            .unwrap_or_default();
+        &self.series[index][usize::from(success)]
+    }
+
+    pub fn count(&self, provider: AuthProvider, success: bool) -> u64 {
+        self.series(provider, success).count.load(Ordering::Relaxed)
+    }
+
+    // The Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let labeled = |provider: AuthProvider, success: bool| {
+            let outcome = if success { "success" } else { "failure" };
+            format!(r#"provider="{}",outcome="{outcome}""#, provider.label())
+        };
+        let every = || {
+            AuthProvider::ALL
+                .into_iter()
+                .flat_map(|provider| [(provider, true), (provider, false)])
+        };
+
+        _ = writeln!(
+            out,
+            "# HELP augesty_auth_total Authentication attempts by provider and outcome"
+        );
+        _ = writeln!(out, "# TYPE augesty_auth_total counter");
+        for (provider, success) in every() {
+            _ = writeln!(
+                out,
+                "augesty_auth_total{{{}}} {}",
+                labeled(provider, success),
+                self.count(provider, success)
+            );
+        }
+
+        _ = writeln!(
+            out,
+            "# HELP augesty_auth_duration_seconds Time spent authenticating by provider and outcome"
+        );
+        _ = writeln!(out, "# TYPE augesty_auth_duration_seconds histogram");
+        for (provider, success) in every() {
+            let series = self.series(provider, success);
+            let labels = labeled(provider, success);
+            let mut cumulative = 0;
+            for (bound, bucket) in BUCKETS.iter().zip(&series.buckets) {
+                cumulative += bucket.load(Ordering::Relaxed);
+                _ = writeln!(
+                    out,
+                    r#"augesty_auth_duration_seconds_bucket{{{labels},le="{bound}"}} {cumulative}"#
+                );
+            }
+            let count = series.count.load(Ordering::Relaxed);
+            _ = writeln!(
+                out,
+                r#"augesty_auth_duration_seconds_bucket{{{labels},le="+Inf"}} {count}"#
+            );
+            _ = writeln!(
+                out,
+                "augesty_auth_duration_seconds_sum{{{labels}}} {}",
+                series.sum.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+            _ = writeln!(
+                out,
+                "augesty_auth_duration_seconds_count{{{labels}}} {count}"
+            );
+        }
+
+        out
+    }
+}
+
+#[test]
+fn test_auth_metrics_render() {
+    let metrics = AuthMetrics::default();
+    metrics.record(AuthProvider::Basic, true, Duration::from_millis(30));
+    metrics.record(AuthProvider::Basic, true, Duration::from_millis(70));
+    metrics.record(AuthProvider::GithubOidc, false, Duration::from_secs(10));
+
+    let rendered = metrics.render();
+    assert!(rendered.contains(r#"augesty_auth_total{provider="basic",outcome="success"} 2"#));
+    assert!(rendered.contains(r#"augesty_auth_total{provider="basic",outcome="failure"} 0"#));
+    assert!(rendered.contains(r#"augesty_auth_total{provider="github_oidc",outcome="failure"} 1"#));
+    assert!(rendered.contains(
+        r#"augesty_auth_duration_seconds_bucket{provider="basic",outcome="success",le="0.025"} 0"#
+    ));
+    assert!(rendered.contains(
+        r#"augesty_auth_duration_seconds_bucket{provider="basic",outcome="success",le="0.05"} 1"#
+    ));
+    assert!(rendered.contains(
+        r#"augesty_auth_duration_seconds_bucket{provider="basic",outcome="success",le="0.1"} 2"#
+    ));
+    assert!(
+        rendered.contains(
+            r#"augesty_auth_duration_seconds_sum{provider="basic",outcome="success"} 0.1"#
+        )
+    );
+    // slower than every bucket only shows in +Inf
+    assert!(rendered.contains(
+        r#"augesty_auth_duration_seconds_bucket{provider="github_oidc",outcome="failure",le="5"} 0"#
+    ));
+    assert!(rendered.contains(
+        r#"augesty_auth_duration_seconds_bucket{provider="github_oidc",outcome="failure",le="+Inf"} 1"#
+    ));
+}
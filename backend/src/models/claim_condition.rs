@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use sqlx::prelude::FromRow;
+
+use crate::{
+    error::unique_violation,
+    models::permission::{Permission, PermissionType},
+};
+
+// A claim the OIDC token of a service account has to match before an action is usable,
+// e.g. push only when `ref` matches `refs/tags/*`
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct ClaimCondition {
+    pub id: Option<i64>,
+    pub user_id: i64,
+    pub permission: PermissionType,
+    pub claim: String,
+    pub pattern: String,
+}
+
+impl ClaimCondition {
+    // `*` in the pattern matches any run of characters, everything else has to be equal
+    pub fn matches(pattern: &str, value: &str) -> bool {
+        let mut parts = pattern.split('*');
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = value.strip_prefix(first) else {
+            return false;
+        };
+        let mut parts: Vec<&str> = parts.collect();
+        let Some(last) = parts.pop() else {
+            // no `*` at all
+            return rest.is_empty();
+        };
+        for part in parts {
+            match rest.find(part) {
+                Some(index) => rest = &rest[index + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+
+    pub fn holds(&self, claims: &BTreeMap<String, String>) -> bool {
+        claims
+            .get(&self.claim)
+            .is_some_and(|value| Self::matches(&self.pattern, value))
+    }
+
+    // Drops the actions with a condition the claims do not meet
+    pub fn narrow(
+        permissions: Vec<Permission>,
+        conditions: &[ClaimCondition],
+        claims: &BTreeMap<String, String>,
+    ) -> Vec<Permission> {
+        permissions
+            .into_iter()
+            .filter(|perm| {
+                conditions
+                    .iter()
+                    .filter(|condition| condition.permission == perm.permission)
+                    .all(|condition| condition.holds(claims))
+            })
+            .collect()
+    }
+
+    // Only docker actions a CI job uses can be conditional
+    pub fn validate(permission: &PermissionType, claim: &str) -> crate::Result<()> {
+        if !matches!(permission, PermissionType::Pull | PermissionType::Push) {
+            return Err(crate::Error::BadRequest(
+                "Conditions only apply to pull and push",
+            ));
+        }
+        if claim.is_empty() {
+            return Err(crate::Error::BadRequest("Claim must be non-empty"));
+        }
+        Ok(())
+    }
+
+    pub async fn list_for_user(user_id: i64, pool: &sqlx::SqlitePool) -> crate::Result<Vec<Self>> {
+        let conditions = sqlx::query_as!(
+            ClaimCondition,
+            "SELECT * FROM claim_conditions WHERE user_id = ?",
+            user_id
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(conditions)
+    }
+
+    pub async fn insert(&self, pool: &sqlx::SqlitePool) -> crate::Result<()> {
+        let permission = self.permission.to_string();
+        sqlx::query!(
+            "INSERT INTO claim_conditions (user_id, permission, claim, pattern) VALUES (?, ?, ?, ?)",
+            self.user_id,
+            permission,
+            self.claim,
+            self.pattern
+        )
+        .execute(pool)
+        .await
+        .map_err(unique_violation("Condition already exists"))?;
+        Ok(())
+    }
+
+    pub async fn delete(&self, pool: &sqlx::SqlitePool) -> crate::Result<()> {
+        let permission = self.permission.to_string();
+        let deleted = sqlx::query!(
+            "DELETE FROM claim_conditions WHERE user_id = ? AND permission = ? AND claim = ? AND pattern = ?",
+            self.user_id,
+            permission,
+            self.claim,
+            self.pattern
+        )
+        .execute(pool)
+        .await?
+        .rows_affected();
+        if deleted == 0 {
+            return Err(crate::Error::NotFound("Condition does not exist"));
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pattern_matching() {
+    assert!(ClaimCondition::matches(
+        "refs/heads/main",
+        "refs/heads/main"
+    ));
+    assert!(!ClaimCondition::matches(
+        "refs/heads/main",
+        "refs/heads/main2"
+    ));
+    assert!(ClaimCondition::matches("refs/tags/*", "refs/tags/v1.0.0"));
+    assert!(!ClaimCondition::matches("refs/tags/*", "refs/heads/main"));
+    assert!(ClaimCondition::matches(
+        "*/release.yaml@*",
+        "example/repo/.github/workflows/release.yaml@refs/tags/v1"
+    ));
+    assert!(!ClaimCondition::matches("refs/*/v*", "refs/tags/1.0"));
+    assert!(ClaimCondition::matches("*", ""));
+}
+
+#[test]
+fn test_conditions_narrow_permissions() {
+    let grant = |permission| Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "example/image".to_string(),
+        permission,
+    };
+    let permissions = vec![grant(PermissionType::Pull), grant(PermissionType::Push)];
+    let conditions = vec![ClaimCondition {
+        id: None,
+        user_id: 1,
+        permission: PermissionType::Push,
+        claim: "ref".to_string(),
+        pattern: "refs/tags/*".to_string(),
+    }];
+    let claims = |git_ref: &str| BTreeMap::from([("ref".to_string(), git_ref.to_string())]);
+
+    // a tag build keeps push
+    assert_eq!(
+        ClaimCondition::narrow(permissions.clone(), &conditions, &claims("refs/tags/v1")),
+        permissions
+    );
+    // a branch build only pulls
+    assert_eq!(
+        ClaimCondition::narrow(permissions.clone(), &conditions, &claims("refs/heads/main")),
+        vec![grant(PermissionType::Pull)]
+    );
+    // a missing claim never matches
+    assert_eq!(
+        ClaimCondition::narrow(permissions, &conditions, &BTreeMap::new()),
+        vec![grant(PermissionType::Pull)]
+    );
+}
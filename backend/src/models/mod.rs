@@ -1,4 +1,6 @@
+pub mod claim_condition;
 pub mod permission;
+pub mod svc_api_key;
 pub mod user;
 pub mod user_identifier;
 pub mod user_pw_hash;
@@ -5,24 +5,56 @@ use sqlx::prelude::{FromRow, Type};
 #[derive(Debug, Clone, PartialEq, Eq, FromRow)]
 pub struct Permission {
     pub id: Option<i64>,
+    pub kind: String,
     pub subject: String,
     pub permission: PermissionType,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Type, serde::Serialize, serde::Deserialize)]
+// Requests without a kind are about repositories, like a docker scope without a type would be
+pub fn default_kind() -> String {
+    "repository".to_string()
+}
+
+#[derive(
+    Debug, Clone, PartialEq, Eq, Type, serde::Serialize, serde::Deserialize, utoipa::ToSchema,
+)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
 #[serde(rename_all = "lowercase")]
 pub enum PermissionType {
     Pull,
     Push,
+    // Read access to the listing endpoints, never part of a docker scope
+    Audit,
+    // The `*` action docker uses for `registry:catalog:*`, never stored
+    #[sqlx(rename = "*")]
+    #[serde(rename = "*")]
+    Wildcard,
 }
 
 impl PermissionType {
+    // Every action that can appear in a docker scope
+    pub const ACTIONS: [PermissionType; 3] = [Self::Pull, Self::Push, Self::Wildcard];
+
     pub fn from_actions(s: &str) -> crate::Result<Self> {
         match s {
             "pull" => Ok(PermissionType::Pull),
             "push" => Ok(PermissionType::Push),
-            _ => Err(crate::Error::BadRequest("Unknown action")),
+            "*" => Ok(PermissionType::Wildcard),
+            _ => Err(crate::Error::Parse("Unknown action")),
+        }
+    }
+}
+
+impl std::str::FromStr for PermissionType {
+    type Err = crate::Error;
+
+    // Parses the permissions that can be granted, `*` only exists in scopes
+    fn from_str(s: &str) -> crate::Result<Self> {
+        match s {
+            "pull" => Ok(Self::Pull),
+            "push" => Ok(Self::Push),
+            "audit" => Ok(Self::Audit),
+            _ => Err(crate::Error::Parse("Unknown permission")),
         }
     }
 }
@@ -32,6 +64,7 @@ impl From<String> for PermissionType {
         match value.as_str() {
             "pull" => Self::Pull,
             "push" => Self::Push,
+            "audit" => Self::Audit,
             other => panic!("Unknown permission type {other}"), // should not happen bc of schema constraints
         }
     }
@@ -42,13 +75,217 @@ impl Display for PermissionType {
         let text = match self {
             &Self::Pull => "pull",
             &Self::Push => "push",
+            &Self::Audit => "audit",
+            &Self::Wildcard => "*",
         };
         write!(f, "{}", text)
     }
 }
 
+// Registry names are case sensitive, lowercasing is opt-in and can merge distinct repositories
+pub fn normalize_subject(subject: &str, lowercase: bool) -> String {
+    if lowercase {
+        subject.to_lowercase()
+    } else {
+        subject.to_string()
+    }
+}
+
+// One grant of one user, the flat row of the audit export
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, serde::Serialize, utoipa::ToSchema)]
+pub struct UserGrant {
+    pub user_name: String,
+    pub kind: String,
+    pub subject: String,
+    pub permission: PermissionType,
+}
+
+// The actions of one user on one subject, for UIs showing access per repository
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, utoipa::ToSchema)]
+pub struct GroupedGrant {
+    pub user_name: String,
+    pub kind: String,
+    pub subject: String,
+    pub permissions: Vec<PermissionType>,
+}
+
+impl GroupedGrant {
+    // Expects the grants ordered by user, kind and subject like list_all_with_users returns them
+    pub fn group(grants: Vec<UserGrant>) -> Vec<Self> {
+        let mut groups: Vec<Self> = Vec::new();
+        for grant in grants {
+            match groups.last_mut() {
+                Some(group)
+                    if group.user_name == grant.user_name
+                        && group.kind == grant.kind
+                        && group.subject == grant.subject =>
+                {
+                    group.permissions.push(grant.permission);
+                }
+                _ => groups.push(Self {
+                    user_name: grant.user_name,
+                    kind: grant.kind,
+                    subject: grant.subject,
+                    permissions: vec![grant.permission],
+                }),
+            }
+        }
+        groups
+    }
+}
+
+impl Permission {
+    pub async fn list_all_with_users(
+        limit: i64,
+        offset: i64,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<Vec<UserGrant>> {
+        let grants = sqlx::query_as!(
+            UserGrant,
+            r"
+            SELECT u.name AS user_name, p.kind, p.subject, p.permission
+            FROM users u
+            JOIN user_permissions up ON u.id = up.user_id
+            JOIN permissions p ON up.permission_id = p.id
+            ORDER BY u.name, p.kind, p.subject, p.permission
+            LIMIT ? OFFSET ?;
+            ",
+            limit,
+            offset
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(grants)
+    }
+
+    // Distinct subjects starting with the prefix, compared literally without LIKE wildcards
+    pub async fn list_subjects(
+        prefix: &str,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<Vec<String>> {
+        let subjects = sqlx::query_scalar!(
+            r"
+            SELECT DISTINCT subject
+            FROM permissions
+            WHERE substr(subject, 1, length(?)) = ?
+            ORDER BY subject;
+            ",
+            prefix,
+            prefix
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(subjects)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, FromRow)]
 pub struct UserPermission {
     pub user_id: i64,
     pub permission_id: i64,
 }
+
+#[tokio::test]
+async fn test_list_all_with_users() -> crate::Result<()> {
+    use crate::models::user::User;
+
+    let pool = crate::test_pool().await?;
+    for (name, subject, permission) in [
+        ("ci", "example/image", PermissionType::Push),
+        ("dev", "example/image", PermissionType::Pull),
+        ("dev", "other/image", PermissionType::Pull),
+    ] {
+        if User::find_by_name(name, &pool).await.is_err() {
+            User::new_user(name.to_string()).insert(&pool).await?;
+        }
+        User::find_by_name(name, &pool)
+            .await?
+            .add_permission(
+                "repository".to_string(),
+                subject.to_string(),
+                permission,
+                &pool,
+            )
+            .await?;
+    }
+
+    let grants = Permission::list_all_with_users(10, 0, &pool).await?;
+    let users: Vec<&str> = grants
+        .iter()
+        .map(|grant| grant.user_name.as_str())
+        .collect();
+    assert_eq!(users, vec!["ci", "dev", "dev"]);
+    assert_eq!(grants[0].permission, PermissionType::Push);
+
+    let page = Permission::list_all_with_users(1, 2, &pool).await?;
+    assert_eq!(page.len(), 1);
+    assert_eq!(page[0].subject, "other/image");
+
+    Ok(())
+}
+
+#[test]
+fn test_grants_group_by_subject() {
+    let grant = |user_name: &str, subject: &str, permission| UserGrant {
+        user_name: user_name.to_string(),
+        kind: "repository".to_string(),
+        subject: subject.to_string(),
+        permission,
+    };
+    let grants = vec![
+        grant("ci", "example/image", PermissionType::Pull),
+        grant("ci", "example/image", PermissionType::Push),
+        grant("ci", "other/image", PermissionType::Pull),
+        grant("dev", "example/image", PermissionType::Pull),
+    ];
+
+    let grouped = GroupedGrant::group(grants);
+    assert_eq!(grouped.len(), 3);
+    assert_eq!(grouped[0].user_name, "ci");
+    assert_eq!(grouped[0].subject, "example/image");
+    assert_eq!(
+        grouped[0].permissions,
+        vec![PermissionType::Pull, PermissionType::Push]
+    );
+    assert_eq!(grouped[1].permissions, vec![PermissionType::Pull]);
+    // the same subject of another user is its own group
+    assert_eq!(grouped[2].user_name, "dev");
+    assert_eq!(grouped[2].permissions, vec![PermissionType::Pull]);
+}
+
+#[tokio::test]
+async fn test_list_subjects() -> crate::Result<()> {
+    use crate::models::user::User;
+
+    let pool = crate::test_pool().await?;
+    for (name, subject, permission) in [
+        ("ci", "example/image", PermissionType::Push),
+        ("dev", "example/image", PermissionType::Pull),
+        ("dev", "example_x/image", PermissionType::Pull),
+        ("dev", "other/image", PermissionType::Pull),
+    ] {
+        if User::find_by_name(name, &pool).await.is_err() {
+            User::new_user(name.to_string()).insert(&pool).await?;
+        }
+        User::find_by_name(name, &pool)
+            .await?
+            .add_permission(
+                "repository".to_string(),
+                subject.to_string(),
+                permission,
+                &pool,
+            )
+            .await?;
+    }
+
+    let subjects = Permission::list_subjects("", &pool).await?;
+    assert_eq!(
+        subjects,
+        vec!["example/image", "example_x/image", "other/image"]
+    );
+
+    let subjects = Permission::list_subjects("example/", &pool).await?;
+    assert_eq!(subjects, vec!["example/image"]);
+
+    Ok(())
+}
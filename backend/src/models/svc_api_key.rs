@@ -0,0 +1,62 @@
+use sqlx::prelude::FromRow;
+
+// The hashed API key a service account exchanges for a svc token without OIDC
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct SvcApiKey {
+    pub user_id: i64,
+    pub key_hash: String,
+}
+
+impl SvcApiKey {
+    pub async fn find(user_id: i64, pool: &sqlx::SqlitePool) -> crate::Result<Option<Self>> {
+        let key = sqlx::query_as!(
+            SvcApiKey,
+            "SELECT user_id, key_hash FROM svc_api_keys WHERE user_id = ?",
+            user_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(key)
+    }
+
+    // Replaces an existing key, so setting a new one rotates it
+    pub async fn set(&self, pool: &sqlx::SqlitePool) -> crate::Result<()> {
+        sqlx::query!(
+            r"
+            INSERT INTO svc_api_keys (user_id, key_hash) VALUES (?, ?)
+            ON CONFLICT(user_id) DO UPDATE SET key_hash = excluded.key_hash
+            ",
+            self.user_id,
+            self.key_hash
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_set_rotates_the_key() -> crate::Result<()> {
+    use crate::models::user::User;
+
+    let pool = crate::test_pool().await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    let svc = User::find_by_name("ci", &pool).await?;
+    let user_id = svc.id.ok_or(crate::Error::Opaque("User ID must exist"))?;
+
+    assert_eq!(SvcApiKey::find(user_id, &pool).await?, None);
+    let key = |key_hash: &str| SvcApiKey {
+        user_id,
+        key_hash: key_hash.to_string(),
+    };
+    key("first").set(&pool).await?;
+    key("second").set(&pool).await?;
+    assert_eq!(SvcApiKey::find(user_id, &pool).await?, Some(key("second")));
+
+    User::delete_by_id(user_id, &pool).await?;
+    assert_eq!(SvcApiKey::find(user_id, &pool).await?, None);
+
+    Ok(())
+}
@@ -3,7 +3,15 @@ use sqlx::{
     prelude::{FromRow, Type},
 };
 
-use crate::models::{permission::Permission, user_identifier::UserIdentifier};
+use crate::{
+    error::unique_violation,
+    models::{
+        permission::{Permission, PermissionType},
+        user_identifier::{MatchStrategy, UserIdentifier},
+    },
+};
+
+const MAX_NAME_LENGTH: usize = 64;
 
 #[derive(Debug, Clone, PartialEq, Eq, FromRow)]
 pub struct User {
@@ -12,8 +20,9 @@ pub struct User {
     pub user_type: UserType,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Type)]
+#[derive(Debug, Clone, PartialEq, Eq, Type, serde::Serialize, utoipa::ToSchema)]
 #[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum UserType {
     User,
     ServiceAccount,
@@ -50,7 +59,31 @@ impl User {
             pw_hash
         )
         .execute(pool)
-        .await?;
+        .await
+        .map_err(unique_violation("User already has a password"))?;
+        Ok(())
+    }
+
+    // Names end up in logs, the sub claim and basic auth, so only a plain charset is allowed
+    pub fn validate_name(name: &str) -> crate::Result<()> {
+        if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+            return Err(crate::Error::BadRequest(
+                "Name must be between 1 and 64 characters",
+            ));
+        }
+        if !name.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+            return Err(crate::Error::BadRequest(
+                "Name must start with a letter or digit",
+            ));
+        }
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(crate::Error::BadRequest(
+                "Name may only contain letters, digits, -, _ and .",
+            ));
+        }
         Ok(())
     }
 
@@ -63,116 +96,262 @@ impl User {
     }
 
     pub async fn find_by_name(name: &str, pool: &sqlx::SqlitePool) -> crate::Result<User> {
-        let user = sqlx::query_as!(User, "SELECT * FROM users WHERE name = ?", name)
-            .fetch_one(pool)
-            .await?;
+        let user = sqlx::query_as!(
+            User,
+            "SELECT id, name, user_type FROM users WHERE name = ?",
+            name
+        )
+        .fetch_one(pool)
+        .await?;
 
         Ok(user)
     }
 
+    // Counting and inserting in one statement keeps concurrent adds from passing the cap
     pub async fn add_user_identifier(
         &self,
         identifier: &str,
+        strategy: MatchStrategy,
+        max_identifiers: usize,
         pool: &sqlx::SqlitePool,
     ) -> crate::Result<()> {
         if self.user_type != UserType::ServiceAccount {
             return Err(crate::Error::BadRequest("Cannot add identifier to user"));
         }
 
-        sqlx::query!(
-            "INSERT INTO user_identifiers (user_id, identifier) VALUES (?, ?)",
+        let max_identifiers = i64::try_from(max_identifiers).unwrap_or(i64::MAX);
+        let strategy = strategy.to_string();
+        let inserted = sqlx::query!(
+            r"
+            INSERT INTO user_identifiers (user_id, identifier, match_strategy)
+            SELECT ?, ?, ?
+            WHERE (SELECT COUNT(*) FROM user_identifiers WHERE user_id = ?) < ?
+            ",
+            self.id,
+            identifier,
+            strategy,
             self.id,
-            identifier
+            max_identifiers
         )
         .execute(pool)
-        .await?;
+        .await
+        .map_err(unique_violation("Identifier already exists"))?
+        .rows_affected();
+        if inserted == 0 {
+            return Err(crate::Error::BadRequest(
+                "Service account has too many identifiers",
+            ));
+        }
         Ok(())
     }
 
-    pub async fn get_identifiers(&self, pool: &sqlx::SqlitePool) -> crate::Result<Vec<String>> {
+    // Swaps all identifiers at once, a failed insert keeps the old ones
+    pub async fn replace_identifiers(
+        &self,
+        identifiers: &[String],
+        max_identifiers: usize,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<()> {
+        if self.user_type != UserType::ServiceAccount {
+            return Err(crate::Error::BadRequest("Cannot add identifier to user"));
+        }
+        // an empty list would silently leave the service account unable to log in
+        if identifiers.is_empty() {
+            return Err(crate::Error::BadRequest(
+                "Service account needs at least one identifier",
+            ));
+        }
+        if identifiers.len() > max_identifiers {
+            return Err(crate::Error::BadRequest(
+                "Service account has too many identifiers",
+            ));
+        }
+
+        let mut tx: Transaction<'_, Sqlite> = pool.begin().await?;
+        sqlx::query!("DELETE FROM user_identifiers WHERE user_id = ?", self.id)
+            .execute(&mut *tx)
+            .await?;
+        for identifier in identifiers {
+            sqlx::query!(
+                "INSERT INTO user_identifiers (user_id, identifier) VALUES (?, ?)",
+                self.id,
+                identifier
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(unique_violation("Identifier already exists"))?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    // Whether any identifier trusts one of the candidates, one indexed lookup per exact or
+    // prefix match, regexes have to be tried one by one
+    pub async fn has_identifier(
+        &self,
+        candidates: &[&str],
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<bool> {
+        if self.user_type != UserType::ServiceAccount {
+            return Err(crate::Error::BadRequest("User is not ServiceAccount"));
+        }
+
+        for identifier in candidates
+            .iter()
+            .flat_map(|candidate| UserIdentifier::matching_identifiers(candidate))
+        {
+            let found = sqlx::query!(
+                "SELECT id FROM user_identifiers WHERE user_id = ? AND identifier = ? AND match_strategy != 'regex'",
+                self.id,
+                identifier
+            )
+            .fetch_optional(pool)
+            .await?
+            .is_some();
+            if found {
+                return Ok(true);
+            }
+        }
+
+        let patterns = sqlx::query_scalar!(
+            "SELECT identifier FROM user_identifiers WHERE user_id = ? AND match_strategy = 'regex'",
+            self.id
+        )
+        .fetch_all(pool)
+        .await?;
+        Ok(patterns.iter().any(|pattern| {
+            candidates
+                .iter()
+                .any(|candidate| UserIdentifier::matches(pattern, MatchStrategy::Regex, candidate))
+        }))
+    }
+
+    // With their match strategy, `example/app` reads differently as an exact name and a regex
+    pub async fn get_identifiers(
+        &self,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<Vec<UserIdentifier>> {
         if self.user_type != UserType::ServiceAccount {
             return Err(crate::Error::BadRequest("User is not ServiceAccount"));
         }
 
         let identifiers = sqlx::query_as!(
             UserIdentifier,
-            "SELECT * FROM user_identifiers WHERE user_id = ?",
+            "SELECT * FROM user_identifiers WHERE user_id = ? ORDER BY identifier",
             self.id
         )
         .fetch_all(pool)
-        .await?
-        .into_iter()
-        .map(|ident| ident.identifier)
-        .collect();
+        .await?;
 
         Ok(identifiers)
     }
 
     pub async fn insert(&self, pool: &sqlx::SqlitePool) -> crate::Result<()> {
-        sqlx::query!(
-            "INSERT INTO users (id, name, user_type) VALUES (?, ?, ?)",
+        let inserted = sqlx::query!(
+            "INSERT INTO users (id, name, user_type) SELECT ?, ?, ? WHERE NOT EXISTS (SELECT 1 FROM users WHERE external_id = ?)",
             self.id,
             self.name,
-            self.user_type
+            self.user_type,
+            self.name
         )
         .execute(pool)
-        .await?;
+        .await
+        .map_err(unique_violation("Name is already taken"))?;
+        if inserted.rows_affected() == 0 {
+            return Err(crate::Error::BadRequest("Name is already taken"));
+        }
+        Ok(())
+    }
+
+    // Inserts the user and its first grants in one transaction, so it never exists without them
+    pub async fn insert_with_permissions(
+        &self,
+        permissions: &[Permission],
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<()> {
+        let mut tx: Transaction<'_, Sqlite> = pool.begin().await?;
+        let inserted = sqlx::query!(
+            "INSERT INTO users (id, name, user_type) SELECT ?, ?, ? WHERE NOT EXISTS (SELECT 1 FROM users WHERE external_id = ?)",
+            self.id,
+            self.name,
+            self.user_type,
+            self.name
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(unique_violation("Name is already taken"))?;
+        if inserted.rows_affected() == 0 {
+            return Err(crate::Error::BadRequest("Name is already taken"));
+        }
+        let user_id = inserted.last_insert_rowid();
+        for perm in permissions {
+            insert_grant(
+                &mut tx,
+                user_id,
+                &perm.kind,
+                &perm.subject,
+                &perm.permission,
+            )
+            .await?;
+        }
+        tx.commit().await?;
+
         Ok(())
     }
 
     pub async fn add_permission(
         &self,
+        kind: String,
         subject: String,
-        permission_type: String,
+        permission_type: PermissionType,
         pool: &sqlx::SqlitePool,
     ) -> crate::Result<()> {
         let user_id = match self.id {
             Some(id) => id,
             None => return Err(crate::Error::Opaque("Missing user_id")), // should not happen
         };
+        if permission_type == PermissionType::Wildcard {
+            return Err(crate::Error::BadRequest("The * action cannot be granted"));
+        }
 
         let mut tx: Transaction<'_, Sqlite> = pool.begin().await?;
-        let perm_str = permission_type.to_string();
-        sqlx::query!(
-            r#"
-            INSERT OR IGNORE INTO permissions (subject, permission)
-            VALUES (?, ?)
-            "#,
-            subject,
-            perm_str,
-        )
-        .execute(&mut *tx)
-        .await?;
+        insert_grant(&mut tx, user_id, &kind, &subject, &permission_type).await?;
+        tx.commit().await?;
 
-        let permission = sqlx::query_as!(
-            Permission,
-            r#"
-            SELECT * FROM permissions
-            WHERE subject = ? AND permission = ?
-            "#,
-            subject,
-            perm_str,
-        )
-        .fetch_one(&mut *tx)
-        .await?;
+        Ok(())
+    }
 
-        let permission_id = match permission.id {
+    pub async fn remove_permission(
+        &self,
+        kind: String,
+        subject: String,
+        permission_type: String,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<()> {
+        let user_id = match self.id {
             Some(id) => id,
-            None => return Err(crate::Error::Opaque("Missing permission_id")), // should not happen
+            None => return Err(crate::Error::Opaque("Missing user_id")), // should not happen
         };
 
-        sqlx::query!(
+        let result = sqlx::query!(
             r#"
-            INSERT OR IGNORE INTO user_permissions (user_id, permission_id)
-            VALUES (?, ?)
+            DELETE FROM user_permissions
+            WHERE user_id = ? AND permission_id = (
+                SELECT id FROM permissions WHERE kind = ? AND subject = ? AND permission = ?
+            )
             "#,
             user_id,
-            permission_id,
+            kind,
+            subject,
+            permission_type,
         )
-        .execute(&mut *tx)
+        .execute(pool)
         .await?;
 
-        tx.commit().await?;
+        if result.rows_affected() == 0 {
+            return Err(crate::Error::NotFound("Permission not granted"));
+        }
 
         Ok(())
     }
@@ -184,6 +363,45 @@ impl User {
         Ok(users)
     }
 
+    // Removes every grant of the user and returns them, the shared permissions rows stay
+    pub async fn clear_permissions(
+        &self,
+        keep_wildcards: bool,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<Vec<crate::models::permission::Permission>> {
+        let user_id = match self.id {
+            Some(id) => id,
+            None => return Err(crate::Error::Opaque("Missing user_id")), // should not happen
+        };
+
+        let mut tx: Transaction<'_, Sqlite> = pool.begin().await?;
+        let removed = sqlx::query_as!(
+            crate::models::permission::Permission,
+            r"
+            SELECT p.id, p.kind, p.subject, p.permission
+            FROM user_permissions up
+            JOIN permissions p ON up.permission_id = p.id
+            WHERE up.user_id = ? AND NOT (? AND p.subject = '*')
+            ",
+            user_id,
+            keep_wildcards
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+        for permission in &removed {
+            sqlx::query!(
+                "DELETE FROM user_permissions WHERE user_id = ? AND permission_id = ?",
+                user_id,
+                permission.id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        Ok(removed)
+    }
+
     pub async fn list_permissions(
         &self,
         pool: &sqlx::SqlitePool,
@@ -191,11 +409,12 @@ impl User {
         let permissions = sqlx::query_as!(
             crate::models::permission::Permission,
             r"
-            SELECT p.id, p.subject, p.permission
+            SELECT p.id, p.kind, p.subject, p.permission
             FROM users u
             JOIN user_permissions up ON u.id = up.user_id
             JOIN permissions p ON up.permission_id = p.id
-            WHERE u.name = ?;
+            WHERE u.name = ?
+            ORDER BY p.kind, p.subject, p.permission;
             ",
             self.name
         )
@@ -204,6 +423,94 @@ impl User {
         Ok(permissions)
     }
 
+    pub async fn external_id(&self, pool: &sqlx::SqlitePool) -> crate::Result<Option<String>> {
+        let external_id =
+            sqlx::query_scalar!("SELECT external_id FROM users WHERE id = ?", self.id)
+                .fetch_one(pool)
+                .await?;
+        Ok(external_id)
+    }
+
+    // None clears the id, the token subject then falls back to the name. Names and external ids
+    // share the subject namespace, so an id naming another user is taken as well
+    pub async fn set_external_id(
+        &self,
+        external_id: Option<&str>,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<()> {
+        let updated = sqlx::query!(
+            "UPDATE users SET external_id = ? WHERE id = ? AND NOT EXISTS (SELECT 1 FROM users WHERE name = ? AND id != ?)",
+            external_id,
+            self.id,
+            external_id,
+            self.id
+        )
+        .execute(pool)
+        .await
+        .map_err(unique_violation("External id is already taken"))?;
+        if updated.rows_affected() == 0 {
+            return Err(crate::Error::BadRequest("External id is already taken"));
+        }
+        Ok(())
+    }
+
+    pub async fn find_by_external_id(
+        external_id: &str,
+        pool: &sqlx::SqlitePool,
+    ) -> crate::Result<Option<User>> {
+        let user = sqlx::query_as!(
+            User,
+            "SELECT id, name, user_type FROM users WHERE external_id = ?",
+            external_id
+        )
+        .fetch_optional(pool)
+        .await?;
+        Ok(user)
+    }
+
+    pub async fn token_epoch(&self, pool: &sqlx::SqlitePool) -> crate::Result<i64> {
+        let epoch =
+            sqlx::query_scalar!("SELECT epoch FROM token_epochs WHERE user_id = ?", self.id)
+                .fetch_optional(pool)
+                .await?;
+        Ok(epoch.unwrap_or(0))
+    }
+
+    // Invalidates every token issued to the user so far and returns the new epoch
+    pub async fn bump_token_epoch(&self, pool: &sqlx::SqlitePool) -> crate::Result<i64> {
+        let epoch = sqlx::query_scalar!(
+            r"
+            INSERT INTO token_epochs (user_id, epoch) VALUES (?, 1)
+            ON CONFLICT(user_id) DO UPDATE SET epoch = epoch + 1
+            RETURNING epoch;
+            ",
+            self.id
+        )
+        .fetch_one(pool)
+        .await?;
+        Ok(epoch)
+    }
+
+    // Permissions and identifiers reference the id, so only the name changes
+    pub async fn rename(&self, new_name: &str, pool: &sqlx::SqlitePool) -> crate::Result<()> {
+        // the UNIQUE index decides, a check before the update could race another rename
+        let updated = sqlx::query!(
+            "UPDATE users SET name = ? WHERE id = ? AND NOT EXISTS (SELECT 1 FROM users WHERE external_id = ? AND id != ?)",
+            new_name,
+            self.id,
+            new_name,
+            self.id
+        )
+        .execute(pool)
+        .await
+        .map_err(unique_violation("Name is already taken"))?;
+        if updated.rows_affected() == 0 {
+            return Err(crate::Error::BadRequest("Name is already taken"));
+        }
+
+        Ok(())
+    }
+
     pub async fn delete_by_id(id: i64, pool: &sqlx::SqlitePool) -> crate::Result<()> {
         sqlx::query!("DELETE FROM users WHERE id = ?", id)
             .execute(pool)
@@ -212,9 +519,86 @@ impl User {
     }
 }
 
+// Links a user to a grant, creating the permission row on first use
+async fn insert_grant(
+    conn: &mut sqlx::SqliteConnection,
+    user_id: i64,
+    kind: &str,
+    subject: &str,
+    permission_type: &PermissionType,
+) -> crate::Result<()> {
+    let perm_str = permission_type.to_string();
+    sqlx::query!(
+        r#"
+        INSERT OR IGNORE INTO permissions (kind, subject, permission)
+        VALUES (?, ?, ?)
+        "#,
+        kind,
+        subject,
+        perm_str,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let permission = sqlx::query_as!(
+        Permission,
+        r#"
+        SELECT * FROM permissions
+        WHERE kind = ? AND subject = ? AND permission = ?
+        "#,
+        kind,
+        subject,
+        perm_str,
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    let permission_id = match permission.id {
+        Some(id) => id,
+        None => return Err(crate::Error::Opaque("Missing permission_id")), // should not happen
+    };
+
+    sqlx::query!(
+        r#"
+        INSERT OR IGNORE INTO user_permissions (user_id, permission_id)
+        VALUES (?, ?)
+        "#,
+        user_id,
+        permission_id,
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
 // for init
 impl User {
-    pub async fn generate_admin(pool: &sqlx::SqlitePool) -> crate::Result<()> {
+    pub async fn generate_admin(
+        pool: &sqlx::SqlitePool,
+        argon: &argon2::Argon2<'_>,
+        password_file: &std::path::Path,
+    ) -> crate::Result<()> {
+        if let Some(pw) = Self::init_admin(pool, argon).await?
+            && let Err(e) = announce_admin_password(&pw, !cfg!(debug_assertions), password_file)
+        {
+            // nobody could read the password, the next start generates a new one
+            sqlx::query!(
+                "DELETE FROM user_pw_hash WHERE user_id = (SELECT id FROM users WHERE name = 'admin')"
+            )
+            .execute(pool)
+            .await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Returns the generated password only to the call that stored it. Replicas booting together
+    // race on the insert, so exactly one of them announces a password and it is the valid one
+    async fn init_admin(
+        pool: &sqlx::SqlitePool,
+        argon: &argon2::Argon2<'_>,
+    ) -> crate::Result<Option<String>> {
         use argon2::PasswordHasher;
 
         let user = Self::new_user("admin".to_string());
@@ -230,17 +614,25 @@ impl User {
 
         let pw_exists = sqlx::query!("SELECT user_id FROM user_pw_hash WHERE user_id = (SELECT id FROM users WHERE name = 'admin')").fetch_optional(pool).await?.is_some();
 
+        let mut generated = None;
+        // the check only skips hashing on later boots, the insert decides who sets the password
         if !pw_exists {
             let salt = argon2::password_hash::SaltString::generate(
                 &mut argon2::password_hash::rand_core::OsRng,
             );
-            let argon = argon2::Argon2::default();
-
             let pw = Self::generate_password(32);
-            tracing::info!("{:<12}- Admin password is {pw}! KEEP IT SAFE!", "Password");
             let pw_hash = argon.hash_password(pw.as_bytes(), &salt)?.to_string();
 
-            user.add_hash(&pw_hash, pool).await?;
+            let inserted = sqlx::query!(
+                "INSERT OR IGNORE INTO user_pw_hash (user_id, pw_hash) SELECT id, ? FROM users WHERE name = 'admin'",
+                pw_hash
+            )
+            .execute(pool)
+            .await?
+            .rows_affected();
+            if inserted == 1 {
+                generated = Some(pw);
+            }
         }
 
         sqlx::query!(
@@ -254,7 +646,8 @@ impl User {
             SELECT u.id, p.id
             FROM users AS u
             JOIN permissions AS p 
-                ON p.subject = '*' 
+                ON p.kind = 'repository'
+                AND p.subject = '*' 
                 AND p.permission = 'pull'
             WHERE u.name = 'admin';
 
@@ -262,7 +655,8 @@ impl User {
             SELECT u.id, p.id
             FROM users AS u
             JOIN permissions AS p 
-                ON p.subject = '*' 
+                ON p.kind = 'repository'
+                AND p.subject = '*' 
                 AND p.permission = 'push'
             WHERE u.name = 'admin';
             "
@@ -270,10 +664,10 @@ impl User {
         .execute(pool)
         .await?;
 
-        Ok(())
+        Ok(generated)
     }
 
-    fn generate_password(len: usize) -> String {
+    pub(crate) fn generate_password(len: usize) -> String {
         use rand::Rng;
 
         rand::rng()
@@ -283,3 +677,518 @@ impl User {
             .collect()
     }
 }
+
+// Release builds log json to stdout, which log collectors ship to central stores, so the
+// password only goes to a file only the augesty user can read there
+fn announce_admin_password(pw: &str, json_logs: bool, path: &std::path::Path) -> crate::Result<()> {
+    if json_logs {
+        write_secret_file(path, pw)?;
+        tracing::info!(
+            event = "admin_password_generated",
+            "{:<12}- Admin password generated and written to {}",
+            "Password",
+            path.display()
+        );
+    } else {
+        tracing::info!("{:<12}- Admin password is {pw}! KEEP IT SAFE!", "Password");
+    }
+    Ok(())
+}
+
+// A file left over from an earlier database is replaced, so the mode is always set on creation
+fn write_secret_file(path: &std::path::Path, secret: &str) -> crate::Result<()> {
+    use std::io::Write;
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    writeln!(options.open(path)?, "{secret}")?;
+    Ok(())
+}
+
+#[cfg(test)]
+fn identifier_names(identifiers: Vec<UserIdentifier>) -> Vec<String> {
+    identifiers
+        .into_iter()
+        .map(|ident| ident.identifier)
+        .collect()
+}
+
+#[test]
+fn test_admin_password_not_in_json_logs() -> crate::Result<()> {
+    let buffer = crate::LogBuffer::default();
+    let writer = buffer.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .json()
+        .with_writer(move || writer.clone())
+        .finish();
+
+    let path = std::env::temp_dir().join(format!("augesty-{}.pw", uuid::Uuid::new_v4()));
+    // a leftover file is replaced, never reused with its old permissions
+    std::fs::write(&path, "old")?;
+
+    let pw = User::generate_password(32);
+    tracing::subscriber::with_default(subscriber, || announce_admin_password(&pw, true, &path))?;
+
+    // nothing but the file gets the password, stdout carries the json logs
+    let logs = buffer.contents();
+    assert!(logs.contains("admin_password_generated"));
+    assert!(!logs.contains(&pw));
+    assert_eq!(std::fs::read_to_string(&path)?, format!("{pw}\n"));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        assert_eq!(
+            std::fs::metadata(&path)?.permissions().mode() & 0o777,
+            0o600
+        );
+    }
+
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_add_permission_stores_valid_type() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    let user = User::find_by_name("dev", &pool).await?;
+
+    user.add_permission(
+        "repository".to_string(),
+        "example/image".to_string(),
+        PermissionType::Push,
+        &pool,
+    )
+    .await?;
+    assert!(
+        user.add_permission(
+            "registry".to_string(),
+            "catalog".to_string(),
+            PermissionType::Wildcard,
+            &pool,
+        )
+        .await
+        .is_err()
+    );
+
+    let permissions = user.list_permissions(&pool).await?;
+    assert_eq!(permissions.len(), 1);
+    assert_eq!(permissions[0].permission, PermissionType::Push);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_permissions_survive_rename() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    let svc = User::find_by_name("ci", &pool).await?;
+    svc.add_user_identifier("example/repo", MatchStrategy::Exact, 10, &pool)
+        .await?;
+    svc.add_permission(
+        "repository".to_string(),
+        "example/image".to_string(),
+        PermissionType::Pull,
+        &pool,
+    )
+    .await?;
+
+    assert!(matches!(
+        svc.rename("dev", &pool).await,
+        Err(crate::Error::BadRequest("Name is already taken"))
+    ));
+    svc.rename("ci-renamed", &pool).await?;
+
+    let renamed = User::find_by_name("ci-renamed", &pool).await?;
+    assert_eq!(renamed.id, svc.id);
+    assert_eq!(
+        identifier_names(renamed.get_identifiers(&pool).await?),
+        vec!["example/repo"]
+    );
+    assert_eq!(renamed.list_permissions(&pool).await?.len(), 1);
+    assert!(User::find_by_name("ci", &pool).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_duplicates_are_bad_requests() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    assert!(matches!(
+        User::new_user("dev".to_string()).insert(&pool).await,
+        Err(crate::Error::BadRequest("Name is already taken"))
+    ));
+
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    let svc = User::find_by_name("ci", &pool).await?;
+    svc.add_user_identifier("example/repo", MatchStrategy::Exact, 10, &pool)
+        .await?;
+    assert!(matches!(
+        svc.add_user_identifier("example/repo", MatchStrategy::Exact, 10, &pool)
+            .await,
+        Err(crate::Error::BadRequest("Identifier already exists"))
+    ));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_renames_to_one_name() -> crate::Result<()> {
+    // a file, so the renames run on their own connections
+    let path = std::env::temp_dir().join(format!("augesty-{}.db", uuid::Uuid::new_v4()));
+    let pool = crate::state::connect_db(&path.to_string_lossy(), None).await?;
+    crate::MIGRATOR.run(&pool).await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    User::new_user("ops".to_string()).insert(&pool).await?;
+    let dev = User::find_by_name("dev", &pool).await?;
+    let ops = User::find_by_name("ops", &pool).await?;
+
+    let (first, second) = tokio::join!(dev.rename("lead", &pool), ops.rename("lead", &pool));
+    let results = [first, second];
+    assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+    // the loser gets the 400 of the unique index, not a database error
+    assert!(results.iter().any(|result| matches!(
+        result,
+        Err(crate::Error::BadRequest("Name is already taken"))
+    )));
+
+    pool.close().await;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_identifier_cap() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    let svc = User::find_by_name("ci", &pool).await?;
+
+    svc.add_user_identifier("example/one", MatchStrategy::Exact, 2, &pool)
+        .await?;
+    svc.add_user_identifier("example/two", MatchStrategy::Exact, 2, &pool)
+        .await?;
+    assert!(matches!(
+        svc.add_user_identifier("example/three", MatchStrategy::Exact, 2, &pool)
+            .await,
+        Err(crate::Error::BadRequest(
+            "Service account has too many identifiers"
+        ))
+    ));
+    assert_eq!(svc.get_identifiers(&pool).await?.len(), 2);
+
+    let too_many = vec!["example/a".to_string(); 3];
+    assert!(matches!(
+        svc.replace_identifiers(&too_many, 2, &pool).await,
+        Err(crate::Error::BadRequest(_))
+    ));
+    assert!(matches!(
+        svc.replace_identifiers(&[], 2, &pool).await,
+        Err(crate::Error::BadRequest(_))
+    ));
+    // the old identifiers are kept
+    assert_eq!(
+        identifier_names(svc.get_identifiers(&pool).await?),
+        vec!["example/one", "example/two"]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_validate_name() {
+    for name in ["admin", "ci-bot", "team.ci_2", "a"] {
+        assert!(User::validate_name(name).is_ok(), "{name}");
+    }
+    let too_long = "a".repeat(65);
+    for name in [
+        "",
+        too_long.as_str(),
+        "-ci",
+        ".hidden",
+        "ci/bot",
+        "ci:bot",
+        "ci bot",
+        "ci\nbot",
+        "ci\u{0}",
+        "bénédicte",
+    ] {
+        assert!(
+            matches!(User::validate_name(name), Err(crate::Error::BadRequest(_))),
+            "{name:?}"
+        );
+    }
+}
+
+#[tokio::test]
+async fn test_clear_permissions() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    let ci = User::find_by_name("ci", &pool).await?;
+    let dev = User::find_by_name("dev", &pool).await?;
+    for user in [&ci, &dev] {
+        user.add_permission(
+            "repository".to_string(),
+            "example/image".to_string(),
+            PermissionType::Pull,
+            &pool,
+        )
+        .await?;
+    }
+    ci.add_permission(
+        "repository".to_string(),
+        "*".to_string(),
+        PermissionType::Push,
+        &pool,
+    )
+    .await?;
+
+    assert_eq!(ci.clear_permissions(false, &pool).await?.len(), 2);
+    assert!(ci.list_permissions(&pool).await?.is_empty());
+    // the shared permission is still granted to others
+    assert_eq!(dev.list_permissions(&pool).await?.len(), 1);
+
+    dev.add_permission(
+        "repository".to_string(),
+        "*".to_string(),
+        PermissionType::Pull,
+        &pool,
+    )
+    .await?;
+    assert_eq!(dev.clear_permissions(true, &pool).await?.len(), 1);
+    assert_eq!(dev.list_permissions(&pool).await?[0].subject, "*");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_has_identifier() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    User::new_service_account("release".to_string())
+        .insert(&pool)
+        .await?;
+    let ci = User::find_by_name("ci", &pool).await?;
+    let release = User::find_by_name("release", &pool).await?;
+    ci.add_user_identifier("example/repo", MatchStrategy::Exact, 10, &pool)
+        .await?;
+    release
+        .add_user_identifier("example/*", MatchStrategy::Prefix, 10, &pool)
+        .await?;
+
+    assert!(ci.has_identifier(&["example/repo"], &pool).await?);
+    assert!(!ci.has_identifier(&["example/other"], &pool).await?);
+    // the org wildcard covers every repository of the org, but not the org itself
+    assert!(release.has_identifier(&["example/other"], &pool).await?);
+    assert!(!release.has_identifier(&["example"], &pool).await?);
+    assert!(!release.has_identifier(&["example-org/repo"], &pool).await?);
+    // any of several candidates is enough
+    assert!(
+        ci.has_identifier(&["example", "example/repo"], &pool)
+            .await?
+    );
+    assert!(!ci.has_identifier(&[], &pool).await?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_has_identifier_by_strategy() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    for name in ["exact", "prefix", "regex"] {
+        User::new_service_account(name.to_string())
+            .insert(&pool)
+            .await?;
+    }
+    let exact = User::find_by_name("exact", &pool).await?;
+    let prefix = User::find_by_name("prefix", &pool).await?;
+    let regex = User::find_by_name("regex", &pool).await?;
+    exact
+        .add_user_identifier("example/app", MatchStrategy::Exact, 10, &pool)
+        .await?;
+    prefix
+        .add_user_identifier("example/*", MatchStrategy::Prefix, 10, &pool)
+        .await?;
+    regex
+        .add_user_identifier("example/app-(web|api)", MatchStrategy::Regex, 10, &pool)
+        .await?;
+
+    assert!(exact.has_identifier(&["example/app"], &pool).await?);
+    assert!(!exact.has_identifier(&["example/app-web"], &pool).await?);
+    assert!(prefix.has_identifier(&["example/app-web"], &pool).await?);
+    assert!(!prefix.has_identifier(&["other/app"], &pool).await?);
+    assert!(regex.has_identifier(&["example/app-api"], &pool).await?);
+    assert!(!regex.has_identifier(&["example/app-cli"], &pool).await?);
+    // a regex is never compared literally
+    assert!(
+        !regex
+            .has_identifier(&["example/app-(web|api)"], &pool)
+            .await?
+    );
+    // an unknown strategy never reaches the table
+    assert!(
+        sqlx::query(
+            "INSERT INTO user_identifiers (user_id, identifier, match_strategy) VALUES (?, 'example/x', 'glob')"
+        )
+        .bind(regex.id)
+        .execute(&pool)
+        .await
+        .is_err()
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_listings_are_sorted() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    let ci = User::find_by_name("ci", &pool).await?;
+    for (subject, permission) in [
+        ("example/web", PermissionType::Push),
+        ("example/api", PermissionType::Push),
+        ("example/web", PermissionType::Pull),
+        ("example/api", PermissionType::Pull),
+    ] {
+        ci.add_permission(
+            "repository".to_string(),
+            subject.to_string(),
+            permission,
+            &pool,
+        )
+        .await?;
+    }
+    for identifier in ["example/web", "example/api"] {
+        ci.add_user_identifier(identifier, MatchStrategy::Exact, 10, &pool)
+            .await?;
+    }
+
+    let listed: Vec<(String, String)> = ci
+        .list_permissions(&pool)
+        .await?
+        .into_iter()
+        .map(|perm| (perm.subject, perm.permission.to_string()))
+        .collect();
+    assert_eq!(
+        listed,
+        vec![
+            ("example/api".to_string(), "pull".to_string()),
+            ("example/api".to_string(), "push".to_string()),
+            ("example/web".to_string(), "pull".to_string()),
+            ("example/web".to_string(), "push".to_string()),
+        ]
+    );
+    assert_eq!(
+        ci.list_permissions(&pool).await?,
+        ci.list_permissions(&pool).await?
+    );
+    assert_eq!(
+        identifier_names(ci.get_identifiers(&pool).await?),
+        vec!["example/api", "example/web"]
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_service_account_starts_with_default_permissions() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    let defaults = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "library/base".to_string(),
+        permission: PermissionType::Pull,
+    }];
+
+    User::new_service_account("ci".to_string())
+        .insert_with_permissions(&defaults, &pool)
+        .await?;
+    let ci = User::find_by_name("ci", &pool).await?;
+    let granted: Vec<(String, String, PermissionType)> = ci
+        .list_permissions(&pool)
+        .await?
+        .into_iter()
+        .map(|perm| (perm.kind, perm.subject, perm.permission))
+        .collect();
+    assert_eq!(
+        granted,
+        vec![(
+            "repository".to_string(),
+            "library/base".to_string(),
+            PermissionType::Pull
+        )]
+    );
+
+    // a taken name creates nothing
+    assert!(matches!(
+        User::new_service_account("ci".to_string())
+            .insert_with_permissions(&defaults, &pool)
+            .await,
+        Err(crate::Error::BadRequest(_))
+    ));
+    User::new_service_account("plain".to_string())
+        .insert_with_permissions(&[], &pool)
+        .await?;
+    let plain = User::find_by_name("plain", &pool).await?;
+    assert!(plain.list_permissions(&pool).await?.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_concurrent_admin_generation_sets_one_password() -> crate::Result<()> {
+    use argon2::PasswordVerifier;
+
+    // two pools on one file, like replicas booting together against the same database
+    let path = std::env::temp_dir().join(format!("augesty-{}.db", uuid::Uuid::new_v4()));
+    let pool = crate::state::connect_db(&path.to_string_lossy(), None).await?;
+    crate::MIGRATOR.run(&pool).await?;
+    let replica = crate::state::connect_db(&path.to_string_lossy(), None).await?;
+    let argon = argon2::Argon2::default();
+
+    let (first, second) = tokio::join!(
+        User::init_admin(&pool, &argon),
+        User::init_admin(&replica, &argon)
+    );
+    let generated: Vec<String> = [first?, second?].into_iter().flatten().collect();
+    assert_eq!(generated.len(), 1);
+
+    let stored = crate::models::user_pw_hash::UserPasswordHash::find_pw("admin", &pool).await?;
+    let hash = argon2::PasswordHash::new(&stored.pw_hash)?;
+    assert!(
+        argon
+            .verify_password(generated[0].as_bytes(), &hash)
+            .is_ok()
+    );
+
+    // later boots keep the password
+    assert_eq!(User::init_admin(&pool, &argon).await?, None);
+    let admin = User::find_by_name("admin", &pool).await?;
+    assert_eq!(admin.list_permissions(&pool).await?.len(), 2);
+
+    pool.close().await;
+    replica.close().await;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
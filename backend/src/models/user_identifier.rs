@@ -1,8 +1,289 @@
-use sqlx::prelude::FromRow;
+use std::fmt::Display;
+
+use sqlx::prelude::{FromRow, Type};
 
 #[derive(Debug, Clone, PartialEq, Eq, FromRow)]
 pub struct UserIdentifier {
     pub id: Option<i64>,
     pub user_id: i64,
     pub identifier: String,
+    pub match_strategy: MatchStrategy,
+}
+
+// How a stored identifier is compared to the OIDC claim value
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    Type,
+    serde::Serialize,
+    serde::Deserialize,
+    utoipa::ToSchema,
+)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum MatchStrategy {
+    #[default]
+    Exact,
+    // `owner/*`, every repository directly below the owner
+    Prefix,
+    // the whole claim value has to match
+    Regex,
+}
+
+impl MatchStrategy {
+    // Without an explicit strategy `owner/*` keeps meaning every repository of the owner
+    pub fn infer(identifier: &str) -> Self {
+        if identifier.ends_with("/*") {
+            Self::Prefix
+        } else {
+            Self::Exact
+        }
+    }
+}
+
+impl From<String> for MatchStrategy {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "exact" => Self::Exact,
+            "prefix" => Self::Prefix,
+            "regex" => Self::Regex,
+            other => panic!("Unknown match strategy {other}"), // should not happen bc of the CHECK constraint
+        }
+    }
+}
+
+impl Display for MatchStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Self::Exact => "exact",
+            Self::Prefix => "prefix",
+            Self::Regex => "regex",
+        };
+        write!(f, "{}", text)
+    }
+}
+
+// Keeps a hostile pattern from using up memory when it is compiled on every identify
+const REGEX_SIZE_LIMIT: usize = 1 << 16;
+
+impl UserIdentifier {
+    // Checks a stored identifier against an OIDC claim value
+    pub fn matches(identifier: &str, strategy: MatchStrategy, candidate: &str) -> bool {
+        if Self::validate(identifier, strategy).is_err() {
+            return false;
+        }
+        match strategy {
+            MatchStrategy::Exact => identifier == candidate,
+            MatchStrategy::Prefix => identifier
+                .strip_suffix("/*")
+                .and_then(|owner| candidate.strip_prefix(owner))
+                .and_then(|rest| rest.strip_prefix('/'))
+                .is_some_and(|repo| !repo.is_empty() && !repo.contains('/')),
+            MatchStrategy::Regex => {
+                Self::regex(identifier).is_ok_and(|regex| regex.is_match(candidate))
+            }
+        }
+    }
+
+    // Anchored, a pattern never matches only part of the claim value. The owner is taken
+    // literally, so a pattern like `.*` or `example/x|.*` cannot trust other owners' repositories
+    fn regex(pattern: &str) -> crate::Result<regex::Regex> {
+        let (owner, repo) = pattern.split_once('/').unwrap_or_default();
+        if owner.is_empty()
+            || !owner
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(crate::Error::BadRequest(
+                "Regex identifiers have to start with a literal owner followed by /",
+            ));
+        }
+        let build = |pattern: &str| {
+            regex::RegexBuilder::new(pattern)
+                .size_limit(REGEX_SIZE_LIMIT)
+                .build()
+                .map_err(|_| crate::Error::BadRequest("Identifier is not a valid regex"))
+        };
+        // on its own first, an unbalanced `)` could otherwise close the group around it
+        build(repo)?;
+        build(&format!("^{owner}/(?:{repo})$"))
+    }
+
+    // Every stored exact or prefix identifier that matches the candidate, so the database can
+    // look them up through the (user_id, identifier) index instead of scanning all of them
+    pub fn matching_identifiers(candidate: &str) -> Vec<String> {
+        let mut identifiers = vec![candidate.to_string()];
+        if let Some((owner, repo)) = candidate.split_once('/')
+            && !repo.is_empty()
+            && !repo.contains('/')
+        {
+            let wildcard = format!("{owner}/*");
+            if Self::validate(&wildcard, MatchStrategy::Prefix).is_ok() {
+                identifiers.push(wildcard);
+            }
+        }
+        identifiers
+    }
+
+    // Rejects wildcards that would trust every repository on GitHub and regexes that do not
+    // compile, so identify never trips over a stored identifier
+    pub fn validate(identifier: &str, strategy: MatchStrategy) -> crate::Result<()> {
+        if identifier.is_empty() {
+            return Err(crate::Error::BadRequest("Identifier must be non-empty"));
+        }
+        match strategy {
+            MatchStrategy::Exact if identifier.contains('*') => Err(crate::Error::BadRequest(
+                "Exact identifiers cannot contain a wildcard",
+            )),
+            MatchStrategy::Exact => Ok(()),
+            MatchStrategy::Prefix => {
+                let owner = identifier.strip_suffix("/*").unwrap_or_default();
+                if owner.is_empty() || owner.contains('*') || owner.contains('/') {
+                    return Err(crate::Error::BadRequest(
+                        "Prefix identifiers have to be an owner followed by /*",
+                    ));
+                }
+                Ok(())
+            }
+            MatchStrategy::Regex => Self::regex(identifier).map(|_| ()),
+        }
+    }
+}
+
+#[test]
+fn test_identifier_matching() {
+    use MatchStrategy::{Exact, Prefix, Regex};
+
+    // exact
+    assert!(UserIdentifier::matches(
+        "example/repo",
+        Exact,
+        "example/repo"
+    ));
+    assert!(!UserIdentifier::matches(
+        "example/repo",
+        Exact,
+        "example/other"
+    ));
+    assert!(!UserIdentifier::matches("example/*", Exact, "example/*"));
+    assert!(UserIdentifier::validate("example/*", Exact).is_err());
+
+    // prefix
+    assert!(UserIdentifier::matches("example/*", Prefix, "example/repo"));
+    assert!(!UserIdentifier::matches("example/*", Prefix, "example"));
+    assert!(!UserIdentifier::matches("example/*", Prefix, "other/repo"));
+    assert!(!UserIdentifier::matches(
+        "example/*",
+        Prefix,
+        "example-org/repo"
+    ));
+    assert!(!UserIdentifier::matches(
+        "example/*",
+        Prefix,
+        "example/repo/nested"
+    ));
+
+    // prefix too broad
+    assert!(!UserIdentifier::matches("*", Prefix, "example/repo"));
+    assert!(!UserIdentifier::matches("/*", Prefix, "/repo"));
+    assert!(UserIdentifier::validate("*", Prefix).is_err());
+    assert!(UserIdentifier::validate("*/*", Prefix).is_err());
+    assert!(UserIdentifier::validate("example", Prefix).is_err());
+    assert!(UserIdentifier::validate("example/*", Prefix).is_ok());
+
+    // regex, anchored at both ends
+    assert!(UserIdentifier::matches(
+        "example/app-.+",
+        Regex,
+        "example/app-web"
+    ));
+    assert!(!UserIdentifier::matches(
+        "example/app-.+",
+        Regex,
+        "example/app-"
+    ));
+    assert!(!UserIdentifier::matches(
+        "example/app-.+",
+        Regex,
+        "fork/example/app-web"
+    ));
+    assert!(!UserIdentifier::matches(
+        "example/app",
+        Regex,
+        "example/app-web"
+    ));
+    assert!(UserIdentifier::matches(
+        "example/(web|api)",
+        Regex,
+        "example/api"
+    ));
+    assert!(UserIdentifier::validate("example/(", Regex).is_err());
+    assert!(!UserIdentifier::matches("example/(", Regex, "example/("));
+
+    // regex too broad, the owner has to be literal
+    for pattern in [
+        ".*",
+        ".*/.*",
+        "exam.le/repo",
+        "(example|other)/repo",
+        "/repo",
+    ] {
+        assert!(
+            UserIdentifier::validate(pattern, Regex).is_err(),
+            "{pattern}"
+        );
+    }
+    assert!(!UserIdentifier::matches(
+        "example/x|.*",
+        Regex,
+        "other/repo"
+    ));
+    assert!(UserIdentifier::matches(
+        "example/x|.*",
+        Regex,
+        "example/repo"
+    ));
+    assert!(UserIdentifier::validate("example/a)|(.*", Regex).is_err());
+
+    // without a strategy wildcards stay prefixes
+    assert_eq!(MatchStrategy::infer("example/*"), Prefix);
+    assert_eq!(MatchStrategy::infer("example/repo"), Exact);
+}
+
+#[test]
+fn test_matching_identifiers_agree_with_matches() {
+    let stored = [
+        "example/repo",
+        "example/*",
+        "example",
+        "*",
+        "/*",
+        "*/*",
+        "other/repo",
+    ]
+    .map(|identifier| (identifier, MatchStrategy::infer(identifier)));
+    let candidates = [
+        "example/repo",
+        "example/other",
+        "example",
+        "example/repo/nested",
+        "example-org/repo",
+        "/repo",
+        "example/",
+    ];
+
+    for candidate in candidates {
+        let matching = UserIdentifier::matching_identifiers(candidate);
+        for (identifier, strategy) in stored {
+            assert_eq!(
+                matching.iter().any(|ident| ident == identifier),
+                UserIdentifier::matches(identifier, strategy, candidate),
+                "{identifier} against {candidate}"
+            );
+        }
+    }
 }
@@ -28,4 +28,15 @@ impl UserPasswordHash {
         .await?
         .ok_or(crate::Error::BadRequest("User is serviceaccount"))
     }
+
+    pub async fn replace(&self, pw_hash: &str, pool: &sqlx::SqlitePool) -> crate::Result<()> {
+        sqlx::query!(
+            "UPDATE user_pw_hash SET pw_hash = ? WHERE user_id = ?",
+            pw_hash,
+            self.user_id
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
 }
@@ -0,0 +1,91 @@
+use std::sync::OnceLock;
+
+use axum::http::HeaderMap;
+use opentelemetry::{propagation::Extractor, trace::TracerProvider};
+use opentelemetry_sdk::{Resource, propagation::TraceContextPropagator, trace::SdkTracerProvider};
+use tracing::Subscriber;
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+static PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+
+// Tracing starts before the config is loaded, so the standard OTLP env var is read directly.
+// Without it no exporter is built and only the logs are written.
+pub fn layer<S>() -> crate::Result<Option<OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>>>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err() {
+        return Ok(None);
+    }
+
+    // the exporter reads the endpoint itself and appends /v1/traces
+    let exporter = opentelemetry_otlp::SpanExporter::builder()
+        .with_http()
+        .build()
+        .map_err(|e| crate::Error::Any(format!("Failed to build the OTLP exporter: {e}")))?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("augesty").build())
+        .build();
+    let tracer = provider.tracer("augesty");
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    opentelemetry::global::set_tracer_provider(provider.clone());
+    _ = PROVIDER.set(provider);
+
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+// Flushes the spans that are still batched
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        _ = provider.shutdown();
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+// Continues the trace of the caller, e.g. the registry, when it sent a traceparent header
+pub fn set_remote_parent(span: &tracing::Span, headers: &HeaderMap) {
+    let parent = opentelemetry::global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(headers))
+    });
+    _ = span.set_parent(parent);
+}
+
+#[test]
+fn test_traceparent_is_continued() {
+    use opentelemetry::trace::TraceContextExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    opentelemetry::global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer = SdkTracerProvider::builder().build().tracer("test");
+    let subscriber =
+        tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(tracer));
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        "traceparent",
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse()
+            .unwrap(),
+    );
+
+    tracing::subscriber::with_default(subscriber, || {
+        let span = tracing::info_span!("request");
+        set_remote_parent(&span, &headers);
+        let trace_id = span.context().span().span_context().trace_id();
+        assert_eq!(trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+    });
+}
@@ -0,0 +1,51 @@
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+
+use crate::{TOKEN_TAG, state::AppState};
+
+#[utoipa::path(
+    method(get),
+    tag = TOKEN_TAG,
+    path = "/api/cert.pem",
+    description = "The public cert registries use to verify the issued tokens",
+    responses(
+        (status = OK, description = "Success", body = String, content_type = "application/x-pem-file")
+    )
+)]
+pub async fn cert(State(state): State<AppState>) -> Response {
+    pem_response(state.cert())
+}
+
+fn pem_response(pem: &[u8]) -> Response {
+    (
+        [(header::CONTENT_TYPE, "application/x-pem-file")],
+        pem.to_vec(),
+    )
+        .into_response()
+}
+
+#[tokio::test]
+async fn test_cert_matches_signing_key() -> crate::Result<()> {
+    use jwt_simple::prelude::ES384KeyPair;
+    use openssl::{ec::EcKey, pkey::PKey, x509::X509};
+
+    let pair = ES384KeyPair::generate();
+    let cert = crate::state::create_cert_from_pair(&pair, "augesty.example.com")?;
+    let response = pem_response(&cert);
+
+    assert_eq!(
+        response.headers()[header::CONTENT_TYPE],
+        "application/x-pem-file"
+    );
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+    let cert_pkey = X509::from_pem(&body)?.public_key()?;
+    let public_pkey = PKey::from_ec_key(EcKey::public_key_from_pem(
+        pair.public_key().to_pem()?.as_bytes(),
+    )?)?;
+    assert!(cert_pkey.public_eq(&public_pkey));
+
+    Ok(())
+}
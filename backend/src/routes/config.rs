@@ -0,0 +1,55 @@
+use axum::{Json, extract::State};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    TOKEN_TAG,
+    config::providers,
+    state::{AppState, SVC_TOKEN_DURATION},
+};
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct ConfigResponse {
+    // in seconds
+    pub token_ttl: u64,
+    // in seconds
+    pub svc_token_ttl: u64,
+    pub signing_algorithm: String,
+    pub issuer: String,
+    pub svc_issuer: String,
+    pub services: Vec<String>,
+    pub providers: Vec<String>,
+    pub cert_url: String,
+    // a verify only instance checks tokens but issues none
+    pub verify_only: bool,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = TOKEN_TAG,
+    path = "/api/config",
+    description = "The effective non secret settings, for integration checks",
+    responses(
+        (status = OK, description = "Success", body = ConfigResponse, content_type = "application/json")
+    )
+)]
+pub async fn config(State(state): State<AppState>) -> Json<ConfigResponse> {
+    Json(ConfigResponse {
+        token_ttl: 60 * state.token_duration(),
+        svc_token_ttl: 60 * SVC_TOKEN_DURATION,
+        signing_algorithm: "ES384".to_string(),
+        issuer: state.own_url().clone(),
+        svc_issuer: state.svc_issuer().to_string(),
+        services: state.docker_urls().to_vec(),
+        providers: providers(state.verify_only())
+            .into_iter()
+            .map(str::to_string)
+            .collect(),
+        cert_url: format!(
+            "https://{}{}/api/cert.pem",
+            state.own_url(),
+            state.base_path()
+        ),
+        verify_only: state.verify_only(),
+    })
+}
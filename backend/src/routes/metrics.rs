@@ -0,0 +1,24 @@
+use axum::{
+    extract::State,
+    http::header,
+    response::{IntoResponse, Response},
+};
+
+use crate::{TOKEN_TAG, state::AppState};
+
+#[utoipa::path(
+    method(get),
+    tag = TOKEN_TAG,
+    path = "/api/metrics",
+    description = "Authentication outcomes and latencies by provider in the Prometheus text format. Only served with METRICS=true",
+    responses(
+        (status = OK, description = "Success", body = String, content_type = "text/plain; version=0.0.4")
+    )
+)]
+pub async fn metrics(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.auth_metrics().render(),
+    )
+        .into_response()
+}
@@ -1,2 +1,6 @@
+pub mod cert;
+pub mod config;
+pub mod metrics;
+pub mod oidc;
 pub mod token;
 pub mod user;
@@ -0,0 +1,44 @@
+use axum::{Json, extract::State};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{TOKEN_TAG, extractors::GithubExtractor, state::AppState};
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct OidcDebugResponse {
+    repository: String,
+    repository_owner: String,
+    sub: String,
+    job_workflow_ref: String,
+    // the values compared against the service account identifiers
+    identifiers: Vec<String>,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = TOKEN_TAG,
+    path = "/api/oidc/debug",
+    description = "Validates the OIDC token and returns the claims augesty sees. Only available with OIDC_DEBUG=true",
+    responses(
+        (status = OK, description = "Success", body = OidcDebugResponse, content_type = "application/json")
+    ),
+    security(("github_oidc" = []))
+)]
+pub async fn debug(
+    State(state): State<AppState>,
+    GithubExtractor(identity): GithubExtractor,
+) -> crate::Result<Json<OidcDebugResponse>> {
+    let identifiers = identity
+        .identifiers(state.oidc_claim())
+        .into_iter()
+        .map(String::from)
+        .collect();
+
+    Ok(Json(OidcDebugResponse {
+        repository: identity.repository,
+        repository_owner: identity.repository_owner,
+        sub: identity.sub,
+        job_workflow_ref: identity.job_workflow_ref,
+        identifiers,
+    }))
+}
@@ -1,26 +1,42 @@
-use axum::{Json, extract::State};
+use std::collections::BTreeMap;
+
+use axum::{Json, extract::State, http::Extensions};
 use axum_extra::extract::{Query, WithRejection};
 use serde::{Deserialize, Serialize};
 use utoipa::{IntoParams, ToSchema};
 
 use crate::{
     TOKEN_TAG,
+    config::UnauthorizedScopes,
     error::LoggedRejection,
     extractors::{GithubExtractor, PermissionExtractor},
-    models::{permission::PermissionType, user::User},
+    models::{
+        claim_condition::ClaimCondition,
+        permission::{Permission, PermissionType, default_kind, normalize_subject},
+        user::{User, UserType},
+    },
     state::AppState,
+    trace::Principal,
 };
 
 #[derive(Debug, Clone, IntoParams, Deserialize)]
 pub struct TokenQuery {
-    pub service: String,
+    // repeated to request one token per registry
+    #[serde(default)]
+    pub service: Vec<String>,
+    // `*` requests every granted permission, no scope keeps the empty `docker login` token
     #[serde(default)]
     pub scope: Vec<String>,
+    // lifetime in seconds, clamped to MAX_TOKEN_DURATION
+    pub requested_ttl: Option<u64>,
 }
 
+// The resource types of the docker token spec, RESOURCE_TYPES replaces them
+pub const DEFAULT_RESOURCE_TYPES: [&str; 3] = ["repository", "registry", "plugin"];
+
 #[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct Scope {
-    #[serde(rename(serialize = "type"))]
+    #[serde(rename = "type")]
     pub kind: String,
     pub name: String,
     pub actions: Vec<PermissionType>,
@@ -29,12 +45,24 @@ pub struct Scope {
 impl Scope {
     pub fn parse_str(input: &str) -> crate::Result<Self> {
         let mut parts = input.splitn(3, ':');
-        let kind = parts.next().ok_or("missing kind")?;
-        let name = parts.next().ok_or("missing name")?;
-        let actions_raw = parts.next().ok_or("missing actions")?;
+        let kind = parts
+            .next()
+            .ok_or(crate::Error::Parse("Scope is missing the resource type"))?;
+        let name = parts
+            .next()
+            .ok_or(crate::Error::Parse("Scope is missing the resource name"))?;
+        let actions_raw = parts
+            .next()
+            .ok_or(crate::Error::Parse("Scope is missing the actions"))?;
 
-        if kind.is_empty() || name.is_empty() || actions_raw.is_empty() {
-            return Err("kind, name, and actions must be non-empty".into());
+        if kind.is_empty() {
+            return Err(crate::Error::Parse("Scope resource type must be non-empty"));
+        }
+        if name.is_empty() {
+            return Err(crate::Error::Parse("Scope resource name must be non-empty"));
+        }
+        if actions_raw.is_empty() {
+            return Err(crate::Error::Parse("Scope actions must be non-empty"));
         }
 
         let mut actions = Vec::new();
@@ -47,7 +75,7 @@ impl Scope {
         }
 
         if actions.is_empty() {
-            return Err("no valid actions found".into());
+            return Err(crate::Error::Parse("Scope contains no valid actions"));
         }
 
         Ok(Self {
@@ -56,12 +84,317 @@ impl Scope {
             actions,
         })
     }
+
+    // Parses the scope and rejects actions and resource types outside of the configured allowlists
+    pub fn parse_allowed(
+        input: &str,
+        allowed: &[PermissionType],
+        resource_types: &[String],
+    ) -> crate::Result<Self> {
+        let scope = Self::parse_str(input)?;
+        if scope.actions.iter().any(|action| !allowed.contains(action)) {
+            return Err(crate::Error::BadRequest(
+                "Scope contains a forbidden action",
+            ));
+        }
+        if !resource_types.contains(&scope.kind) {
+            return Err(crate::Error::BadRequest(
+                "Scope contains an unknown resource type",
+            ));
+        }
+
+        Ok(scope)
+    }
+
+    pub fn is_catalog(&self) -> bool {
+        self.kind == "registry" && self.name == "catalog"
+    }
+
+    // Checks if every requested action is covered by the given permissions
+    pub fn is_granted(&self, permissions: &[Permission]) -> bool {
+        self.missing_actions(permissions).is_empty()
+    }
+
+    // Returns the requested actions not covered by the given permissions
+    pub fn missing_actions(&self, permissions: &[Permission]) -> Vec<PermissionType> {
+        let permission_types: Vec<PermissionType> = permissions
+            .iter()
+            .filter(|perm| {
+                self.kind == perm.kind && (self.name == perm.subject || perm.subject == "*")
+            })
+            .map(|perm| perm.permission.clone())
+            .collect();
+
+        self.actions
+            .iter()
+            .filter(|action| !permission_types.contains(action))
+            .cloned()
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let actions = self
+            .actions
+            .iter()
+            .map(|action| action.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        write!(f, "{}:{}:{}", self.kind, self.name, actions)
+    }
+}
+
+// Adds the permissions implied by the deployment configuration to the granted ones
+fn implied_permissions(
+    permissions: Vec<Permission>,
+    pull_implies_catalog: bool,
+    push_implies_pull: bool,
+) -> Vec<Permission> {
+    let mut permissions = permissions;
+    // Most registries treat push as a superset of pull, augesty only does so when asked
+    // to keep grants explicit. Each push grant also satisfies pull on the same subject.
+    if push_implies_pull {
+        let pulls: Vec<Permission> = permissions
+            .iter()
+            .filter(|perm| perm.permission == PermissionType::Push)
+            .map(|perm| Permission {
+                id: None,
+                kind: perm.kind.clone(),
+                subject: perm.subject.clone(),
+                permission: PermissionType::Pull,
+            })
+            .collect();
+        permissions.extend(pulls);
+    }
+    // The catalog has no per repository filtering, a token for `registry:catalog:*`
+    // lists every repository name in the registry. Enabling this leaks the names of
+    // images a user cant pull to anyone with a single pull grant, so it is opt-in.
+    if pull_implies_catalog
+        && permissions
+            .iter()
+            .any(|perm| perm.permission == PermissionType::Pull)
+    {
+        permissions.push(Permission {
+            id: None,
+            kind: "registry".to_string(),
+            subject: "catalog".to_string(),
+            permission: PermissionType::Wildcard,
+        });
+    }
+
+    permissions
+}
+
+// Each scope is checked on its own, so a cross repo blob mount
+// (`repository:target:pull,push repository:source:pull`) needs push on the target and pull
+// on the source. Returns the first scope that is not fully granted.
+fn denied_scope<'a>(
+    scopes: &'a [Scope],
+    permissions: &[Permission],
+) -> Option<(&'a Scope, Vec<PermissionType>)> {
+    scopes.iter().find_map(|scope| {
+        let missing = scope.missing_actions(permissions);
+        (!missing.is_empty()).then_some((scope, missing))
+    })
+}
+
+// Narrows every scope to its granted actions, scopes without any are left out entirely.
+// `checked` are the scopes as compared against the permissions, e.g. lowercased, the
+// returned ones keep the requested spelling. Also returns what was left out
+fn granted_subset(
+    scopes: Vec<Scope>,
+    checked: &[Scope],
+    permissions: &[Permission],
+) -> (Vec<Scope>, Vec<Scope>) {
+    let mut granted = Vec::new();
+    let mut dropped = Vec::new();
+    for (scope, checked) in scopes.into_iter().zip(checked) {
+        let missing = checked.missing_actions(permissions);
+        if missing.is_empty() {
+            granted.push(scope);
+            continue;
+        }
+        let actions: Vec<PermissionType> = scope
+            .actions
+            .iter()
+            .filter(|action| !missing.contains(action))
+            .cloned()
+            .collect();
+        dropped.push(Scope {
+            actions: missing,
+            ..scope.clone()
+        });
+        if !actions.is_empty() {
+            granted.push(Scope { actions, ..scope });
+        }
+    }
+
+    (granted, dropped)
+}
+
+// With SUBJECT_INHERITANCE a grant on `org` also covers everything below it, like
+// `org/team/service`. Unlike the `*` wildcard it only matches whole path segments, so `org`
+// does not cover `org-infra`. Adds a grant for every requested scope an ancestor covers
+fn inherited_permissions(
+    scopes: &[Scope],
+    permissions: Vec<Permission>,
+    enabled: bool,
+) -> Vec<Permission> {
+    if !enabled {
+        return permissions;
+    }
+    let inherited: Vec<Permission> = scopes
+        .iter()
+        .flat_map(|scope| {
+            permissions
+                .iter()
+                .filter(|perm| {
+                    perm.kind == scope.kind
+                        && scope
+                            .name
+                            .strip_prefix(perm.subject.as_str())
+                            .is_some_and(|rest| rest.starts_with('/'))
+                })
+                .map(|perm| Permission {
+                    id: None,
+                    kind: scope.kind.clone(),
+                    subject: scope.name.clone(),
+                    permission: perm.permission.clone(),
+                })
+        })
+        .collect();
+
+    let mut permissions = permissions;
+    permissions.extend(inherited);
+    permissions
+}
+
+// The permissions the token filter decides on, shared so other checks cant drift from it
+fn effective_permissions(state: &AppState, permissions: Vec<Permission>) -> Vec<Permission> {
+    implied_permissions(
+        permissions,
+        state.pull_implies_catalog(),
+        state.push_implies_pull(),
+    )
+}
+
+// `scope=*` asks for a token carrying everything the user may do instead of a single scope
+const ALL_SCOPES: &str = "*";
+
+// One scope per granted resource, audit is not a docker action and never part of it.
+// Wildcard grants end up as a literal `*` name, registries only match that exactly.
+fn all_granted_scopes(
+    permissions: &[Permission],
+    allowed: &[PermissionType],
+    resource_types: &[String],
+) -> Vec<Scope> {
+    let mut resources: BTreeMap<(&str, &str), Vec<PermissionType>> = BTreeMap::new();
+    for perm in permissions {
+        if !PermissionType::ACTIONS.contains(&perm.permission)
+            || !allowed.contains(&perm.permission)
+            || !resource_types.contains(&perm.kind)
+        {
+            continue;
+        }
+        let actions = resources
+            .entry((perm.kind.as_str(), perm.subject.as_str()))
+            .or_default();
+        if !actions.contains(&perm.permission) {
+            actions.push(perm.permission.clone());
+        }
+    }
+
+    resources
+        .into_iter()
+        .map(|((kind, name), actions)| Scope {
+            kind: kind.to_string(),
+            name: name.to_string(),
+            actions,
+        })
+        .collect()
+}
+
+// Lowercases both sides of the comparison, the token keeps the names as requested
+fn normalized(
+    scopes: &[Scope],
+    permissions: Vec<Permission>,
+    lowercase: bool,
+) -> (Vec<Scope>, Vec<Permission>) {
+    let scopes = scopes
+        .iter()
+        .map(|scope| Scope {
+            name: normalize_subject(&scope.name, lowercase),
+            ..scope.clone()
+        })
+        .collect();
+    let permissions = permissions
+        .into_iter()
+        .map(|perm| Permission {
+            subject: normalize_subject(&perm.subject, lowercase),
+            ..perm
+        })
+        .collect();
+
+    (scopes, permissions)
+}
+
+// The scopes and grants the way every decision compares them, with NORMALIZE_SUBJECTS and
+// SUBJECT_INHERITANCE applied, so the token, check, identify and validate paths cant disagree
+pub(crate) fn comparable(
+    scopes: &[Scope],
+    permissions: Vec<Permission>,
+    normalize_subjects: bool,
+    subject_inheritance: bool,
+) -> (Vec<Scope>, Vec<Permission>) {
+    let (checked, permissions) = normalized(scopes, permissions, normalize_subjects);
+    let permissions = inherited_permissions(&checked, permissions, subject_inheritance);
+    (checked, permissions)
+}
+
+// One service keeps the standard docker shape, several return a token per service
+#[derive(Debug, Clone, ToSchema, Serialize)]
+#[serde(untagged)]
+pub enum TokenResponses {
+    Single(TokenResponse),
+    Multiple {
+        tokens: BTreeMap<String, TokenResponse>,
+    },
+}
+
+impl TokenResponses {
+    fn from_tokens(mut tokens: Vec<(String, TokenResponse)>) -> Self {
+        if tokens.len() == 1 {
+            return Self::Single(tokens.remove(0).1);
+        }
+        Self::Multiple {
+            tokens: tokens.into_iter().collect(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, ToSchema, Serialize)]
 pub struct TokenResponse {
     token: String,
     expires_in: u64, // in seconds
+    // space separated list of the granted scopes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+// Joins the scopes a token was minted with into the oauth style scope string
+fn granted_scope(scopes: &[Scope]) -> Option<String> {
+    if scopes.is_empty() {
+        return None;
+    }
+
+    Some(
+        scopes
+            .iter()
+            .map(|scope| scope.to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
 }
 
 #[utoipa::path(
@@ -71,7 +404,7 @@ pub struct TokenResponse {
     description = "The token endpoint for docker to fetch a registry token",
     params(TokenQuery),
     responses(
-        (status = OK, description = "Success", body = TokenResponse, content_type = "application/json")
+        (status = OK, description = "Success", body = TokenResponses, content_type = "application/json")
     ),
     security(("docker_basic" = []))
 )]
@@ -79,50 +412,249 @@ pub async fn token(
     State(state): State<AppState>,
     PermissionExtractor { user, permissions }: PermissionExtractor,
     WithRejection(Query(params), _): WithRejection<Query<TokenQuery>, LoggedRejection>,
-) -> crate::Result<Json<TokenResponse>> {
-    let scopes: Vec<Scope> = params
-        .scope
-        .into_iter()
-        .map(|scope| Scope::parse_str(&scope))
-        .collect::<Result<_, _>>()?;
+) -> crate::Result<Json<TokenResponses>> {
+    verify_services(&params.service, state.docker_urls())?;
+    let permissions = effective_permissions(&state, permissions);
+    let scopes: Vec<Scope> = if params.scope.iter().any(|scope| scope.trim() == ALL_SCOPES) {
+        all_granted_scopes(
+            &permissions,
+            state.allowed_actions(),
+            state.resource_types(),
+        )
+    } else {
+        // some clients send all scopes space separated in a single parameter
+        params
+            .scope
+            .iter()
+            .flat_map(|scope| scope.split_whitespace())
+            .map(|scope| {
+                Scope::parse_allowed(scope, state.allowed_actions(), state.resource_types())
+            })
+            .collect::<Result<_, _>>()?
+    };
+    let (checked, permissions) = comparable(
+        &scopes,
+        permissions,
+        state.normalize_subjects(),
+        state.subject_inheritance(),
+    );
 
     tracing::debug!("{:<12}- Scopes: {scopes:?}", "REQUEST");
     tracing::debug!("{:<12}- Perms: {permissions:?}", "REQUEST");
-    for scope in &scopes {
-        let permission_types: Vec<PermissionType> = permissions
-            .iter()
-            .filter(|perm| {
-                scope.kind == "repository" && (scope.name == perm.subject || perm.subject == "*")
-            })
-            .map(|perm| perm.permission.clone())
-            .collect();
-        if !scope
-            .actions
-            .iter()
-            .all(|action| permission_types.contains(action))
-        {
-            return Err(crate::Error::Unauthorized("Insufficient Permissions"));
+    let scopes = match state.unauthorized_scopes() {
+        UnauthorizedScopes::Deny => {
+            if let Some((scope, missing)) = denied_scope(&checked, &permissions) {
+                let missing = missing
+                    .iter()
+                    .map(|action| action.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                tracing::debug!(
+                    "{:<12}- {} is missing {missing} on {}:{}",
+                    "Denied",
+                    user.name,
+                    scope.kind,
+                    scope.name
+                );
+                return Err(crate::Error::Unauthorized("Insufficient Permissions"));
+            }
+            scopes
         }
-    }
+        UnauthorizedScopes::Drop => {
+            let (granted, dropped) = granted_subset(scopes, &checked, &permissions);
+            for scope in &dropped {
+                tracing::info!(
+                    "{:<12}- {scope} is not granted to {}, left out of the token",
+                    "Dropped",
+                    user.name
+                );
+            }
+            granted
+        }
+    };
 
-    if &params.service != state.docker_url() {
+    // grants are not bound to a registry, so every service gets the same scopes
+    let scope = granted_scope(&scopes);
+    let epoch = user.token_epoch(state.db()).await?;
+    let sub = state.token_subject(&user).await?;
+    let ttl = state.token_ttl(params.requested_ttl);
+    let tokens = params
+        .service
+        .into_iter()
+        .map(|service| {
+            let (token, expires_in) =
+                state.create_docker_jwt(&user.name, &sub, &service, scopes.clone(), epoch, ttl)?;
+            let response = TokenResponse {
+                token,
+                expires_in,
+                scope: scope.clone(),
+            };
+            Ok((service, response))
+        })
+        .collect::<crate::Result<Vec<_>>>()?;
+
+    Ok(Json(TokenResponses::from_tokens(tokens)))
+}
+
+// Every requested service has to be one of the configured registries
+fn verify_services(services: &[String], allowed: &[String]) -> crate::Result<()> {
+    if services.is_empty() {
+        return Err(crate::Error::BadRequest("service is missing"));
+    }
+    if let Some(service) = services.iter().find(|service| !allowed.contains(service)) {
         tracing::debug!(
-            "{:<12}- registry {} asked for registry {}",
+            "{:<12}- registry {} is not one of {:?}",
             "Error",
-            &params.service,
-            state.docker_url()
+            service,
+            allowed
         );
         return Err(crate::Error::Unauthorized("Invalid Registry"));
     }
+    Ok(())
+}
 
-    let (token, expires_in) = state.create_docker_jwt(&user.name, &params.service, scopes)?;
+#[derive(Debug, Clone, IntoParams, Deserialize)]
+pub struct CheckQuery {
+    pub user: String,
+    // The docker resource type, e.g. plugin
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    pub subject: String,
+    pub action: String,
+}
 
-    Ok(Json(TokenResponse { token, expires_in }))
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct CheckResponse {
+    allowed: bool,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = TOKEN_TAG,
+    path = "/api/check",
+    description = "Checks if a user would get a token for the action. Admin can check everyone, others only themselves",
+    params(CheckQuery),
+    responses(
+        (status = OK, description = "Success", body = CheckResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn check(
+    State(state): State<AppState>,
+    PermissionExtractor { user, permissions }: PermissionExtractor,
+    WithRejection(Query(params), _): WithRejection<Query<CheckQuery>, LoggedRejection>,
+) -> crate::Result<Json<CheckResponse>> {
+    let permissions = if params.user == user.name {
+        permissions
+    } else {
+        crate::routes::user::verify_admin(&user)?;
+        User::find_by_name(&params.user, state.db())
+            .await?
+            .list_permissions(state.db())
+            .await?
+    };
+
+    let scope = Scope::parse_allowed(
+        &format!("{}:{}:{}", params.kind, params.subject, params.action),
+        state.allowed_actions(),
+        state.resource_types(),
+    )?;
+    let (scopes, permissions) = comparable(
+        &[scope],
+        effective_permissions(&state, permissions),
+        state.normalize_subjects(),
+        state.subject_inheritance(),
+    );
+    let allowed = denied_scope(&scopes, &permissions).is_none();
+
+    Ok(Json(CheckResponse { allowed }))
+}
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct ValidateBody {
+    token: String,
+    // the registry asking, has to be one of DOCKER_URL and match the audience of the token
+    service: String,
+    // docker scope the registry is about to authorize, e.g. repository:name:pull
+    scope: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct ValidateResponse {
+    valid: bool,
+}
+
+// The access claim covers a scope the same way the grants of a user do, including
+// NORMALIZE_SUBJECTS and SUBJECT_INHERITANCE
+fn access_authorizes(
+    access: &[Scope],
+    scope: &Scope,
+    normalize_subjects: bool,
+    subject_inheritance: bool,
+) -> bool {
+    let permissions: Vec<Permission> = access
+        .iter()
+        .flat_map(|granted| {
+            granted.actions.iter().map(|action| Permission {
+                id: None,
+                kind: granted.kind.clone(),
+                subject: granted.name.clone(),
+                permission: action.clone(),
+            })
+        })
+        .collect();
+    let (scopes, permissions) = comparable(
+        std::slice::from_ref(scope),
+        permissions,
+        normalize_subjects,
+        subject_inheritance,
+    );
+
+    scopes.iter().all(|scope| scope.is_granted(&permissions))
+}
+
+#[utoipa::path(
+    method(post),
+    tag = TOKEN_TAG,
+    path = "/api/token/validate",
+    description = "For registries that validate presented tokens with augesty instead of verifying the JWT. \
+        Checks signature, expiry, force expiry, that the token was issued for the service and whether the access claim covers the scope",
+    request_body = ValidateBody,
+    responses(
+        (status = OK, description = "Success", body = ValidateResponse, content_type = "application/json")
+    )
+)]
+pub async fn validate(
+    State(state): State<AppState>,
+    WithRejection(Json(body), _): WithRejection<Json<ValidateBody>, crate::Error>,
+) -> crate::Result<Json<ValidateResponse>> {
+    let scope = Scope::parse_str(&body.scope)?;
+    verify_services(std::slice::from_ref(&body.service), state.docker_urls())?;
+    let claims = match state.verify_docker_jwt(&body.token, &body.service).await {
+        Ok(claims) => claims,
+        Err(crate::Error::Unauthorized(reason)) => {
+            tracing::debug!("{:<12}- Rejected token: {reason}", "Validate");
+            return Ok(Json(ValidateResponse { valid: false }));
+        }
+        Err(e) => return Err(e),
+    };
+
+    Ok(Json(ValidateResponse {
+        valid: access_authorizes(
+            &claims.custom.access,
+            &scope,
+            state.normalize_subjects(),
+            state.subject_inheritance(),
+        ),
+    }))
 }
 
 #[derive(Debug, Clone, ToSchema, Deserialize)]
 pub struct IdentifyBody {
     service_account: String,
+    // Optional docker scopes (e.g. repository:name:pull) to narrow the issued token to
+    #[serde(default)]
+    scope: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, ToSchema, Serialize)]
@@ -130,6 +662,73 @@ pub struct IdentifyResponse {
     accesstoken: String,
 }
 
+// Looks up the service account for identify, so CI gets a 404 instead of a db error for typos
+async fn find_service_account(name: &str, pool: &sqlx::SqlitePool) -> crate::Result<User> {
+    let not_found = crate::Error::NotFound("service account not found");
+    let user = match User::find_by_name(name, pool).await {
+        Ok(user) => user,
+        Err(crate::Error::Db(sqlx::Error::RowNotFound)) => return Err(not_found),
+        Err(e) => return Err(e),
+    };
+    if user.user_type != UserType::ServiceAccount {
+        return Err(not_found);
+    }
+
+    Ok(user)
+}
+
+// The scope embedded in a svc token. A requested scope has to be granted, without one the
+// token is only narrowed when claim conditions dropped some of the actions
+async fn svc_token_scope(
+    state: &AppState,
+    svc_account: &User,
+    claims: &BTreeMap<String, String>,
+    requested: Option<Vec<String>>,
+) -> crate::Result<Option<Vec<Scope>>> {
+    let granted = svc_account.list_permissions(state.db()).await?;
+    let conditions = ClaimCondition::list_for_user(
+        svc_account
+            .id
+            .ok_or(crate::Error::Opaque("User ID must exist"))?,
+        state.db(),
+    )
+    .await?;
+    let permissions = ClaimCondition::narrow(granted.clone(), &conditions, claims);
+
+    let scope = match requested {
+        Some(scope) => {
+            let scopes: Vec<Scope> = scope
+                .iter()
+                .map(|scope| {
+                    Scope::parse_allowed(scope, state.allowed_actions(), state.resource_types())
+                })
+                .collect::<Result<_, _>>()?;
+            // compared like on /api/token, the token keeps the names as requested
+            let (checked, permissions) = comparable(
+                &scopes,
+                permissions.clone(),
+                state.normalize_subjects(),
+                state.subject_inheritance(),
+            );
+            if !checked.iter().all(|scope| scope.is_granted(&permissions)) {
+                return Err(crate::Error::Unauthorized(
+                    "Requested scope exceeds the service account permissions",
+                ));
+            }
+            Some(scopes)
+        }
+        // unmet conditions narrow the token to the remaining docker actions
+        None if permissions.len() < granted.len() => Some(all_granted_scopes(
+            &permissions,
+            state.allowed_actions(),
+            state.resource_types(),
+        )),
+        None => None,
+    };
+
+    Ok(scope)
+}
+
 #[utoipa::path(
     method(post),
     tag = TOKEN_TAG,
@@ -137,25 +736,565 @@ pub struct IdentifyResponse {
     description = "The endpoint for the action to request a service account jwt",
     request_body = IdentifyBody,
     responses(
-        (status = OK, description = "Success", body = IdentifyResponse, content_type = "application/json")
+        (status = OK, description = "Success", body = IdentifyResponse, content_type = "application/json"),
+        (status = NOT_FOUND, description = "The service account does not exist")
     ),
-    security(("service_bearer" = []))
+    security(("github_oidc" = []))
 )]
 pub async fn identify(
     State(state): State<AppState>,
-    GithubExtractor(repo): GithubExtractor,
-    Json(body): Json<IdentifyBody>,
+    GithubExtractor(identity): GithubExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<IdentifyBody>, crate::Error>,
 ) -> crate::Result<Json<IdentifyResponse>> {
-    let svc_account = User::find_by_name(&body.service_account, state.db()).await?;
-    let idents = svc_account.get_identifiers(state.db()).await?;
-
-    if !idents.iter().any(|ident| *ident == *repo) {
+    let svc_account = find_service_account(&body.service_account, state.db()).await?;
+    let candidates = identity.identifiers(state.oidc_claim());
+    if !svc_account.has_identifier(&candidates, state.db()).await? {
         return Err(crate::Error::Unauthorized(
             "This repo cant access this service account",
         ));
     }
 
-    let accesstoken = state.create_jwt(svc_account.name)?;
+    let scope = svc_token_scope(&state, &svc_account, &identity.claims, body.scope).await?;
+
+    state.consume_oidc_jti(&identity.jti)?;
+    let epoch = svc_account.token_epoch(state.db()).await?;
+    let accesstoken = state.create_jwt(svc_account.name, scope, epoch)?;
 
     Ok(Json(IdentifyResponse { accesstoken }))
 }
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct ApiKeyIdentifyBody {
+    service_account: String,
+    api_key: String,
+    // Optional docker scopes (e.g. repository:name:pull) to narrow the issued token to
+    #[serde(default)]
+    scope: Option<Vec<String>>,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = TOKEN_TAG,
+    path = "/api/identify/api_key",
+    description = "Exchanges the API key of a service account for a service account jwt, for CI without OIDC. \
+        Actions with claim conditions are never granted this way",
+    request_body = ApiKeyIdentifyBody,
+    responses(
+        (status = OK, description = "Success", body = IdentifyResponse, content_type = "application/json"),
+        (status = UNAUTHORIZED, description = "Unknown service account or wrong API key")
+    )
+)]
+pub async fn identify_api_key(
+    State(state): State<AppState>,
+    extensions: Extensions,
+    WithRejection(Json(body), _): WithRejection<Json<ApiKeyIdentifyBody>, crate::Error>,
+) -> crate::Result<Json<IdentifyResponse>> {
+    state.verify_password_length(&body.api_key)?;
+    let svc_account = state
+        .verify_api_key(&body.service_account, &body.api_key)
+        .await?;
+    Principal::record(&extensions, &svc_account.name);
+
+    // without OIDC claims no condition can hold
+    let scope = svc_token_scope(&state, &svc_account, &BTreeMap::new(), body.scope).await?;
+    let epoch = svc_account.token_epoch(state.db()).await?;
+    let accesstoken = state.create_jwt(svc_account.name, scope, epoch)?;
+
+    Ok(Json(IdentifyResponse { accesstoken }))
+}
+
+#[test]
+fn test_scope_is_granted() -> crate::Result<()> {
+    let permissions = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "example/image".to_string(),
+        permission: PermissionType::Pull,
+    }];
+
+    assert!(Scope::parse_str("repository:example/image:pull")?.is_granted(&permissions));
+    assert!(!Scope::parse_str("repository:example/image:pull,push")?.is_granted(&permissions));
+    assert!(!Scope::parse_str("repository:other/image:pull")?.is_granted(&permissions));
+    assert_eq!(
+        Scope::parse_str("repository:example/image:pull,push")?.missing_actions(&permissions),
+        vec![PermissionType::Push]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_scope_parse_errors() {
+    let malformed = [
+        "repository",
+        "repository:example/image",
+        ":example/image:pull",
+        "repository::pull",
+        "repository:example/image:",
+        "repository:example/image: , ",
+        "repository:example/image:delete",
+    ];
+
+    for input in malformed {
+        let result = Scope::parse_str(input);
+        assert!(
+            matches!(result, Err(crate::Error::Parse(_))),
+            "{input} should be rejected as a parse error"
+        );
+    }
+}
+
+#[test]
+fn test_plugin_scope_is_granted() -> crate::Result<()> {
+    let permissions = vec![Permission {
+        id: None,
+        kind: "plugin".to_string(),
+        subject: "example/plugin".to_string(),
+        permission: PermissionType::Pull,
+    }];
+
+    assert!(Scope::parse_str("plugin:example/plugin:pull")?.is_granted(&permissions));
+    assert!(!Scope::parse_str("repository:example/plugin:pull")?.is_granted(&permissions));
+
+    Ok(())
+}
+
+#[test]
+fn test_granted_scope() -> crate::Result<()> {
+    let scopes = vec![
+        Scope::parse_str("repository:example/image:pull,push")?,
+        Scope::parse_str("repository:other/image:pull")?,
+    ];
+
+    assert_eq!(
+        granted_scope(&scopes).as_deref(),
+        Some("repository:example/image:pull,push repository:other/image:pull")
+    );
+    assert_eq!(granted_scope(&[]), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_pull_implies_catalog() -> crate::Result<()> {
+    let permissions = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "example/image".to_string(),
+        permission: PermissionType::Pull,
+    }];
+    let catalog = Scope::parse_str("registry:catalog:*")?;
+
+    assert!(!catalog.is_granted(&implied_permissions(permissions.clone(), false, false)));
+    assert!(catalog.is_granted(&implied_permissions(permissions, true, false)));
+    assert!(!catalog.is_granted(&implied_permissions(Vec::new(), true, false)));
+
+    Ok(())
+}
+
+#[test]
+fn test_push_implies_pull() -> crate::Result<()> {
+    let permissions = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "example/image".to_string(),
+        permission: PermissionType::Push,
+    }];
+    let pull = Scope::parse_str("repository:example/image:pull,push")?;
+
+    assert!(!pull.is_granted(&implied_permissions(permissions.clone(), false, false)));
+    assert!(pull.is_granted(&implied_permissions(permissions.clone(), false, true)));
+    assert!(
+        !Scope::parse_str("repository:other/image:pull")?.is_granted(&implied_permissions(
+            permissions,
+            false,
+            true
+        ))
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_identify_missing_service_account() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+
+    assert!(find_service_account("ci", &pool).await.is_ok());
+    assert!(matches!(
+        find_service_account("missing", &pool).await,
+        Err(crate::Error::NotFound(_))
+    ));
+    assert!(matches!(
+        find_service_account("dev", &pool).await,
+        Err(crate::Error::NotFound(_))
+    ));
+
+    Ok(())
+}
+
+#[cfg(test)]
+fn standard_types() -> Vec<String> {
+    DEFAULT_RESOURCE_TYPES.map(str::to_string).to_vec()
+}
+
+#[test]
+fn test_scope_allowed_actions() -> crate::Result<()> {
+    let types = standard_types();
+    assert!(
+        Scope::parse_allowed(
+            "repository:example/image:pull,push",
+            &PermissionType::ACTIONS,
+            &types
+        )
+        .is_ok()
+    );
+
+    let pull_only = [PermissionType::Pull];
+    assert!(Scope::parse_allowed("repository:example/image:pull", &pull_only, &types).is_ok());
+    assert!(matches!(
+        Scope::parse_allowed("repository:example/image:pull,push", &pull_only, &types),
+        Err(crate::Error::BadRequest(_))
+    ));
+    assert!(matches!(
+        Scope::parse_allowed("registry:catalog:*", &pull_only, &types),
+        Err(crate::Error::BadRequest(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_scope_resource_types() -> crate::Result<()> {
+    let types = standard_types();
+    for scope in [
+        "repository:example/image:pull",
+        "registry:catalog:*",
+        "plugin:example/plugin:pull",
+    ] {
+        assert!(Scope::parse_allowed(scope, &PermissionType::ACTIONS, &types).is_ok());
+    }
+    for scope in ["image:example/image:pull", "Repository:example/image:pull"] {
+        assert!(matches!(
+            Scope::parse_allowed(scope, &PermissionType::ACTIONS, &types),
+            Err(crate::Error::BadRequest(
+                "Scope contains an unknown resource type"
+            ))
+        ));
+    }
+
+    // a narrowed allowlist drops the other types, also from `scope=*`
+    let repository_only = vec!["repository".to_string()];
+    assert!(
+        Scope::parse_allowed(
+            "plugin:example/plugin:pull",
+            &PermissionType::ACTIONS,
+            &repository_only
+        )
+        .is_err()
+    );
+    let permissions = vec![
+        Permission {
+            id: None,
+            kind: "repository".to_string(),
+            subject: "example/image".to_string(),
+            permission: PermissionType::Pull,
+        },
+        Permission {
+            id: None,
+            kind: "plugin".to_string(),
+            subject: "example/plugin".to_string(),
+            permission: PermissionType::Pull,
+        },
+    ];
+    let scopes = all_granted_scopes(&permissions, &PermissionType::ACTIONS, &repository_only);
+    assert_eq!(scopes.len(), 1);
+    assert_eq!(scopes[0].kind, "repository");
+
+    Ok(())
+}
+
+#[test]
+fn test_check_exact_and_wildcard() -> crate::Result<()> {
+    let permissions = vec![
+        Permission {
+            id: None,
+            kind: "repository".to_string(),
+            subject: "*".to_string(),
+            permission: PermissionType::Pull,
+        },
+        Permission {
+            id: None,
+            kind: "repository".to_string(),
+            subject: "example/image".to_string(),
+            permission: PermissionType::Push,
+        },
+    ];
+
+    // wildcard
+    assert!(Scope::parse_str("repository:any/image:pull")?.is_granted(&permissions));
+    assert!(!Scope::parse_str("repository:any/image:push")?.is_granted(&permissions));
+    // exact
+    assert!(Scope::parse_str("repository:example/image:push")?.is_granted(&permissions));
+    assert!(!Scope::parse_str("plugin:example/image:push")?.is_granted(&permissions));
+
+    Ok(())
+}
+
+#[test]
+fn test_cross_repo_mount_scopes() -> crate::Result<()> {
+    let permission = |subject: &str, permission| Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: subject.to_string(),
+        permission,
+    };
+    let scopes = vec![
+        Scope::parse_str("repository:example/target:pull,push")?,
+        Scope::parse_str("repository:example/source:pull")?,
+    ];
+
+    let permissions = vec![
+        permission("example/target", PermissionType::Pull),
+        permission("example/target", PermissionType::Push),
+        permission("example/source", PermissionType::Pull),
+    ];
+    assert!(denied_scope(&scopes, &permissions).is_none());
+    assert_eq!(
+        granted_scope(&scopes).as_deref(),
+        Some("repository:example/target:pull,push repository:example/source:pull")
+    );
+
+    // without pull on the source the whole mount is denied
+    let (scope, missing) = denied_scope(&scopes, &permissions[..2]).unwrap();
+    assert_eq!(scope.name, "example/source");
+    assert_eq!(missing, vec![PermissionType::Pull]);
+
+    Ok(())
+}
+
+#[test]
+fn test_unauthorized_scopes_deny_or_drop() -> crate::Result<()> {
+    let permissions = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "example/image".to_string(),
+        permission: PermissionType::Pull,
+    }];
+    let scopes = vec![
+        Scope::parse_str("repository:Example/Image:pull,push")?,
+        Scope::parse_str("repository:example/unknown:pull")?,
+        Scope::parse_str("repository:example/image:pull")?,
+    ];
+    let (checked, permissions) = normalized(&scopes, permissions, true);
+
+    // deny refuses the whole token
+    let (scope, missing) = denied_scope(&checked, &permissions).unwrap();
+    assert_eq!(scope.name, "example/image");
+    assert_eq!(missing, vec![PermissionType::Push]);
+
+    // drop keeps the granted actions in the requested spelling
+    let (granted, dropped) = granted_subset(scopes, &checked, &permissions);
+    assert_eq!(
+        granted_scope(&granted).as_deref(),
+        Some("repository:Example/Image:pull repository:example/image:pull")
+    );
+    assert_eq!(
+        granted_scope(&dropped).as_deref(),
+        Some("repository:Example/Image:push repository:example/unknown:pull")
+    );
+
+    // nothing granted still issues a token, it just carries no access
+    let unknown = vec![Scope::parse_str("repository:example/unknown:pull")?];
+    let (granted, dropped) = granted_subset(unknown.clone(), &unknown, &permissions);
+    assert!(granted.is_empty());
+    assert_eq!(dropped, unknown);
+
+    Ok(())
+}
+
+#[test]
+fn test_tokens_for_two_services() -> crate::Result<()> {
+    let allowed = vec![
+        "registry.example.com".to_string(),
+        "mirror.example.com".to_string(),
+    ];
+    assert!(verify_services(&allowed, &allowed).is_ok());
+    assert!(verify_services(&[], &allowed).is_err());
+    assert!(
+        verify_services(
+            &[
+                "registry.example.com".to_string(),
+                "other.example.com".to_string()
+            ],
+            &allowed
+        )
+        .is_err()
+    );
+
+    let response = |token: &str| TokenResponse {
+        token: token.to_string(),
+        expires_in: 300,
+        scope: None,
+    };
+    let single = TokenResponses::from_tokens(vec![(allowed[0].clone(), response("a"))]);
+    let json = serde_json::to_value(&single).map_err(|e| crate::Error::Any(e.to_string()))?;
+    assert_eq!(json["token"], "a");
+
+    let multiple = TokenResponses::from_tokens(vec![
+        (allowed[0].clone(), response("a")),
+        (allowed[1].clone(), response("b")),
+    ]);
+    let json = serde_json::to_value(&multiple).map_err(|e| crate::Error::Any(e.to_string()))?;
+    assert_eq!(json["tokens"]["registry.example.com"]["token"], "a");
+    assert_eq!(json["tokens"]["mirror.example.com"]["token"], "b");
+
+    Ok(())
+}
+
+#[test]
+fn test_subject_normalization() -> crate::Result<()> {
+    let scopes = vec![Scope::parse_str("repository:myorg/app:pull")?];
+    let permissions = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "MyOrg/App".to_string(),
+        permission: PermissionType::Pull,
+    }];
+
+    // registry semantics by default, the case has to match
+    let (checked, perms) = normalized(&scopes, permissions.clone(), false);
+    assert!(denied_scope(&checked, &perms).is_some());
+
+    let (checked, perms) = normalized(&scopes, permissions, true);
+    assert!(denied_scope(&checked, &perms).is_none());
+    // only the comparison is lowercased
+    assert_eq!(scopes[0].name, "myorg/app");
+
+    assert_eq!(normalize_subject("MyOrg/App", false), "MyOrg/App");
+    assert_eq!(normalize_subject("MyOrg/App", true), "myorg/app");
+
+    Ok(())
+}
+
+#[test]
+fn test_all_scopes_embeds_every_grant() -> crate::Result<()> {
+    let grant = |kind: &str, subject: &str, permission| Permission {
+        id: None,
+        kind: kind.to_string(),
+        subject: subject.to_string(),
+        permission,
+    };
+    let permissions = vec![
+        grant("repository", "example/image", PermissionType::Pull),
+        grant("repository", "example/image", PermissionType::Push),
+        grant("repository", "other/image", PermissionType::Pull),
+        grant("repository", "other/image", PermissionType::Pull),
+        grant("plugin", "example/plugin", PermissionType::Pull),
+        grant("repository", "*", PermissionType::Audit),
+    ];
+
+    let scopes = all_granted_scopes(&permissions, &PermissionType::ACTIONS, &standard_types());
+    let scopes: Vec<String> = scopes.iter().map(|scope| scope.to_string()).collect();
+    assert_eq!(
+        scopes,
+        vec![
+            "plugin:example/plugin:pull",
+            "repository:example/image:pull,push",
+            "repository:other/image:pull",
+        ]
+    );
+
+    let pull_only = all_granted_scopes(&permissions, &[PermissionType::Pull], &standard_types());
+    assert!(
+        pull_only
+            .iter()
+            .all(|scope| scope.actions == vec![PermissionType::Pull])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_subject_inheritance() -> crate::Result<()> {
+    let permissions = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "example".to_string(),
+        permission: PermissionType::Pull,
+    }];
+    let scopes = [
+        Scope::parse_str("repository:example:pull")?,
+        Scope::parse_str("repository:example/team/service:pull")?,
+    ];
+
+    // exact matches only by default
+    let inherited = inherited_permissions(&scopes, permissions.clone(), false);
+    assert!(scopes[0].is_granted(&inherited));
+    assert!(!scopes[1].is_granted(&inherited));
+
+    let inherited = inherited_permissions(&scopes, permissions.clone(), true);
+    assert!(denied_scope(&scopes, &inherited).is_none());
+
+    // only whole path segments are inherited, and never other actions or kinds
+    let outside = [
+        Scope::parse_str("repository:example-infra/service:pull")?,
+        Scope::parse_str("repository:example/service:push")?,
+        Scope::parse_str("plugin:example/service:pull")?,
+    ];
+    let inherited = inherited_permissions(&outside, permissions, true);
+    assert!(outside.iter().all(|scope| !scope.is_granted(&inherited)));
+
+    Ok(())
+}
+
+#[test]
+fn test_access_authorizes_scope() -> crate::Result<()> {
+    let access = vec![
+        Scope::parse_str("repository:example/image:pull,push")?,
+        Scope::parse_str("repository:*:pull")?,
+    ];
+
+    assert!(access_authorizes(
+        &access,
+        &Scope::parse_str("repository:example/image:push")?,
+        false,
+        false
+    ));
+    assert!(access_authorizes(
+        &access,
+        &Scope::parse_str("repository:other/image:pull")?,
+        false,
+        false
+    ));
+    assert!(!access_authorizes(
+        &access,
+        &Scope::parse_str("repository:other/image:pull,push")?,
+        false,
+        false
+    ));
+    assert!(!access_authorizes(
+        &access,
+        &Scope::parse_str("plugin:example/image:pull")?,
+        false,
+        false
+    ));
+    assert!(!access_authorizes(
+        &[],
+        &Scope::parse_str("repository:example/image:pull")?,
+        false,
+        false
+    ));
+
+    // the same normalization and inheritance as /api/token
+    let access = vec![Scope::parse_str("repository:MyOrg/Team:pull")?];
+    let nested = Scope::parse_str("repository:myorg/team/app:pull")?;
+    assert!(!access_authorizes(&access, &nested, true, false));
+    assert!(!access_authorizes(&access, &nested, false, true));
+    assert!(access_authorizes(&access, &nested, true, true));
+
+    Ok(())
+}
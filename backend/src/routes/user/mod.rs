@@ -1,30 +1,101 @@
 mod user;
 use axum::{Json, extract::State};
+use axum_extra::extract::{Query, WithRejection};
 use serde::{Deserialize, Serialize};
 pub use user::*;
 mod svc;
 pub use svc::*;
-use utoipa::ToSchema;
+use utoipa::{IntoParams, ToSchema};
 
-use crate::{USER_TAG, extractors::PermissionExtractor, models::user::User, state::AppState};
+use crate::{
+    USER_TAG,
+    error::LoggedRejection,
+    extractors::PermissionExtractor,
+    models::{
+        permission::{
+            GroupedGrant, Permission, PermissionType, UserGrant, default_kind, normalize_subject,
+        },
+        user::{User, UserType},
+    },
+    state::AppState,
+    webhook::{PermissionAction, PermissionEvent},
+};
 
-pub(self) fn verify_admin(user: &User) -> crate::Result<()> {
+pub(crate) fn verify_admin(user: &User) -> crate::Result<()> {
     if user.name != "admin" {
         return Err(crate::Error::Unauthorized("Only admin can manage users"));
     }
     Ok(())
 }
 
-#[derive(Debug, Clone, ToSchema, Deserialize)]
+// Auditors can read the listings but not modify anything, admin can do both
+fn verify_auditor(user: &User, permissions: &[Permission]) -> crate::Result<()> {
+    if user.name != "admin"
+        && !permissions
+            .iter()
+            .any(|perm| perm.permission == PermissionType::Audit)
+    {
+        return Err(crate::Error::Unauthorized(
+            "Only admin or auditors can list",
+        ));
+    }
+    Ok(())
+}
+
+// The admin account must keep its wildcard grants, otherwise nobody can manage users anymore
+fn verify_not_admin_wildcard(name: &str, subject: &str) -> crate::Result<()> {
+    if name == "admin" && subject == "*" {
+        return Err(crate::Error::BadRequest(
+            "Cannot revoke the wildcard permissions of admin",
+        ));
+    }
+    Ok(())
+}
+
+fn verify_not_admin(name: &str) -> crate::Result<()> {
+    if name == "admin" {
+        return Err(crate::Error::BadRequest(
+            "Cannot delete or rename the admin account",
+        ));
+    }
+    Ok(())
+}
+
+// Audit is not a docker action, so only actual actions are checked against the allowlist
+fn verify_action_allowed(access: &PermissionType, allowed: &[PermissionType]) -> crate::Result<()> {
+    if PermissionType::ACTIONS.contains(access) && !allowed.contains(access) {
+        return Err(crate::Error::BadRequest("Action is not allowed"));
+    }
+    Ok(())
+}
+
+// A grant /api/token would never accept is rejected instead of stored, e.g. a typo in the kind
+fn verify_grant(kind: &str, image: &str, resource_types: &[String]) -> crate::Result<()> {
+    if !resource_types
+        .iter()
+        .any(|resource_type| resource_type == kind)
+    {
+        return Err(crate::Error::BadRequest("Unknown resource type"));
+    }
+    if image.trim().is_empty() {
+        return Err(crate::Error::BadRequest("Image must be non-empty"));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct GrantAccessBody {
-    name: String,
-    image: String,
-    access: String,
+    pub name: String,
+    // The docker resource type, e.g. plugin
+    #[serde(default = "default_kind")]
+    pub kind: String,
+    pub image: String,
+    pub access: String,
 }
 
-#[derive(Debug, Clone, ToSchema, Serialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct GrantAccessResponse {
-    user_name: String,
+    pub user_name: String,
 }
 
 #[utoipa::path(
@@ -40,16 +111,388 @@ pub struct GrantAccessResponse {
 )]
 pub async fn grant_access(
     State(state): State<AppState>,
-    PermissionExtractor { user, .. }: PermissionExtractor,
-    Json(body): Json<GrantAccessBody>,
+    PermissionExtractor { user: actor, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<GrantAccessBody>, crate::Error>,
 ) -> crate::Result<Json<GrantAccessResponse>> {
-    verify_admin(&user)?;
+    verify_admin(&actor)?;
+    state.verify_writable()?;
+    let access: PermissionType = body.access.parse()?;
+    verify_action_allowed(&access, state.allowed_actions())?;
+    verify_grant(&body.kind, &body.image, state.resource_types())?;
+
+    let image = normalize_subject(&body.image, state.normalize_subjects());
 
     let user = User::find_by_name(&body.name, state.db()).await?;
-    user.add_permission(body.image, body.access, state.db())
+    user.add_permission(body.kind.clone(), image.clone(), access, state.db())
         .await?;
+    state.notify_permission_change(PermissionEvent::new(
+        PermissionAction::Grant,
+        user.name.clone(),
+        body.kind,
+        image,
+        body.access,
+        actor.name,
+    ));
 
     Ok(Json(GrantAccessResponse {
         user_name: user.name,
     }))
 }
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct RevokeAccessBody {
+    name: String,
+    // The docker resource type, e.g. plugin
+    #[serde(default = "default_kind")]
+    kind: String,
+    image: String,
+    access: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct RevokeAccessResponse {
+    user_name: String,
+}
+
+#[utoipa::path(
+    method(delete),
+    tag = USER_TAG,
+    path = "/api/user/access",
+    description = "Only admin can call",
+    request_body = RevokeAccessBody,
+    responses(
+        (status = OK, description = "Success", body = RevokeAccessResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn revoke_access(
+    State(state): State<AppState>,
+    PermissionExtractor { user: actor, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<RevokeAccessBody>, crate::Error>,
+) -> crate::Result<Json<RevokeAccessResponse>> {
+    verify_admin(&actor)?;
+    state.verify_writable()?;
+    verify_not_admin_wildcard(&body.name, &body.image)?;
+    let image = normalize_subject(&body.image, state.normalize_subjects());
+
+    let user = User::find_by_name(&body.name, state.db()).await?;
+    user.remove_permission(
+        body.kind.clone(),
+        image.clone(),
+        body.access.clone(),
+        state.db(),
+    )
+    .await?;
+    state.notify_permission_change(PermissionEvent::new(
+        PermissionAction::Revoke,
+        user.name.clone(),
+        body.kind,
+        image,
+        body.access,
+        actor.name,
+    ));
+
+    Ok(Json(RevokeAccessResponse {
+        user_name: user.name,
+    }))
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct AdminLoginResponse {
+    pub token: String,
+    // in seconds
+    pub expires_in: u64,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = USER_TAG,
+    path = "/api/admin/login",
+    description = "Exchanges the admin credentials for a short lived bearer token the admin endpoints accept. \
+        POST /api/user/expire_tokens for admin revokes it. Only admin can call, with Basic auth",
+    responses(
+        (status = OK, description = "Success", body = AdminLoginResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn admin_login(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+) -> crate::Result<Json<AdminLoginResponse>> {
+    // an admin token cannot extend itself, the extractor only takes Basic auth on token routes
+    verify_admin(&user)?;
+
+    let epoch = user.token_epoch(state.db()).await?;
+    let (token, expires_in) = state.create_admin_jwt(epoch)?;
+
+    Ok(Json(AdminLoginResponse { token, expires_in }))
+}
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct ClearAccessBody {
+    name: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct ClearAccessResponse {
+    user_name: String,
+    removed: usize,
+}
+
+#[utoipa::path(
+    method(delete),
+    tag = USER_TAG,
+    path = "/api/user/access/all",
+    description = "Revokes every permission of a user at once. The wildcard grants of admin are kept. Only admin can call",
+    request_body = ClearAccessBody,
+    responses(
+        (status = OK, description = "Success", body = ClearAccessResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn clear_access(
+    State(state): State<AppState>,
+    PermissionExtractor { user: actor, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<ClearAccessBody>, crate::Error>,
+) -> crate::Result<Json<ClearAccessResponse>> {
+    verify_admin(&actor)?;
+    state.verify_writable()?;
+
+    let user = User::find_by_name(&body.name, state.db()).await?;
+    // admin keeps its wildcard grants like with a single revoke
+    let removed = user
+        .clear_permissions(user.name == "admin", state.db())
+        .await?;
+    for perm in &removed {
+        state.notify_permission_change(PermissionEvent::new(
+            PermissionAction::Revoke,
+            user.name.clone(),
+            perm.kind.clone(),
+            perm.subject.clone(),
+            perm.permission.to_string(),
+            actor.name.clone(),
+        ));
+    }
+
+    Ok(Json(ClearAccessResponse {
+        user_name: user.name,
+        removed: removed.len(),
+    }))
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct ListUsersEntry {
+    name: String,
+    user_type: UserType,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = USER_TAG,
+    path = "/api/user",
+    description = "Only admin or auditors can call",
+    responses(
+        (status = OK, description = "Success", body = Vec<ListUsersEntry>, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn list_users(
+    State(state): State<AppState>,
+    PermissionExtractor { user, permissions }: PermissionExtractor,
+) -> crate::Result<Json<Vec<ListUsersEntry>>> {
+    verify_auditor(&user, &permissions)?;
+
+    let users = User::list(state.db())
+        .await?
+        .into_iter()
+        .map(|user| ListUsersEntry {
+            name: user.name,
+            user_type: user.user_type,
+        })
+        .collect();
+
+    Ok(Json(users))
+}
+
+#[derive(Debug, Clone, IntoParams, Deserialize)]
+pub struct ListPermissionsQuery {
+    #[serde(default = "default_limit")]
+    pub limit: i64,
+    #[serde(default)]
+    pub offset: i64,
+    // one entry per user and subject with all its actions instead of one per action
+    #[serde(default)]
+    pub grouped: bool,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+#[serde(untagged)]
+pub enum ListPermissionsResponse {
+    Flat(Vec<UserGrant>),
+    Grouped(Vec<GroupedGrant>),
+}
+
+#[utoipa::path(
+    method(get),
+    tag = USER_TAG,
+    path = "/api/permissions",
+    description = "Every grant of every user for access reviews. With grouped=true the actions on a subject \
+        are returned together, limit and offset still count single grants. Only admin or auditors can call",
+    params(ListPermissionsQuery),
+    responses(
+        (status = OK, description = "Success", body = ListPermissionsResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn list_all_permissions(
+    State(state): State<AppState>,
+    PermissionExtractor { user, permissions }: PermissionExtractor,
+    WithRejection(Query(params), _): WithRejection<Query<ListPermissionsQuery>, LoggedRejection>,
+) -> crate::Result<Json<ListPermissionsResponse>> {
+    verify_auditor(&user, &permissions)?;
+    if !(1..=1000).contains(&params.limit) || params.offset < 0 {
+        return Err(crate::Error::BadRequest(
+            "limit has to be between 1 and 1000 and offset positive",
+        ));
+    }
+
+    let grants = Permission::list_all_with_users(params.limit, params.offset, state.db()).await?;
+    let response = if params.grouped {
+        ListPermissionsResponse::Grouped(GroupedGrant::group(grants))
+    } else {
+        ListPermissionsResponse::Flat(grants)
+    };
+
+    Ok(Json(response))
+}
+
+#[derive(Debug, Clone, IntoParams, Deserialize)]
+pub struct ListSubjectsQuery {
+    #[serde(default)]
+    pub prefix: String,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = USER_TAG,
+    path = "/api/permissions/subjects",
+    description = "Distinct granted subjects for autocompletion, optionally starting with prefix. Only admin can call",
+    params(ListSubjectsQuery),
+    responses(
+        (status = OK, description = "Success", body = Vec<String>, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn list_subjects(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Query(params), _): WithRejection<Query<ListSubjectsQuery>, LoggedRejection>,
+) -> crate::Result<Json<Vec<String>>> {
+    verify_admin(&user)?;
+
+    let subjects = Permission::list_subjects(&params.prefix, state.db()).await?;
+
+    Ok(Json(subjects))
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct ReadOnlyBody {
+    pub read_only: bool,
+}
+
+#[utoipa::path(
+    method(put),
+    tag = USER_TAG,
+    path = "/api/read_only",
+    description = "Toggles the read only mode, which rejects every mutating admin call with 503 while tokens are still issued. Only admin can call",
+    request_body = ReadOnlyBody,
+    responses(
+        (status = OK, description = "Success", body = ReadOnlyBody, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn set_read_only(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<ReadOnlyBody>, crate::Error>,
+) -> crate::Result<Json<ReadOnlyBody>> {
+    verify_admin(&user)?;
+    state.set_read_only(body.read_only);
+
+    Ok(Json(ReadOnlyBody {
+        read_only: state.read_only(),
+    }))
+}
+
+#[test]
+fn test_auditor_can_list_but_not_manage() {
+    let auditor = User::new_user("auditor".to_string());
+    let audit = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "*".to_string(),
+        permission: PermissionType::Audit,
+    }];
+
+    assert!(verify_auditor(&auditor, &audit).is_ok());
+    assert!(verify_admin(&auditor).is_err());
+}
+
+#[test]
+fn test_roles_for_admin_and_plain_user() {
+    let admin = User::new_user("admin".to_string());
+    assert!(verify_auditor(&admin, &[]).is_ok());
+    assert!(verify_admin(&admin).is_ok());
+
+    let user = User::new_user("ci".to_string());
+    let pull = vec![Permission {
+        id: None,
+        kind: "repository".to_string(),
+        subject: "*".to_string(),
+        permission: PermissionType::Pull,
+    }];
+    assert!(verify_auditor(&user, &pull).is_err());
+    assert!(verify_admin(&user).is_err());
+}
+
+#[test]
+fn test_admin_wildcard_is_protected() {
+    assert!(verify_not_admin_wildcard("admin", "*").is_err());
+    assert!(verify_not_admin_wildcard("admin", "example/image").is_ok());
+    assert!(verify_not_admin_wildcard("ci", "*").is_ok());
+}
+
+#[test]
+fn test_admin_account_is_protected() {
+    assert!(verify_not_admin("admin").is_err());
+    assert!(verify_not_admin("ci").is_ok());
+}
+
+#[test]
+fn test_grant_respects_allowed_actions() {
+    let pull_only = [PermissionType::Pull];
+    assert!(verify_action_allowed(&PermissionType::Pull, &pull_only).is_ok());
+    assert!(verify_action_allowed(&PermissionType::Audit, &pull_only).is_ok());
+    assert!(verify_action_allowed(&PermissionType::Push, &pull_only).is_err());
+    assert!(verify_action_allowed(&PermissionType::Push, &PermissionType::ACTIONS).is_ok());
+}
+
+#[test]
+fn test_grant_needs_a_known_kind_and_an_image() {
+    let resource_types = ["repository".to_string(), "plugin".to_string()];
+    assert!(verify_grant("repository", "example/image", &resource_types).is_ok());
+    assert!(verify_grant("plugin", "example/image", &resource_types).is_ok());
+    assert!(matches!(
+        verify_grant("plugins", "example/image", &resource_types),
+        Err(crate::Error::BadRequest("Unknown resource type"))
+    ));
+    assert!(matches!(
+        verify_grant("repository", "", &resource_types),
+        Err(crate::Error::BadRequest("Image must be non-empty"))
+    ));
+    assert!(verify_grant("repository", " ", &resource_types).is_err());
+}
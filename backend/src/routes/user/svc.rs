@@ -1,24 +1,37 @@
 use axum::{Json, extract::State};
+use axum_extra::extract::WithRejection;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-use crate::{USER_TAG, extractors::PermissionExtractor, models::user::User, state::AppState};
+use crate::{
+    USER_TAG,
+    extractors::PermissionExtractor,
+    models::{
+        claim_condition::ClaimCondition,
+        permission::PermissionType,
+        svc_api_key::SvcApiKey,
+        user::{User, UserType},
+        user_identifier::{MatchStrategy, UserIdentifier},
+    },
+    state::AppState,
+    webhook::{PermissionAction, PermissionEvent},
+};
 
-#[derive(Debug, Clone, ToSchema, Deserialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct CreateServiceAccountBody {
-    name: String,
+    pub name: String,
 }
 
-#[derive(Debug, Clone, ToSchema, Serialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct CreateServiceAccountResponse {
-    svc_name: String,
+    pub svc_name: String,
 }
 
 #[utoipa::path(
     method(post),
     tag = USER_TAG,
     path = "/api/service_account",
-    description = "Only admin can call",
+    description = "The account starts with the DEFAULT_PERMISSIONS grants. Only admin can call",
     request_body = CreateServiceAccountBody,
     responses(
         (status = OK, description = "Success", body = CreateServiceAccountResponse, content_type = "application/json")
@@ -27,35 +40,52 @@ pub struct CreateServiceAccountResponse {
 )]
 pub async fn create_service_account(
     State(state): State<AppState>,
-    PermissionExtractor { user, .. }: PermissionExtractor,
-    Json(body): Json<CreateServiceAccountBody>,
+    PermissionExtractor { user: actor, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<CreateServiceAccountBody>, crate::Error>,
 ) -> crate::Result<Json<CreateServiceAccountResponse>> {
-    super::verify_admin(&user)?;
+    super::verify_admin(&actor)?;
+    state.verify_writable()?;
+    User::validate_name(&body.name)?;
 
     let user = User::new_service_account(body.name);
-    user.insert(state.db()).await?;
+    user.insert_with_permissions(state.default_permissions(), state.db())
+        .await?;
+    for perm in state.default_permissions() {
+        state.notify_permission_change(PermissionEvent::new(
+            PermissionAction::Grant,
+            user.name.clone(),
+            perm.kind.clone(),
+            perm.subject.clone(),
+            perm.permission.to_string(),
+            actor.name.clone(),
+        ));
+    }
 
     Ok(Json(CreateServiceAccountResponse {
         svc_name: user.name,
     }))
 }
 
-#[derive(Debug, Clone, ToSchema, Deserialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct AddIdentifierBody {
-    svc_name: String,
-    repo: String,
+    pub svc_name: String,
+    pub repo: String,
+    // `prefix` when repo ends in `/*`, `exact` otherwise
+    #[serde(default)]
+    pub match_strategy: Option<MatchStrategy>,
 }
 
-#[derive(Debug, Clone, ToSchema, Serialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct AddIdentifierResponse {
-    svc_name: String,
+    pub svc_name: String,
 }
 
 #[utoipa::path(
     method(post),
     tag = USER_TAG,
     path = "/api/service_account/identifier",
-    description = "Only admin can call",
+    description = "`exact` matches one repository, `prefix` (`owner/*`) every repository of an owner \
+        and `regex` claim values matching the whole pattern. Only admin can call",
     request_body = AddIdentifierBody,
     responses(
         (status = OK, description = "Success", body = AddIdentifierResponse, content_type = "application/json")
@@ -65,14 +95,279 @@ pub struct AddIdentifierResponse {
 pub async fn add_identifier(
     State(state): State<AppState>,
     PermissionExtractor { user, .. }: PermissionExtractor,
-    Json(body): Json<AddIdentifierBody>,
+    WithRejection(Json(body), _): WithRejection<Json<AddIdentifierBody>, crate::Error>,
 ) -> crate::Result<Json<AddIdentifierResponse>> {
     super::verify_admin(&user)?;
+    state.verify_writable()?;
+    let strategy = body
+        .match_strategy
+        .unwrap_or_else(|| MatchStrategy::infer(&body.repo));
+    UserIdentifier::validate(&body.repo, strategy)?;
 
     let user = User::find_by_name(&body.svc_name, state.db()).await?;
-    user.add_user_identifier(&body.repo, state.db()).await?;
+    user.add_user_identifier(&body.repo, strategy, state.max_identifiers(), state.db())
+        .await?;
 
     Ok(Json(AddIdentifierResponse {
         svc_name: user.name,
     }))
 }
+
+// long enough that the key is not guessable, argon2 only slows down a leaked hash
+const API_KEY_LENGTH: usize = 40;
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SetApiKeyBody {
+    pub svc_name: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SetApiKeyResponse {
+    pub svc_name: String,
+    // only returned once, augesty keeps the hash
+    pub api_key: String,
+}
+
+#[utoipa::path(
+    method(put),
+    tag = USER_TAG,
+    path = "/api/service_account/api_key",
+    description = "Generates the API key CI without OIDC exchanges at /api/identify/api_key, replacing the previous one. \
+        Only admin can call",
+    request_body = SetApiKeyBody,
+    responses(
+        (status = OK, description = "Success", body = SetApiKeyResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn set_api_key(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<SetApiKeyBody>, crate::Error>,
+) -> crate::Result<Json<SetApiKeyResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+
+    let svc = User::find_by_name(&body.svc_name, state.db()).await?;
+    if svc.user_type != UserType::ServiceAccount {
+        return Err(crate::Error::BadRequest(
+            "API keys only apply to service accounts",
+        ));
+    }
+
+    let api_key = User::generate_password(API_KEY_LENGTH);
+    let key_hash = state.hash_secret(&api_key).await?;
+    SvcApiKey {
+        user_id: svc.id.ok_or(crate::Error::Opaque("User ID must exist"))?,
+        key_hash,
+    }
+    .set(state.db())
+    .await?;
+
+    Ok(Json(SetApiKeyResponse {
+        svc_name: svc.name,
+        api_key,
+    }))
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct ClaimConditionBody {
+    pub svc_name: String,
+    // pull or push
+    pub action: String,
+    // name of the OIDC claim, e.g. `ref`
+    pub claim: String,
+    // `*` matches any run of characters, e.g. `refs/tags/*`
+    pub pattern: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct ClaimConditionResponse {
+    pub svc_name: String,
+}
+
+async fn claim_condition(
+    body: ClaimConditionBody,
+    pool: &sqlx::SqlitePool,
+) -> crate::Result<(User, ClaimCondition)> {
+    let permission: PermissionType = body.action.parse()?;
+    ClaimCondition::validate(&permission, &body.claim)?;
+    let svc = User::find_by_name(&body.svc_name, pool).await?;
+    if svc.user_type != UserType::ServiceAccount {
+        return Err(crate::Error::BadRequest(
+            "Conditions only apply to service accounts",
+        ));
+    }
+
+    let condition = ClaimCondition {
+        id: None,
+        user_id: svc.id.ok_or(crate::Error::Opaque("User ID must exist"))?,
+        permission,
+        claim: body.claim,
+        pattern: body.pattern,
+    };
+    Ok((svc, condition))
+}
+
+#[utoipa::path(
+    method(post),
+    tag = USER_TAG,
+    path = "/api/service_account/condition",
+    description = "Makes an action of a service account depend on a claim of the OIDC token. \
+        Tokens from /api/identify lose the action when any of its conditions is not met. Only admin can call",
+    request_body = ClaimConditionBody,
+    responses(
+        (status = OK, description = "Success", body = ClaimConditionResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn add_condition(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<ClaimConditionBody>, crate::Error>,
+) -> crate::Result<Json<ClaimConditionResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+
+    let (svc, condition) = claim_condition(body, state.db()).await?;
+    condition.insert(state.db()).await?;
+
+    Ok(Json(ClaimConditionResponse { svc_name: svc.name }))
+}
+
+#[utoipa::path(
+    method(delete),
+    tag = USER_TAG,
+    path = "/api/service_account/condition",
+    description = "Only admin can call",
+    request_body = ClaimConditionBody,
+    responses(
+        (status = OK, description = "Success", body = ClaimConditionResponse, content_type = "application/json"),
+        (status = NOT_FOUND, description = "The condition does not exist")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn remove_condition(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<ClaimConditionBody>, crate::Error>,
+) -> crate::Result<Json<ClaimConditionResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+
+    let (svc, condition) = claim_condition(body, state.db()).await?;
+    condition.delete(state.db()).await?;
+
+    Ok(Json(ClaimConditionResponse { svc_name: svc.name }))
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SyncGithubOrgBody {
+    pub svc_name: String,
+    pub org: String,
+    // needs read access to the org's repositories, it is not stored
+    pub token: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct SyncGithubOrgResponse {
+    pub svc_name: String,
+    pub identifiers: Vec<String>,
+}
+
+#[utoipa::path(
+    method(put),
+    tag = USER_TAG,
+    path = "/api/service_account/identifier/github_org",
+    description = "Replaces the identifiers of a service account with every repository of a GitHub org. \
+        Requires GITHUB_ORG_SYNC. Only admin can call",
+    request_body = SyncGithubOrgBody,
+    responses(
+        (status = OK, description = "Success", body = SyncGithubOrgResponse, content_type = "application/json"),
+        (status = NOT_IMPLEMENTED, description = "GITHUB_ORG_SYNC is disabled"),
+        (status = SERVICE_UNAVAILABLE, description = "GitHub rate limit exceeded")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn sync_github_org(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<SyncGithubOrgBody>, crate::Error>,
+) -> crate::Result<Json<SyncGithubOrgResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+    let github = state.github()?;
+
+    let svc = User::find_by_name(&body.svc_name, state.db()).await?;
+    if svc.user_type != UserType::ServiceAccount {
+        return Err(crate::Error::BadRequest("Cannot add identifier to user"));
+    }
+    let identifiers = github.org_repos(&body.org, &body.token).await?;
+    for identifier in &identifiers {
+        UserIdentifier::validate(identifier, MatchStrategy::Exact)?;
+    }
+    svc.replace_identifiers(&identifiers, state.max_identifiers(), state.db())
+        .await?;
+
+    Ok(Json(SyncGithubOrgResponse {
+        svc_name: svc.name,
+        identifiers,
+    }))
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct ListServiceAccountsEntry {
+    pub name: String,
+    pub identifier_count: usize,
+}
+
+fn service_accounts(users: Vec<User>) -> Vec<User> {
+    users
+        .into_iter()
+        .filter(|user| user.user_type == UserType::ServiceAccount)
+        .collect()
+}
+
+#[utoipa::path(
+    method(get),
+    tag = USER_TAG,
+    path = "/api/service_account",
+    description = "Only admin or auditors can call",
+    responses(
+        (status = OK, description = "Success", body = Vec<ListServiceAccountsEntry>, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn list_service_accounts(
+    State(state): State<AppState>,
+    PermissionExtractor { user, permissions }: PermissionExtractor,
+) -> crate::Result<Json<Vec<ListServiceAccountsEntry>>> {
+    super::verify_auditor(&user, &permissions)?;
+
+    let mut entries = Vec::new();
+    for svc in service_accounts(User::list(state.db()).await?) {
+        let identifier_count = svc.get_identifiers(state.db()).await?.len();
+        entries.push(ListServiceAccountsEntry {
+            name: svc.name,
+            identifier_count,
+        });
+    }
+
+    Ok(Json(entries))
+}
+
+#[test]
+fn test_only_service_accounts_are_listed() {
+    let users = vec![
+        User::new_user("admin".to_string()),
+        User::new_service_account("ci".to_string()),
+        User::new_user("dev".to_string()),
+        User::new_service_account("release".to_string()),
+    ];
+
+    let names: Vec<String> = service_accounts(users)
+        .into_iter()
+        .map(|user| user.name)
+        .collect();
+    assert_eq!(names, vec!["ci", "release"]);
+}
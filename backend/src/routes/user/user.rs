@@ -1,18 +1,31 @@
-use axum::{Json, extract::State};
+use axum::{
+    Json,
+    extract::{Path, State},
+};
+use axum_extra::extract::WithRejection;
 use serde::{Deserialize, Serialize};
 use utoipa::ToSchema;
 
-use crate::{USER_TAG, extractors::PermissionExtractor, models::user::User, state::AppState};
+use crate::{
+    USER_TAG,
+    extractors::PermissionExtractor,
+    models::{
+        permission::PermissionType,
+        user::{User, UserType},
+        user_identifier::MatchStrategy,
+    },
+    state::AppState,
+};
 
-#[derive(Debug, Clone, ToSchema, Deserialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct CreateUserBody {
-    name: String,
-    password: String,
+    pub name: String,
+    pub password: String,
 }
 
-#[derive(Debug, Clone, ToSchema, Serialize)]
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
 pub struct CreateUserResponse {
-    user_name: String,
+    pub user_name: String,
 }
 
 #[utoipa::path(
@@ -29,21 +42,373 @@ pub struct CreateUserResponse {
 pub async fn create_user(
     State(state): State<AppState>,
     PermissionExtractor { user, .. }: PermissionExtractor,
-    Json(body): Json<CreateUserBody>,
+    WithRejection(Json(body), _): WithRejection<Json<CreateUserBody>, crate::Error>,
 ) -> crate::Result<Json<CreateUserResponse>> {
-    use argon2::PasswordHasher;
     super::verify_admin(&user)?;
+    state.verify_writable()?;
+    User::validate_name(&body.name)?;
+    state.verify_password_length(&body.password)?;
 
-    let salt =
-        argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
-    let argon = argon2::Argon2::default();
-    let pw_hash = argon.hash_password(body.password.as_bytes(), &salt)?;
+    let pw_hash = state.hash_secret(&body.password).await?;
 
-    let user = User::new_user(body.name);
-    user.insert(state.db()).await?;
-    user.add_hash(&pw_hash.to_string(), state.db()).await?;
+    User::new_user(body.name.clone()).insert(state.db()).await?;
+    // reload to get the id assigned by the database
+    let user = User::find_by_name(&body.name, state.db()).await?;
+    user.add_hash(&pw_hash, state.db()).await?;
 
     Ok(Json(CreateUserResponse {
         user_name: user.name,
     }))
 }
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct DeleteUserBody {
+    name: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct DeleteUserResponse {
+    user_name: String,
+}
+
+#[utoipa::path(
+    method(delete),
+    tag = USER_TAG,
+    path = "/api/user",
+    description = "Only admin can call",
+    request_body = DeleteUserBody,
+    responses(
+        (status = OK, description = "Success", body = DeleteUserResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn delete_user(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<DeleteUserBody>, crate::Error>,
+) -> crate::Result<Json<DeleteUserResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+    super::verify_not_admin(&body.name)?;
+
+    let user = User::find_by_name(&body.name, state.db()).await?;
+    let user_id = user.id.ok_or(crate::Error::Opaque("User ID must exist"))?;
+    User::delete_by_id(user_id, state.db()).await?;
+
+    Ok(Json(DeleteUserResponse {
+        user_name: user.name,
+    }))
+}
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct RenameUserBody {
+    name: String,
+    new_name: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct RenameUserResponse {
+    user_name: String,
+}
+
+#[utoipa::path(
+    method(patch),
+    tag = USER_TAG,
+    path = "/api/user",
+    description = "Renames a user or service account keeping its permissions and identifiers. Only admin can call",
+    request_body = RenameUserBody,
+    responses(
+        (status = OK, description = "Success", body = RenameUserResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn rename_user(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<RenameUserBody>, crate::Error>,
+) -> crate::Result<Json<RenameUserResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+    super::verify_not_admin(&body.name)?;
+    super::verify_not_admin(&body.new_name)?;
+    User::validate_name(&body.new_name)?;
+
+    let user = User::find_by_name(&body.name, state.db()).await?;
+    user.rename(&body.new_name, state.db()).await?;
+
+    Ok(Json(RenameUserResponse {
+        user_name: body.new_name,
+    }))
+}
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct ExpireTokensBody {
+    name: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct ExpireTokensResponse {
+    user_name: String,
+    token_epoch: i64,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = USER_TAG,
+    path = "/api/user/expire_tokens",
+    description = "Invalidates every token issued to the user so far. Registry tokens are stateless, \
+        so registries only notice when they validate them with augesty, otherwise they stay valid \
+        until they expire after TOKEN_DURATION. Only admin can call",
+    request_body = ExpireTokensBody,
+    responses(
+        (status = OK, description = "Success", body = ExpireTokensResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn expire_tokens(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<ExpireTokensBody>, crate::Error>,
+) -> crate::Result<Json<ExpireTokensResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+
+    // for admin this revokes the admin tokens, its password stays valid
+    let user = User::find_by_name(&body.name, state.db()).await?;
+    let token_epoch = user.bump_token_epoch(state.db()).await?;
+
+    Ok(Json(ExpireTokensResponse {
+        user_name: user.name,
+        token_epoch,
+    }))
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct VerifyBody {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize, Deserialize)]
+pub struct VerifyResponse {
+    pub valid: bool,
+}
+
+#[utoipa::path(
+    method(post),
+    tag = USER_TAG,
+    path = "/api/verify",
+    description = "Checks the password of a user without issuing a token. Unknown users and service accounts \
+        are invalid and take as long to check as a wrong password. Only admin can call",
+    request_body = VerifyBody,
+    responses(
+        (status = OK, description = "Success", body = VerifyResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn verify(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<VerifyBody>, crate::Error>,
+) -> crate::Result<Json<VerifyResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_password_length(&body.password)?;
+
+    let valid = state.verify_credentials(&body.name, &body.password).await?;
+
+    Ok(Json(VerifyResponse { valid }))
+}
+
+#[derive(Debug, Clone, ToSchema, Deserialize)]
+pub struct SetExternalIdBody {
+    name: String,
+    // null removes the external id
+    external_id: Option<String>,
+}
+
+#[derive(Debug, Clone, ToSchema, Serialize)]
+pub struct SetExternalIdResponse {
+    user_name: String,
+}
+
+#[utoipa::path(
+    method(put),
+    tag = USER_TAG,
+    path = "/api/user/external_id",
+    description = "Sets the stable id that registry tokens carry in `sub` with TOKEN_SUBJECT=external_id. Only admin can call",
+    request_body = SetExternalIdBody,
+    responses(
+        (status = OK, description = "Success", body = SetExternalIdResponse, content_type = "application/json")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn set_external_id(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    WithRejection(Json(body), _): WithRejection<Json<SetExternalIdBody>, crate::Error>,
+) -> crate::Result<Json<SetExternalIdResponse>> {
+    super::verify_admin(&user)?;
+    state.verify_writable()?;
+    if body.external_id.as_deref().is_some_and(str::is_empty) {
+        return Err(crate::Error::BadRequest("External id must be non-empty"));
+    }
+
+    let user = User::find_by_name(&body.name, state.db()).await?;
+    user.set_external_id(body.external_id.as_deref(), state.db())
+        .await?;
+
+    Ok(Json(SetExternalIdResponse {
+        user_name: user.name,
+    }))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, ToSchema, Serialize)]
+pub struct UserDetailPermission {
+    kind: String,
+    subject: String,
+    permission: PermissionType,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, ToSchema, Serialize)]
+pub struct UserDetailIdentifier {
+    identifier: String,
+    match_strategy: MatchStrategy,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, ToSchema, Serialize)]
+pub struct UserDetailResponse {
+    name: String,
+    user_type: UserType,
+    permissions: Vec<UserDetailPermission>,
+    // only service accounts have identifiers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    identifiers: Option<Vec<UserDetailIdentifier>>,
+}
+
+#[utoipa::path(
+    method(get),
+    tag = USER_TAG,
+    path = "/api/user/{name}",
+    description = "The full profile of a user or service account. Only admin can call",
+    params(("name" = String, Path, description = "Name of the user")),
+    responses(
+        (status = OK, description = "Success", body = UserDetailResponse, content_type = "application/json"),
+        (status = NOT_FOUND, description = "Unknown user")
+    ),
+    security(("docker_basic" = []))
+)]
+pub async fn get_user(
+    State(state): State<AppState>,
+    PermissionExtractor { user, .. }: PermissionExtractor,
+    Path(name): Path<String>,
+) -> crate::Result<Json<UserDetailResponse>> {
+    super::verify_admin(&user)?;
+
+    Ok(Json(user_detail(&name, state.db()).await?))
+}
+
+async fn user_detail(name: &str, pool: &sqlx::SqlitePool) -> crate::Result<UserDetailResponse> {
+    let user = match User::find_by_name(name, pool).await {
+        Ok(user) => user,
+        Err(crate::Error::Db(sqlx::Error::RowNotFound)) => {
+            return Err(crate::Error::NotFound("user not found"));
+        }
+        Err(e) => return Err(e),
+    };
+
+    let permissions = user
+        .list_permissions(pool)
+        .await?
+        .into_iter()
+        .map(|perm| UserDetailPermission {
+            kind: perm.kind,
+            subject: perm.subject,
+            permission: perm.permission,
+        })
+        .collect();
+    let identifiers = match user.user_type {
+        UserType::ServiceAccount => Some(
+            user.get_identifiers(pool)
+                .await?
+                .into_iter()
+                .map(|ident| UserDetailIdentifier {
+                    identifier: ident.identifier,
+                    match_strategy: ident.match_strategy,
+                })
+                .collect(),
+        ),
+        UserType::User => None,
+    };
+
+    Ok(UserDetailResponse {
+        name: user.name,
+        user_type: user.user_type,
+        permissions,
+        identifiers,
+    })
+}
+
+#[tokio::test]
+async fn test_user_detail() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    User::new_service_account("ci".to_string())
+        .insert(&pool)
+        .await?;
+    let dev = User::find_by_name("dev", &pool).await?;
+    dev.add_permission(
+        "repository".to_string(),
+        "example/image".to_string(),
+        PermissionType::Pull,
+        &pool,
+    )
+    .await?;
+    let ci = User::find_by_name("ci", &pool).await?;
+    ci.add_user_identifier("example/repo", MatchStrategy::Exact, 10, &pool)
+        .await?;
+    ci.add_user_identifier("example/app-.+", MatchStrategy::Regex, 10, &pool)
+        .await?;
+    ci.add_permission(
+        "repository".to_string(),
+        "example/image".to_string(),
+        PermissionType::Push,
+        &pool,
+    )
+    .await?;
+
+    let detail = user_detail("dev", &pool).await?;
+    assert_eq!(detail.user_type, UserType::User);
+    assert_eq!(
+        detail.permissions,
+        vec![UserDetailPermission {
+            kind: "repository".to_string(),
+            subject: "example/image".to_string(),
+            permission: PermissionType::Pull,
+        }]
+    );
+    assert_eq!(detail.identifiers, None);
+
+    let detail = user_detail("ci", &pool).await?;
+    assert_eq!(detail.user_type, UserType::ServiceAccount);
+    assert_eq!(detail.permissions[0].permission, PermissionType::Push);
+    assert_eq!(
+        detail.identifiers,
+        Some(vec![
+            UserDetailIdentifier {
+                identifier: "example/app-.+".to_string(),
+                match_strategy: MatchStrategy::Regex,
+            },
+            UserDetailIdentifier {
+                identifier: "example/repo".to_string(),
+                match_strategy: MatchStrategy::Exact,
+            },
+        ])
+    );
+
+    assert!(matches!(
+        user_detail("missing", &pool).await,
+        Err(crate::Error::NotFound(_))
+    ));
+
+    Ok(())
+}
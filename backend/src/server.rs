@@ -0,0 +1,212 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use axum::Router;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::{
+        conn::auto::Builder,
+        graceful::{GracefulShutdown, Watcher},
+    },
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        self,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+    },
+};
+
+// A client that never finishes the handshake must not hold up the graceful shutdown
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Pause after a failed accept, e.g. while out of file descriptors
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+// Connection level settings, HTTP/1.1 is always served
+#[derive(Debug, Clone, Default)]
+pub struct ServerConfig {
+    // allows h2c next to HTTP/1.1 on the same port
+    pub http2: bool,
+    // interval of HTTP/2 keep-alive pings, none disables them
+    pub keep_alive: Option<Duration>,
+    pub max_concurrent_streams: Option<u32>,
+    // serves HTTPS instead of plain HTTP
+    pub tls: Option<TlsFiles>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    // PEM, the leaf first followed by its intermediates
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+impl ServerConfig {
+    fn builder(&self) -> Builder<TokioExecutor> {
+        let mut builder = Builder::new(TokioExecutor::new());
+        builder.http1().keep_alive(true);
+        if !self.http2 {
+            return builder.http1_only();
+        }
+
+        builder
+            .http2()
+            .timer(TokioTimer::new())
+            .keep_alive_interval(self.keep_alive)
+            .max_concurrent_streams(self.max_concurrent_streams);
+        builder
+    }
+
+    // HTTP/2 over TLS is negotiated with ALPN instead of h2c
+    fn tls_acceptor(&self) -> crate::Result<Option<TlsAcceptor>> {
+        let Some(files) = &self.tls else {
+            return Ok(None);
+        };
+        let pem_error = |path: &PathBuf| {
+            let path = path.display().to_string();
+            move |e: rustls::pki_types::pem::Error| {
+                crate::Error::Any(format!("Error reading TLS PEM file {path}: {e}"))
+            }
+        };
+        let certs = CertificateDer::pem_file_iter(&files.cert)
+            .map_err(pem_error(&files.cert))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(pem_error(&files.cert))?;
+        let key = PrivateKeyDer::from_pem_file(&files.key).map_err(pem_error(&files.key))?;
+
+        let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .and_then(|config| config.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| crate::Error::Any(format!("Invalid TLS certificate or key: {e}")))?;
+        config.alpn_protocols = if self.http2 {
+            vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+        } else {
+            vec![b"http/1.1".to_vec()]
+        };
+
+        Ok(Some(TlsAcceptor::from(Arc::new(config))))
+    }
+}
+
+// Serves the app until the signal resolves, then waits for open connections to finish
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    config: &ServerConfig,
+    signal: impl Future<Output = ()>,
+) -> crate::Result<()> {
+    let builder = config.builder();
+    let acceptor = config.tls_acceptor()?;
+    let graceful = GracefulShutdown::new();
+    tokio::pin!(signal);
+
+    loop {
+        let stream = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("{:<12}- Failed to accept connection: {}", "API", e);
+                    // errors like EMFILE persist until connections close, retrying at once
+                    // would spin the loop and flood the log
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                    continue;
+                }
+            },
+            _ = &mut signal => break,
+        };
+
+        let builder = builder.clone();
+        let app = app.clone();
+        let acceptor = acceptor.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let result = match acceptor {
+                Some(acceptor) => {
+                    match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await
+                    {
+                        Ok(Ok(stream)) => serve_connection(&builder, stream, app, watcher).await,
+                        Ok(Err(e)) => {
+                            tracing::debug!("{:<12}- TLS handshake failed: {}", "API", e);
+                            return;
+                        }
+                        Err(_) => {
+                            tracing::debug!("{:<12}- TLS handshake timed out", "API");
+                            return;
+                        }
+                    }
+                }
+                None => serve_connection(&builder, stream, app, watcher).await,
+            };
+            if let Err(e) = result {
+                tracing::debug!("{:<12}- Connection closed with error: {}", "API", e);
+            }
+        });
+    }
+
+    drop(listener);
+    graceful.shutdown().await;
+
+    Ok(())
+}
+
+async fn serve_connection<I>(
+    builder: &Builder<TokioExecutor>,
+    io: I,
+    app: Router,
+    watcher: Watcher,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = TowerToHyperService::new(app);
+    let conn = builder
+        .serve_connection_with_upgrades(TokioIo::new(io), service)
+        .into_owned();
+    watcher.watch(conn).await
+}
+
+#[tokio::test]
+async fn test_serves_http1_and_http2() -> crate::Result<()> {
+    use axum::{body::Body, http::Request, routing::get};
+    use hyper::client::conn::{http1, http2};
+
+    let app = Router::new().route("/", get(|| async { "ok" }));
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let config = ServerConfig {
+        http2: true,
+        keep_alive: Some(Duration::from_secs(10)),
+        max_concurrent_streams: Some(16),
+        tls: None,
+    };
+    tokio::spawn(async move { serve(listener, app, &config, std::future::pending()).await });
+
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let (mut sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let request = Request::builder()
+        .uri(format!("http://{addr}/"))
+        .body(Body::empty())
+        .unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!(response.version(), axum::http::Version::HTTP_2);
+    assert!(response.status().is_success());
+
+    let stream = tokio::net::TcpStream::connect(addr).await?;
+    let (mut sender, conn) = http1::handshake(TokioIo::new(stream)).await.unwrap();
+    tokio::spawn(conn);
+    let request = Request::builder().uri("/").body(Body::empty()).unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!(response.version(), axum::http::Version::HTTP_11);
+    assert!(response.status().is_success());
+
+    Ok(())
+}
@@ -1,18 +1,46 @@
 use argon2::PasswordVerifier;
+use axum::http::{HeaderName, HeaderValue};
 use data_encoding::BASE32_NOPAD;
-use jwt_simple::prelude::{ECDSAP384KeyPairLike, ECDSAP384PublicKeyLike, ES384KeyPair};
+use jwt_simple::prelude::{
+    ECDSAP384KeyPairLike, ECDSAP384PublicKeyLike, ES384KeyPair, ES384PublicKey, JWTClaims,
+    VerificationOptions,
+};
 use openssl::{
     asn1::Asn1Time,
     ec::EcKey,
     hash::MessageDigest,
+    nid::Nid,
     pkey::PKey,
-    x509::{X509Builder, X509NameBuilder},
+    x509::{
+        X509, X509Builder, X509NameBuilder,
+        extension::{BasicConstraints, KeyUsage},
+    },
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    ops::Deref,
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::{Duration, Instant},
 };
-use std::{ops::Deref, sync::Arc};
+use tokio::sync::Semaphore;
 
 use crate::{
-    models::{permission::Permission, user::User, user_pw_hash::UserPasswordHash},
-    routes::token::Scope,
+    config::{Config, TokenSubject, UnauthorizedScopes},
+    extractors::IdentifierClaim,
+    github::GithubApi,
+    metrics::AuthMetrics,
+    models::{
+        permission::{Permission, PermissionType},
+        svc_api_key::SvcApiKey,
+        user::{User, UserType},
+        user_pw_hash::UserPasswordHash,
+    },
+    routes::token::{Scope, comparable},
+    webhook::{PermissionEvent, Webhook},
 };
 
 #[derive(Clone)]
@@ -21,8 +49,8 @@ pub struct AppState {
 }
 
 impl AppState {
-    pub async fn new() -> crate::Result<Self> {
-        let inner = InnerState::new().await?;
+    pub async fn new(config: Config) -> crate::Result<Self> {
+        let inner = InnerState::new(config).await?;
         Ok(Self {
             inner: Arc::new(inner),
         })
@@ -40,32 +68,143 @@ impl Deref for AppState {
 pub struct InnerState {
     db: sqlx::SqlitePool,
     token_duration: u64,
-    jwt_key: ES384KeyPair,
+    // in minutes, the longest lifetime a client can request
+    max_token_duration: u64,
+    request_timeout: Duration,
+    argon_limit: Semaphore,
+    argon2: argon2::Argon2<'static>,
+    // Argon2 does not expose these, they decide whether a stored hash is upgraded
+    argon_algorithm: argon2::Algorithm,
+    argon_version: argon2::Version,
+    // verified against for unknown users and service accounts, so a wrong username costs as
+    // much time as a wrong password
+    dummy_hash: String,
+    jwt_key: JwtKey,
     own_url: String,
-    docker_url: String,
+    docker_urls: Vec<String>,
+    oidc_claim: IdentifierClaim,
+    oidc_debug: bool,
+    metrics: bool,
+    auth_metrics: AuthMetrics,
+    warm_up: bool,
+    pull_implies_catalog: bool,
+    push_implies_pull: bool,
+    normalize_subjects: bool,
+    subject_inheritance: bool,
+    swagger_path: Option<String>,
+    base_path: String,
+    svc_issuer: String,
+    svc_audience: String,
+    token_audience: Option<String>,
+    token_subject: TokenSubject,
+    unauthorized_scopes: UnauthorizedScopes,
+    iat_backdate: u64,
+    max_password_length: usize,
+    max_identifiers: usize,
+    cert: Vec<u8>,
+    write_cert_file: bool,
+    admin_password_file: String,
+    read_only: AtomicBool,
+    webhook: Option<Webhook>,
+    github: Option<GithubApi>,
+    jwks: RefreshCache<github_oidc::GithubJWKS>,
+    allowed_actions: Vec<PermissionType>,
+    resource_types: Vec<String>,
+    default_permissions: Vec<Permission>,
+    security_headers: Arc<[(HeaderName, HeaderValue)]>,
+    admin_challenge: HeaderValue,
+    custom_claims: Vec<(String, String)>,
+    catalog_max: Option<u64>,
+    oidc_replay: ReplayCache,
 }
 
 impl InnerState {
-    pub async fn new() -> crate::Result<Self> {
-        let db_url = std::env::var("DATABASE_PATH")?;
-        let mut db_options = sqlx::sqlite::SqliteConnectOptions::new();
-        db_options = db_options.create_if_missing(true);
-        db_options = db_options.filename(&db_url);
-        let db = sqlx::SqlitePool::connect_with(db_options).await?;
-        let mut jwt_key = ES384KeyPair::generate();
-        jwt_key = add_kid(jwt_key)?;
-        let own_url = std::env::var("OWN_URL")?;
-        let docker_url = std::env::var("DOCKER_URL")?;
-        let token_duration = std::env::var("TOKEN_DURATION")?.parse::<u64>().map_err(|_| crate::Error::Opaque("Error parsing TOKEN_DURATION"))?;
-        let cert = create_cert_from_pair(&jwt_key, &own_url)?;
-        tokio::fs::write("/config/jwt.pub", cert).await?;
+    pub async fn new(config: Config) -> crate::Result<Self> {
+        let db = connect_db(&config.database_path, config.database_key.as_deref()).await?;
+        let webhook = match config.webhook_url {
+            Some(url) => Some(Webhook::new(url)?),
+            None => None,
+        };
+        let github = match config.github_org_sync {
+            true => Some(GithubApi::new(config.github_api_url)?),
+            false => None,
+        };
+        let (jwt_key, cert) = match &config.verify_only_cert {
+            Some(path) => {
+                let cert = tokio::fs::read(path).await?;
+                (JwtKey::verify_only(&cert)?, cert)
+            }
+            None => {
+                let pair = match &config.jwt_private_key_pem {
+                    Some(pem) => pair_from_pem(pem)?,
+                    None => add_kid(ES384KeyPair::generate())?,
+                };
+                let cert = create_cert_from_pair(&pair, &config.own_url)?;
+                self_check(&pair, &cert)?;
+                (JwtKey::Signing(pair), cert)
+            }
+        };
+        // the cert has to actually be issued by the chain, registries reject it otherwise
+        let cert = match config.cert_chain {
+            Some(path) => append_chain(&cert, &tokio::fs::read(path).await?)?,
+            None => cert,
+        };
+        store_cert_file(Path::new(CERT_FILE), &cert, config.write_cert_file).await?;
+
+        let argon2 = argon2::Argon2::new(
+            config.argon_algorithm,
+            config.argon_version,
+            config.argon_params,
+        );
+        let dummy_hash = dummy_hash(&argon2)?;
 
         Ok(InnerState {
             db,
-            token_duration,
+            token_duration: config.token_duration,
+            max_token_duration: config.max_token_duration,
+            request_timeout: config.request_timeout,
+            argon_limit: Semaphore::new(config.argon_concurrency),
+            argon2,
+            argon_algorithm: config.argon_algorithm,
+            argon_version: config.argon_version,
+            dummy_hash,
             jwt_key,
-            own_url,
-            docker_url,
+            own_url: config.own_url,
+            docker_urls: config.docker_urls,
+            oidc_claim: config.oidc_claim,
+            oidc_debug: config.oidc_debug,
+            metrics: config.metrics,
+            auth_metrics: AuthMetrics::default(),
+            warm_up: config.warm_up,
+            pull_implies_catalog: config.pull_implies_catalog,
+            push_implies_pull: config.push_implies_pull,
+            normalize_subjects: config.normalize_subjects,
+            subject_inheritance: config.subject_inheritance,
+            swagger_path: config.swagger_path,
+            base_path: config.base_path,
+            svc_issuer: config.svc_issuer,
+            svc_audience: config.svc_audience,
+            token_audience: config.token_audience,
+            token_subject: config.token_subject,
+            unauthorized_scopes: config.unauthorized_scopes,
+            iat_backdate: config.iat_backdate,
+            max_password_length: config.max_password_length,
+            max_identifiers: config.max_identifiers,
+            cert,
+            write_cert_file: config.write_cert_file,
+            admin_password_file: config.admin_password_file,
+            read_only: AtomicBool::new(config.read_only),
+            webhook,
+            github,
+            jwks: RefreshCache::new(config.jwks_ttl),
+            allowed_actions: config.allowed_actions,
+            resource_types: config.resource_types,
+            default_permissions: config.default_permissions,
+            security_headers: config.security_headers.into(),
+            admin_challenge: admin_challenge(&config.basic_realm)?,
+            custom_claims: config.custom_claims,
+            catalog_max: config.catalog_max,
+            oidc_replay: ReplayCache::new(OIDC_REPLAY_WINDOW, OIDC_REPLAY_CAPACITY),
         })
     }
 
@@ -73,60 +212,312 @@ impl InnerState {
         &self.db
     }
 
-    pub fn docker_url(&self) -> &String {
-        &self.docker_url
+    pub fn docker_urls(&self) -> &[String] {
+        &self.docker_urls
     }
-    
+
     pub fn own_url(&self) -> &String {
         &self.own_url
     }
 
-    // Returns a JWT key as a String for a svc account
-    pub fn create_jwt(&self, name: String) -> crate::Result<String> {
-        let claims = SvcClaims { svc_name: name };
-        let claims = jwt_simple::claims::Claims::with_custom_claims(
-            claims,
-            jwt_simple::prelude::Duration::from_mins(5),
+    pub fn oidc_claim(&self) -> IdentifierClaim {
+        self.oidc_claim
+    }
+
+    pub fn oidc_debug(&self) -> bool {
+        self.oidc_debug
+    }
+
+    pub fn metrics_enabled(&self) -> bool {
+        self.metrics
+    }
+
+    pub fn warm_up_enabled(&self) -> bool {
+        self.warm_up
+    }
+
+    pub fn admin_password_file(&self) -> &Path {
+        Path::new(&self.admin_password_file)
+    }
+
+    pub fn auth_metrics(&self) -> &AuthMetrics {
+        &self.auth_metrics
+    }
+
+    pub fn pull_implies_catalog(&self) -> bool {
+        self.pull_implies_catalog
+    }
+
+    pub fn push_implies_pull(&self) -> bool {
+        self.push_implies_pull
+    }
+
+    pub fn normalize_subjects(&self) -> bool {
+        self.normalize_subjects
+    }
+
+    pub fn subject_inheritance(&self) -> bool {
+        self.subject_inheritance
+    }
+
+    pub fn unauthorized_scopes(&self) -> UnauthorizedScopes {
+        self.unauthorized_scopes
+    }
+
+    pub fn swagger_path(&self) -> Option<&str> {
+        self.swagger_path.as_deref()
+    }
+
+    // Prefix of every route, empty or starting with / without a trailing /
+    pub fn base_path(&self) -> &str {
+        &self.base_path
+    }
+
+    // PEM of the self signed cert for the current signing key
+    pub fn cert(&self) -> &[u8] {
+        &self.cert
+    }
+
+    // Removes the cert file on shutdown, unless it was never written
+    pub async fn remove_cert_file(&self) -> crate::Result<()> {
+        if self.write_cert_file {
+            tokio::fs::remove_file(CERT_FILE).await?;
+        }
+        Ok(())
+    }
+
+    // Fires the permission webhook if one is configured, never waits for delivery
+    pub fn notify_permission_change(&self, event: PermissionEvent) {
+        if let Some(webhook) = &self.webhook {
+            webhook.send(event);
+        }
+    }
+
+    pub fn github(&self) -> crate::Result<&GithubApi> {
+        self.github
+            .as_ref()
+            .ok_or(crate::Error::NotImplemented("GITHUB_ORG_SYNC is disabled"))
+    }
+
+    // The GitHub signing keys, fetched at most once per cache period
+    pub async fn github_jwks(&self) -> crate::Result<Arc<github_oidc::GithubJWKS>> {
+        self.jwks.get_or_refresh(fetch_github_jwks).await
+    }
+
+    // Whether the JWKS is cached and fresh, so the next OIDC login does not fetch it
+    pub async fn jwks_cached(&self) -> bool {
+        self.jwks.is_fresh().await
+    }
+
+    // Pays the one time costs of the first login before it arrives: the blocking pool and
+    // memory argon2 runs on and the first JWKS fetch. Failures only cost that first login
+    pub async fn warm_up(&self) {
+        self.warm_up_with(fetch_github_jwks).await;
+    }
+
+    pub async fn warm_up_with<F, Fut>(&self, fetch_jwks: F)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = crate::Result<github_oidc::GithubJWKS>>,
+    {
+        let started = Instant::now();
+        if let Err(e) = self.verify_hash(self.dummy_hash.clone(), "warm-up").await {
+            tracing::warn!("{:<12}- Warming up argon2 failed: {e}", "Startup");
+        }
+        if let Err(e) = self.jwks.get_or_refresh(fetch_jwks).await {
+            tracing::warn!("{:<12}- Prefetching the GitHub JWKS failed: {e}", "Startup");
+        }
+        tracing::info!(
+            "{:<12}- Warmed up in {}ms",
+            "Startup",
+            started.elapsed().as_millis()
         );
-        self.jwt_key
-            .sign(claims)
-            .map_err(|_| crate::Error::Opaque("Failed to create JWT token"))
     }
 
+    // Argon2 cost grows with the input, so oversized passwords never reach the hasher
+    pub fn verify_password_length(&self, password: &str) -> crate::Result<()> {
+        verify_password_length(password, self.max_password_length)
+    }
+
+    pub fn max_identifiers(&self) -> usize {
+        self.max_identifiers
+    }
+
+    // Hashes new passwords with the configured variant and version
+    pub fn argon2(&self) -> &argon2::Argon2<'static> {
+        &self.argon2
+    }
+
+    pub fn read_only(&self) -> bool {
+        self.read_only.load(Ordering::Relaxed)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        if self.read_only.swap(read_only, Ordering::Relaxed) != read_only {
+            tracing::warn!("{:<12}- Read only mode set to {read_only}", "Maintenance");
+        }
+    }
+
+    // Mutating admin calls check this first, token issuance never does
+    pub fn verify_writable(&self) -> crate::Result<()> {
+        if self.read_only() {
+            return Err(crate::Error::Unavailable("augesty is in read only mode"));
+        }
+        Ok(())
+    }
+
+    pub fn security_headers(&self) -> Arc<[(HeaderName, HeaderValue)]> {
+        self.security_headers.clone()
+    }
+
+    pub fn admin_challenge(&self) -> HeaderValue {
+        self.admin_challenge.clone()
+    }
+
+    pub fn allowed_actions(&self) -> &[PermissionType] {
+        &self.allowed_actions
+    }
+
+    pub fn resource_types(&self) -> &[String] {
+        &self.resource_types
+    }
+
+    pub fn default_permissions(&self) -> &[Permission] {
+        &self.default_permissions
+    }
+
+    // Marks the jti of an OIDC token as used, rejecting tokens that were already exchanged
+    pub fn consume_oidc_jti(&self, jti: &str) -> crate::Result<()> {
+        if !self.oidc_replay.insert(jti, Instant::now())? {
+            return Err(crate::Error::Unauthorized("OIDC token was already used"));
+        }
+        Ok(())
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    // in minutes
+    pub fn token_duration(&self) -> u64 {
+        self.token_duration
+    }
+
+    // Lifetime in seconds of a registry token, a client may ask for one up to MAX_TOKEN_DURATION
+    pub fn token_ttl(&self, requested: Option<u64>) -> u64 {
+        granted_ttl(
+            requested,
+            60 * self.token_duration,
+            60 * self.max_token_duration,
+        )
+    }
+
+    pub fn svc_issuer(&self) -> &str {
+        &self.svc_issuer
+    }
+
+    pub fn verify_only(&self) -> bool {
+        matches!(self.jwt_key, JwtKey::VerifyOnly(_))
+    }
+
+    // Returns a JWT key as a String for a svc account, optionally narrowed to the given scopes
+    pub fn create_jwt(
+        &self,
+        name: String,
+        scope: Option<Vec<Scope>>,
+        epoch: i64,
+    ) -> crate::Result<String> {
+        let claims = SvcClaims {
+            svc_name: name,
+            scope,
+            epoch,
+        };
+        sign_svc_jwt(&self.jwt_key, claims, &self.svc_issuer, &self.svc_audience)
+    }
+
+    // `{user}` in custom claims is always the name, `sub` comes from token_subject
     pub fn create_docker_jwt(
         &self,
+        name: &str,
         sub: &str,
         aud: &str,
         scope: Vec<Scope>,
+        epoch: i64,
+        expires_in: u64,
     ) -> crate::Result<(String, u64)> {
-        let expires_in = 60 * self.token_duration;
+        let aud = token_audience(self.token_audience.as_deref(), aud, &self.docker_urls)?;
+        let claims = docker_claims(
+            DockerClaims {
+                catalog_max: catalog_max(&scope, self.catalog_max),
+                access: sorted_access(scope),
+                epoch,
+                custom: resolve_custom_claims(&self.custom_claims, name),
+            },
+            expires_in,
+            self.iat_backdate,
+        )
+        .with_audience(aud)
+        .with_subject(sub)
+        .with_issuer(&self.own_url);
 
-        let claims = DockerClaims { access: scope };
-        let mut claims = jwt_simple::claims::Claims::with_custom_claims(
-            claims,
-            jwt_simple::prelude::Duration::from_mins(self.token_duration),
-        );
-        claims = claims.with_audience(aud);
-        claims = claims.with_subject(sub);
-        claims = claims.with_issuer(&self.own_url);
-
-        let jwt = self
-            .jwt_key
-            .sign(claims)
-            .map_err(|_| crate::Error::Opaque("Failed to create JWT token"))?;
+        let jwt = self.jwt_key.sign(claims)?;
 
         Ok((jwt, expires_in))
     }
 
-    fn verify_jwt(&self, token: &str) -> crate::Result<SvcClaims> {
-        let custom_claims = self
-            .jwt_key
-            .public_key()
-            .verify_token::<SvcClaims>(token, None)
-            .map_err(|_| crate::Error::Unauthorized("Invalid JWT token"))?
-            .custom;
+    pub async fn token_subject(&self, user: &User) -> crate::Result<String> {
+        token_subject(self.token_subject, user, self.db()).await
+    }
+
+    // Checks signature, issuer, audience and the token epoch of a registry token. The audience
+    // is the one create_docker_jwt sets for the service, so a token for one registry is not
+    // accepted by another
+    pub async fn verify_docker_jwt(
+        &self,
+        token: &str,
+        service: &str,
+    ) -> crate::Result<JWTClaims<DockerClaims>> {
+        let aud = token_audience(self.token_audience.as_deref(), service, &self.docker_urls)
+            .map_err(|_| crate::Error::Unauthorized("Invalid Registry"))?;
+        let claims = verify_docker_jwt(&self.jwt_key.public_key(), token, &self.own_url, aud)?;
+        let sub = claims
+            .subject
+            .as_deref()
+            .ok_or(crate::Error::Unauthorized("Token has no subject"))?;
+        let user = user_by_subject(self.token_subject, sub, self.db())
+            .await
+            .map_err(|_| crate::Error::Unauthorized("Unknown token subject"))?;
+        verify_epoch(&user, claims.custom.epoch, self.db()).await?;
+
+        Ok(claims)
+    }
+
+    // A short lived bearer token for the admin api, returns it with its lifetime in seconds
+    pub fn create_admin_jwt(&self, epoch: i64) -> crate::Result<(String, u64)> {
+        let token = sign_admin_jwt(
+            &self.jwt_key,
+            AdminClaims { admin_epoch: epoch },
+            jwt_simple::prelude::Duration::from_mins(ADMIN_TOKEN_DURATION),
+            &self.own_url,
+        )?;
+        Ok((token, 60 * ADMIN_TOKEN_DURATION))
+    }
+
+    // Checks signature, expiry and the token epoch of admin, returns the admin user
+    pub async fn verify_admin_jwt(&self, token: &str) -> crate::Result<User> {
+        let claims = verify_admin_jwt(&self.jwt_key.public_key(), token, &self.own_url)?;
+        let admin = User::find_by_name("admin", self.db()).await?;
+        verify_epoch(&admin, claims.admin_epoch, self.db()).await?;
+        Ok(admin)
+    }
 
-        Ok(custom_claims)
+    fn verify_jwt(&self, token: &str) -> crate::Result<SvcClaims> {
+        verify_svc_jwt(
+            &self.jwt_key.public_key(),
+            token,
+            &self.svc_issuer,
+            &self.svc_audience,
+        )
     }
 
     async fn permissions_for_svc_account(
@@ -138,33 +529,633 @@ impl InnerState {
         if user.name != claims.svc_name {
             return Err(crate::Error::Unauthorized("Missmatched user and token"));
         }
+        verify_epoch(&user, claims.epoch, self.db()).await?;
         let permissions = user.list_permissions(self.db()).await?;
+
+        // A narrowed token only carries the scoped actions, still bounded by the current grants
+        let permissions = match claims.scope {
+            Some(scopes) => {
+                let (checked, granted) = comparable(
+                    &scopes,
+                    permissions,
+                    self.normalize_subjects,
+                    self.subject_inheritance,
+                );
+                scopes
+                    .into_iter()
+                    .zip(checked)
+                    .filter(|(_, checked)| checked.is_granted(&granted))
+                    .flat_map(|(scope, _)| {
+                        scope.actions.into_iter().map(move |action| Permission {
+                            id: None,
+                            kind: scope.kind.clone(),
+                            subject: scope.name.clone(),
+                            permission: action,
+                        })
+                    })
+                    .collect()
+            }
+            None => permissions,
+        };
+
         Ok(permissions)
     }
 
     async fn permissions_for_user(&self, user: User, pass: &str) -> crate::Result<Vec<Permission>> {
         let pw_hash = UserPasswordHash::find_pw(&user.name, self.db()).await?;
-        let hash = argon2::PasswordHash::try_from(pw_hash.pw_hash.as_str())?;
-        let phfs = argon2::Argon2::default();
-        phfs.verify_password(pass.as_bytes(), &hash)
-            .map_err(|_| crate::Error::Unauthorized("Invalid password"))?;
+        if !self.verify_hash(pw_hash.pw_hash.clone(), pass).await? {
+            return Err(crate::Error::Unauthorized(INVALID_LOGIN));
+        }
+        if needs_rehash(
+            &pw_hash.pw_hash,
+            self.argon_algorithm,
+            self.argon_version,
+            self.argon2.params(),
+        ) {
+            self.rehash(&pw_hash, &user.name, pass).await;
+        }
 
         user.list_permissions(self.db()).await
     }
 
+    // Best effort, a failed upgrade keeps the old hash and never fails the login
+    async fn rehash(&self, pw_hash: &UserPasswordHash, name: &str, pass: &str) {
+        if self.read_only() {
+            return;
+        }
+        let result = match self.hash_secret(pass).await {
+            Ok(new_hash) => pw_hash.replace(&new_hash, self.db()).await,
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => tracing::info!("{:<12}- Upgraded the password hash of {name}", "Auth"),
+            Err(e) => tracing::warn!(
+                "{:<12}- Upgrading the password hash of {name} failed: {e}",
+                "Auth"
+            ),
+        }
+    }
+
+    // Hashes a new password or API key with the configured costs, bounded by ARGON2_CONCURRENCY
+    pub async fn hash_secret(&self, secret: &str) -> crate::Result<String> {
+        let secret = secret.to_string();
+        let phfs = self.argon2.clone();
+        run_limited(&self.argon_limit, move || {
+            use argon2::PasswordHasher;
+
+            let salt = argon2::password_hash::SaltString::generate(
+                &mut argon2::password_hash::rand_core::OsRng,
+            );
+            Ok(phfs.hash_password(secret.as_bytes(), &salt)?.to_string())
+        })
+        .await?
+    }
+
+    async fn verify_hash(&self, pw_hash: String, pass: &str) -> crate::Result<bool> {
+        let pass = pass.to_string();
+        let phfs = self.argon2.clone();
+        run_limited(&self.argon_limit, move || {
+            // the variant, version and params are read from the stored hash
+            let hash = argon2::PasswordHash::try_from(pw_hash.as_str())?;
+            Ok(phfs.verify_password(pass.as_bytes(), &hash).is_ok())
+        })
+        .await?
+    }
+
+    // Checks a password without issuing anything. Unknown users and service accounts are
+    // verified against the dummy hash and always invalid
+    pub async fn verify_credentials(&self, name: &str, pass: &str) -> crate::Result<bool> {
+        let stored = match UserPasswordHash::find_pw(name, self.db()).await {
+            Ok(pw_hash) => Some(pw_hash.pw_hash),
+            // find_pw reports a service account as BadRequest
+            Err(crate::Error::Db(sqlx::Error::RowNotFound) | crate::Error::BadRequest(_)) => None,
+            Err(e) => return Err(e),
+        };
+        let valid = self
+            .verify_hash(
+                stored.clone().unwrap_or_else(|| self.dummy_hash.clone()),
+                pass,
+            )
+            .await?;
+
+        Ok(stored.is_some() && valid)
+    }
+
+    // Returns the service account the API key belongs to. Unknown service accounts and ones
+    // without a key are verified against the dummy hash so they take as long as a wrong key
+    pub async fn verify_api_key(&self, name: &str, key: &str) -> crate::Result<User> {
+        let svc = match User::find_by_name(name, self.db()).await {
+            Ok(user) if user.user_type == UserType::ServiceAccount => Some(user),
+            Ok(_) | Err(crate::Error::Db(sqlx::Error::RowNotFound)) => None,
+            Err(e) => return Err(e),
+        };
+        let stored = match svc.as_ref().and_then(|svc| svc.id) {
+            Some(user_id) => SvcApiKey::find(user_id, self.db())
+                .await?
+                .map(|key| key.key_hash),
+            None => None,
+        };
+        let valid = self
+            .verify_hash(
+                stored.clone().unwrap_or_else(|| self.dummy_hash.clone()),
+                key,
+            )
+            .await?;
+
+        match svc {
+            Some(svc) if stored.is_some() && valid => Ok(svc),
+            _ => Err(crate::Error::Unauthorized(
+                "Invalid service account or API key",
+            )),
+        }
+    }
+
     pub async fn get_permissions(&self, user: User, pass: &str) -> crate::Result<Vec<Permission>> {
         let perms = match user.user_type {
-            crate::models::user::UserType::ServiceAccount => {
-                self.permissions_for_svc_account(user, pass).await?
-            }
-            crate::models::user::UserType::User => self.permissions_for_user(user, pass).await?,
+            UserType::ServiceAccount => self.permissions_for_svc_account(user, pass).await?,
+            UserType::User => self.permissions_for_user(user, pass).await?,
         };
 
         Ok(perms)
     }
 }
 
-fn create_cert_from_pair(pair: &ES384KeyPair, own_url: &str) -> crate::Result<Vec<u8>> {
+// The answer to both an unknown user and a wrong password
+pub(crate) const INVALID_LOGIN: &str = "Invalid username or password";
+
+// A stored hash is upgraded when it uses another variant or version, or lower costs than
+// configured. Unparsable hashes are left alone, verifying them already failed
+fn needs_rehash(
+    stored: &str,
+    algorithm: argon2::Algorithm,
+    version: argon2::Version,
+    params: &argon2::Params,
+) -> bool {
+    let Ok(hash) = argon2::PasswordHash::new(stored) else {
+        return false;
+    };
+    let Ok(stored_params) = argon2::Params::try_from(&hash) else {
+        return false;
+    };
+
+    hash.algorithm != algorithm.ident()
+        || hash.version != Some(u32::from(version))
+        || stored_params.m_cost() < params.m_cost()
+        || stored_params.t_cost() < params.t_cost()
+        || stored_params.p_cost() < params.p_cost()
+}
+
+fn dummy_hash(argon2: &argon2::Argon2<'static>) -> crate::Result<String> {
+    use argon2::PasswordHasher;
+
+    let salt =
+        argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    let password = uuid::Uuid::new_v4().to_string();
+    Ok(argon2
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+fn verify_password_length(password: &str, max: usize) -> crate::Result<()> {
+    if password.len() > max {
+        return Err(crate::Error::BadRequest("Password is too long"));
+    }
+    Ok(())
+}
+
+// Registries with a clock slightly behind ours would reject a token issued "in the future",
+// so iat and nbf are backdated while exp stays at now + duration
+fn docker_claims(
+    claims: DockerClaims,
+    duration_secs: u64,
+    backdate_secs: u64,
+) -> JWTClaims<DockerClaims> {
+    let mut claims = jwt_simple::claims::Claims::with_custom_claims(
+        claims,
+        jwt_simple::prelude::Duration::from_secs(duration_secs),
+    );
+    let backdate = jwt_simple::prelude::Duration::from_secs(backdate_secs);
+    claims.issued_at = claims.issued_at.map(|iat| iat - backdate);
+    claims.invalid_before = claims.invalid_before.map(|nbf| nbf - backdate);
+
+    claims
+}
+
+const MIN_TOKEN_TTL: u64 = 60;
+
+// Registries are told to expect tokens living at least 60 seconds, so shorter requests are
+// raised to that and longer ones are clamped to the maximum
+fn granted_ttl(requested: Option<u64>, default_secs: u64, max_secs: u64) -> u64 {
+    match requested {
+        Some(requested) => requested.clamp(MIN_TOKEN_TTL.min(max_secs), max_secs),
+        None => default_secs,
+    }
+}
+
+async fn token_subject(
+    mode: TokenSubject,
+    user: &User,
+    pool: &sqlx::SqlitePool,
+) -> crate::Result<String> {
+    match mode {
+        TokenSubject::Name => Ok(user.name.clone()),
+        TokenSubject::ExternalId => Ok(user.external_id(pool).await?.unwrap_or(user.name.clone())),
+    }
+}
+
+// Reverses token_subject, a user without external id was issued the token under its name.
+// A user that has one never gets its name in sub, so the name alone does not resolve to it
+async fn user_by_subject(
+    mode: TokenSubject,
+    sub: &str,
+    pool: &sqlx::SqlitePool,
+) -> crate::Result<User> {
+    if mode == TokenSubject::Name {
+        return User::find_by_name(sub, pool).await;
+    }
+    if let Some(user) = User::find_by_external_id(sub, pool).await? {
+        return Ok(user);
+    }
+    let user = User::find_by_name(sub, pool).await?;
+    match user.external_id(pool).await? {
+        None => Ok(user),
+        Some(_) => Err(crate::Error::NotFound("User not found")),
+    }
+}
+
+// Registries validating against their own configured name get that instead of the service.
+// The route already checked the service, this keeps a token from ever being minted for a
+// registry augesty does not know
+fn token_audience<'a>(
+    configured: Option<&'a str>,
+    service: &'a str,
+    docker_urls: &[String],
+) -> crate::Result<&'a str> {
+    if !docker_urls.iter().any(|url| url == service) {
+        return Err(crate::Error::Opaque(
+            "Token service is not a configured registry",
+        ));
+    }
+    Ok(configured.unwrap_or(service))
+}
+
+fn resolve_custom_claims(claims: &[(String, String)], user: &str) -> BTreeMap<String, String> {
+    claims
+        .iter()
+        .map(|(name, value)| (name.clone(), value.replace("{user}", user)))
+        .collect()
+}
+
+// The WWW-Authenticate value that makes browsers and CLIs prompt for credentials, Bearer
+// tells clients an admin token from /api/admin/login is accepted as well
+fn admin_challenge(realm: &str) -> crate::Result<HeaderValue> {
+    if realm.contains(['"', '\\']) {
+        return Err(crate::Error::Opaque(
+            "BASIC_AUTH_REALM must not contain quotes or backslashes",
+        ));
+    }
+    HeaderValue::from_str(&format!(
+        "Basic realm=\"{realm}\", charset=\"UTF-8\", Bearer realm=\"{realm}\""
+    ))
+    .map_err(|_| crate::Error::Opaque("Invalid BASIC_AUTH_REALM"))
+}
+
+// Only tokens that may list the catalog carry the hint
+fn catalog_max(scopes: &[Scope], configured: Option<u64>) -> Option<u64> {
+    configured.filter(|_| scopes.iter().any(Scope::is_catalog))
+}
+
+// Tokens for the same request have the same access claim, whatever order the scopes came in
+fn sorted_access(mut scopes: Vec<Scope>) -> Vec<Scope> {
+    for scope in &mut scopes {
+        scope.actions.sort_by_key(|action| action.to_string());
+        scope.actions.dedup();
+    }
+    scopes.sort_by(|a, b| (&a.kind, &a.name).cmp(&(&b.kind, &b.name)));
+    scopes
+}
+
+// in minutes, long enough for a session of admin calls
+pub(crate) const ADMIN_TOKEN_DURATION: u64 = 15;
+// Neither svc nor registry tokens are issued for this audience
+const ADMIN_AUDIENCE: &str = "augesty-admin";
+
+fn sign_admin_jwt(
+    key: &JwtKey,
+    claims: AdminClaims,
+    duration: jwt_simple::prelude::Duration,
+    issuer: &str,
+) -> crate::Result<String> {
+    let claims = jwt_simple::claims::Claims::with_custom_claims(claims, duration)
+        .with_issuer(issuer)
+        .with_audience(ADMIN_AUDIENCE)
+        .with_subject("admin");
+    key.sign(claims)
+}
+
+fn verify_admin_jwt(key: &ES384PublicKey, token: &str, issuer: &str) -> crate::Result<AdminClaims> {
+    let options = VerificationOptions {
+        allowed_issuers: Some(HashSet::from([issuer.to_string()])),
+        allowed_audiences: Some(HashSet::from([ADMIN_AUDIENCE.to_string()])),
+        // the default tolerance would keep an expired admin token valid for another 15 minutes
+        time_tolerance: Some(jwt_simple::prelude::Duration::from_secs(0)),
+        ..Default::default()
+    };
+    let claims = key
+        .verify_token::<AdminClaims>(token, Some(options))
+        .map_err(|_| crate::Error::Unauthorized("Invalid admin token"))?;
+    if claims.subject.as_deref() != Some("admin") {
+        return Err(crate::Error::Unauthorized("Invalid admin token"));
+    }
+
+    Ok(claims.custom)
+}
+
+// in minutes, svc tokens are exchanged right away so they stay short lived
+pub(crate) const SVC_TOKEN_DURATION: u64 = 5;
+
+// Svc tokens carry their own audience so a docker token can never pass as one
+fn sign_svc_jwt(
+    key: &JwtKey,
+    claims: SvcClaims,
+    issuer: &str,
+    audience: &str,
+) -> crate::Result<String> {
+    let claims = jwt_simple::claims::Claims::with_custom_claims(
+        claims,
+        jwt_simple::prelude::Duration::from_mins(SVC_TOKEN_DURATION),
+    )
+    .with_issuer(issuer)
+    .with_audience(audience);
+    key.sign(claims)
+}
+
+fn verify_svc_jwt(
+    key: &ES384PublicKey,
+    token: &str,
+    issuer: &str,
+    audience: &str,
+) -> crate::Result<SvcClaims> {
+    let options = VerificationOptions {
+        allowed_issuers: Some(HashSet::from([issuer.to_string()])),
+        allowed_audiences: Some(HashSet::from([audience.to_string()])),
+        ..Default::default()
+    };
+    let custom_claims = key
+        .verify_token::<SvcClaims>(token, Some(options))
+        .map_err(|_| crate::Error::Unauthorized("Invalid JWT token"))?
+        .custom;
+
+    Ok(custom_claims)
+}
+
+// A secondary instance only holds the public key of the signing instance
+enum JwtKey {
+    Signing(ES384KeyPair),
+    VerifyOnly(ES384PublicKey),
+}
+
+impl JwtKey {
+    // Reads the public key out of the cert the signing instance serves
+    fn verify_only(cert: &[u8]) -> crate::Result<Self> {
+        let public_pem = X509::from_pem(cert)?.public_key()?.public_key_to_pem()?;
+        let key = ES384PublicKey::from_pem(std::str::from_utf8(&public_pem)?)?;
+        Ok(Self::VerifyOnly(key))
+    }
+
+    fn sign<C: serde::Serialize + serde::de::DeserializeOwned>(
+        &self,
+        claims: JWTClaims<C>,
+    ) -> crate::Result<String> {
+        match self {
+            Self::Signing(pair) => pair
+                .sign(claims)
+                .map_err(|_| crate::Error::Opaque("Failed to create JWT token")),
+            Self::VerifyOnly(_) => Err(crate::Error::NotImplemented(
+                "Tokens are not issued in verify only mode",
+            )),
+        }
+    }
+
+    fn public_key(&self) -> ES384PublicKey {
+        match self {
+            Self::Signing(pair) => pair.public_key(),
+            Self::VerifyOnly(key) => key.clone(),
+        }
+    }
+}
+
+fn verify_docker_jwt(
+    key: &ES384PublicKey,
+    token: &str,
+    issuer: &str,
+    audience: &str,
+) -> crate::Result<JWTClaims<DockerClaims>> {
+    let options = VerificationOptions {
+        allowed_issuers: Some(HashSet::from([issuer.to_string()])),
+        allowed_audiences: Some(HashSet::from([audience.to_string()])),
+        ..Default::default()
+    };
+    key.verify_token::<DockerClaims>(token, Some(options))
+        .map_err(|_| crate::Error::Unauthorized("Invalid JWT token"))
+}
+
+// Tokens minted before the last force expire of their user are rejected
+async fn verify_epoch(user: &User, epoch: i64, pool: &sqlx::SqlitePool) -> crate::Result<()> {
+    if epoch < user.token_epoch(pool).await? {
+        return Err(crate::Error::Unauthorized("Token was force expired"));
+    }
+    Ok(())
+}
+
+pub const CERT_FILE: &str = "/config/jwt.pub";
+
+// Deployments bringing their own cert or a read only filesystem skip the write
+async fn store_cert_file(path: &Path, cert: &[u8], enabled: bool) -> crate::Result<()> {
+    if enabled {
+        tokio::fs::write(path, cert).await?;
+    }
+    Ok(())
+}
+
+// GitHub OIDC tokens are only valid for a few minutes, so a jti older than this cant be replayed anyway
+const OIDC_REPLAY_WINDOW: Duration = Duration::from_secs(10 * 60);
+const OIDC_REPLAY_CAPACITY: usize = 10_000;
+
+// Remembers recently used token ids, bounded in size and forgetting entries after the window.
+// Live ids are never evicted, that would let a flood of tokens make room for a replay
+struct ReplayCache {
+    window: Duration,
+    capacity: usize,
+    seen: Mutex<HashMap<String, Instant>>,
+}
+
+impl ReplayCache {
+    fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            seen: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Returns false if the id was already seen within the window, 503 while the cache is full
+    fn insert(&self, id: &str, now: Instant) -> crate::Result<bool> {
+        let mut seen = self.seen.lock().unwrap_or_else(|e| e.into_inner());
+        seen.retain(|_, used| now.saturating_duration_since(*used) < self.window);
+        if seen.contains_key(id) {
+            return Ok(false);
+        }
+
+        if seen.len() >= self.capacity {
+            tracing::warn!(
+                "{:<12}- {} OIDC tokens used within the replay window",
+                "Replay",
+                seen.len()
+            );
+            return Err(crate::Error::Unavailable(
+                "Too many OIDC tokens used, try again later",
+            ));
+        }
+        seen.insert(id.to_string(), now);
+
+        Ok(true)
+    }
+}
+
+// Caches one value for a while, the lock is held during the refresh so concurrent
+// misses wait for a single fetch instead of all hitting the upstream
+struct RefreshCache<T> {
+    ttl: Duration,
+    entry: tokio::sync::Mutex<Option<(Instant, Arc<T>)>>,
+}
+
+impl<T> RefreshCache<T> {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entry: tokio::sync::Mutex::new(None),
+        }
+    }
+
+    async fn get_or_refresh<F, Fut>(&self, fetch: F) -> crate::Result<Arc<T>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = crate::Result<T>>,
+    {
+        let mut entry = self.entry.lock().await;
+        if let Some((fetched, value)) = entry.as_ref()
+            && fetched.elapsed() < self.ttl
+        {
+            return Ok(value.clone());
+        }
+
+        let value = Arc::new(fetch().await?);
+        *entry = Some((Instant::now(), value.clone()));
+
+        Ok(value)
+    }
+
+    async fn is_fresh(&self) -> bool {
+        self.entry
+            .lock()
+            .await
+            .as_ref()
+            .is_some_and(|(fetched, _)| fetched.elapsed() < self.ttl)
+    }
+}
+
+async fn fetch_github_jwks() -> crate::Result<github_oidc::GithubJWKS> {
+    github_oidc::fetch_jwks(github_oidc::DEFAULT_GITHUB_OIDC_URL)
+        .await
+        .map_err(|_| crate::Error::Opaque("Error fetching github jwks"))
+}
+
+// Runs blocking work like argon2 off the runtime with at most as many jobs as the semaphore allows
+async fn run_limited<T, F>(limit: &Semaphore, f: F) -> crate::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let _permit = limit
+        .acquire()
+        .await
+        .map_err(|_| crate::Error::Opaque("Semaphore closed"))?;
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|_| crate::Error::Opaque("Blocking task failed"))
+}
+
+pub async fn connect_db(path: &str, key: Option<&str>) -> crate::Result<sqlx::SqlitePool> {
+    check_database_path(Path::new(path))?;
+    open_db(path, key).await
+}
+
+async fn open_db(db_url: &str, db_key: Option<&str>) -> crate::Result<sqlx::SqlitePool> {
+    let mut db_options = sqlx::sqlite::SqliteConnectOptions::new();
+    db_options = db_options.create_if_missing(true);
+    db_options = db_options.filename(db_url);
+    if let Some(key) = db_key {
+        if cfg!(feature = "sqlcipher") {
+            // sqlx always sends the key pragma first, as sqlcipher requires, but does not quote it
+            db_options = db_options.pragma("key", format!("'{}'", key.replace('\'', "''")));
+        } else {
+            tracing::warn!(
+                "{:<12}- DATABASE_KEY is set but augesty was built without sqlcipher",
+                "Database"
+            );
+        }
+    }
+    let db = pool_options().connect_with(db_options).await?;
+    Ok(db)
+}
+
+// sqlite only reports "unable to open database file", so the common mistakes are named up front
+fn check_database_path(path: &Path) -> crate::Result<()> {
+    if path.as_os_str() == ":memory:" {
+        return Ok(());
+    }
+    if path.is_dir() {
+        return Err(crate::Error::Any(format!(
+            "DATABASE_PATH {} is a directory, point it to a file inside it like {}",
+            path.display(),
+            path.join("augesty.db").display()
+        )));
+    }
+
+    // an empty file is a valid new database, so creating it here is harmless
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map(|_| ())
+        .map_err(|e| database_path_error(path, &e))
+}
+
+fn database_path_error(path: &Path, error: &std::io::Error) -> crate::Error {
+    let problem = match error.kind() {
+        std::io::ErrorKind::PermissionDenied => {
+            "is not writable, check the owner and mode of the file and its directory".to_string()
+        }
+        std::io::ErrorKind::NotFound => "is in a directory that does not exist".to_string(),
+        _ => format!("cannot be opened: {error}"),
+    };
+    crate::Error::Any(format!("DATABASE_PATH {} {problem}", path.display()))
+}
+
+// Connections are pinged before use and replaced regularly, so a database file that was
+// swapped out underneath a long running server is picked up without a restart
+fn pool_options() -> sqlx::sqlite::SqlitePoolOptions {
+    sqlx::sqlite::SqlitePoolOptions::new()
+        .test_before_acquire(true)
+        .max_lifetime(Duration::from_secs(30 * 60))
+        .idle_timeout(Duration::from_secs(5 * 60))
+}
+
+pub(crate) fn create_cert_from_pair(pair: &ES384KeyPair, own_url: &str) -> crate::Result<Vec<u8>> {
     let private_pem = pair.to_pem()?;
     let private_ec_key = EcKey::private_key_from_pem(&private_pem.as_bytes())?;
     let private_pkey = PKey::from_ec_key(private_ec_key)?;
@@ -189,11 +1180,80 @@ fn create_cert_from_pair(pair: &ES384KeyPair, own_url: &str) -> crate::Result<Ve
     let serial = serial.to_asn1_integer()?;
 
     builder.set_serial_number(&serial.as_ref())?;
-    builder.sign(&private_pkey, openssl::hash::MessageDigest::sha384())?;
+
+    // a leaf cert that may only verify token signatures
+    let basic_constraints = BasicConstraints::new().critical().build()?;
+    builder.append_extension(basic_constraints)?;
+    let key_usage = KeyUsage::new().critical().digital_signature().build()?;
+    builder.append_extension(key_usage)?;
+
+    // SHA-384 like the ES384 signatures of the tokens
+    builder.sign(&private_pkey, MessageDigest::sha384())?;
 
     Ok(builder.build().to_pem()?)
 }
 
+// The leaf first, then the chain in the given order, like TLS cert chains. The self signed
+// cert of signing mode is not issued by any chain and fails here
+fn append_chain(leaf: &[u8], chain: &[u8]) -> crate::Result<Vec<u8>> {
+    let leaf = X509::from_pem(leaf)?;
+    let chain = X509::stack_from_pem(chain)?;
+    let issuer = chain
+        .first()
+        .ok_or(crate::Error::Opaque("CERT_CHAIN contains no cert"))?;
+    if !leaf.verify(issuer.public_key()?.as_ref())? {
+        return Err(crate::Error::Opaque(
+            "The cert is not signed by the first cert of CERT_CHAIN",
+        ));
+    }
+
+    let mut pem = leaf.to_pem()?;
+    for cert in &chain {
+        pem.extend(cert.to_pem()?);
+    }
+    Ok(pem)
+}
+
+// Makes sure issued tokens can be verified by the registry with the written cert
+fn self_check(pair: &ES384KeyPair, cert: &[u8]) -> crate::Result<()> {
+    let claims = jwt_simple::claims::Claims::create(jwt_simple::prelude::Duration::from_mins(1));
+    let token = pair
+        .sign(claims)
+        .map_err(|_| crate::Error::Opaque("Self check failed to sign a token"))?;
+    pair.public_key()
+        .verify_token::<jwt_simple::claims::NoCustomClaims>(&token, None)
+        .map_err(|_| crate::Error::Opaque("Self check failed to verify a token"))?;
+
+    let public_pem = pair.public_key().to_pem()?;
+    let public_pkey = PKey::public_key_from_pem(public_pem.as_bytes())?;
+    let cert_pkey = X509::from_pem(cert)?.public_key()?;
+    if !cert_pkey.public_eq(&public_pkey) {
+        return Err(crate::Error::Opaque(
+            "Self check found a cert not matching the signing key",
+        ));
+    }
+
+    Ok(())
+}
+
+// Accepts PKCS#8 and SEC1 PEM, secrets managers flattening the PEM to one line with
+// escaped line breaks included. ES384 needs a P-384 key
+fn pair_from_pem(pem: &str) -> crate::Result<ES384KeyPair> {
+    let pkey = PKey::private_key_from_pem(pem.replace("\\n", "\n").as_bytes())
+        .map_err(|_| crate::Error::Opaque("JWT_PRIVATE_KEY_PEM is not a PEM private key"))?;
+    let curve = pkey.ec_key().ok().and_then(|key| key.group().curve_name());
+    if curve != Some(Nid::SECP384R1) {
+        return Err(crate::Error::Opaque(
+            "JWT_PRIVATE_KEY_PEM has to be a P-384 key to sign ES384",
+        ));
+    }
+
+    let pkcs8 = pkey.private_key_to_pem_pkcs8()?;
+    let pair = ES384KeyPair::from_pem(std::str::from_utf8(&pkcs8)?)
+        .map_err(|_| crate::Error::Opaque("JWT_PRIVATE_KEY_PEM is not a PEM private key"))?;
+    add_kid(pair)
+}
+
 fn add_kid(pair: ES384KeyPair) -> crate::Result<ES384KeyPair> {
     let public_der = pair.public_key().to_der()?;
 
@@ -214,11 +1274,844 @@ fn add_kid(pair: ES384KeyPair) -> crate::Result<ES384KeyPair> {
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SvcClaims {
     pub svc_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scope: Option<Vec<Scope>>,
+    // token epoch of the user at issuance
+    #[serde(default)]
+    pub epoch: i64,
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AdminClaims {
+    // token epoch of admin at issuance, required so no other token passes as an admin token
+    pub admin_epoch: i64,
 }
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct DockerClaims {
     pub access: Vec<Scope>,
+    // token epoch of the user at issuance
+    #[serde(default)]
+    pub epoch: i64,
+    // page size hint for registries paginating the catalog, see CATALOG_MAX_ENTRIES
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub catalog_max: Option<u64>,
+    // configured with TOKEN_CUSTOM_CLAIMS for policy engines next to the registry
+    #[serde(flatten)]
+    pub custom: BTreeMap<String, String>,
+}
+
+#[test]
+fn test_access_claim_is_sorted() -> crate::Result<()> {
+    let scopes = |raw: &[&str]| -> crate::Result<Vec<Scope>> {
+        raw.iter().map(|scope| Scope::parse_str(scope)).collect()
+    };
+    let sorted = sorted_access(scopes(&[
+        "repository:example/web:push,pull",
+        "repository:example/api:pull",
+    ])?);
+    assert_eq!(
+        sorted,
+        scopes(&[
+            "repository:example/api:pull",
+            "repository:example/web:pull,push"
+        ])?
+    );
+    assert_eq!(
+        sorted,
+        sorted_access(scopes(&[
+            "repository:example/api:pull",
+            "repository:example/web:pull,push",
+        ])?)
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_cert_chain_follows_the_leaf() -> crate::Result<()> {
+    let ca_pair = ES384KeyPair::generate();
+    let ca = create_cert_from_pair(&ca_pair, "ca.example.com")?;
+    let ca_pkey = PKey::private_key_from_pem(ca_pair.to_pem()?.as_bytes())?;
+
+    // a leaf for the signing key issued by the ca
+    let pair = ES384KeyPair::generate();
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    builder.set_subject_name(
+        X509::from_pem(&create_cert_from_pair(&pair, "augesty.example.com")?)?.subject_name(),
+    )?;
+    builder.set_issuer_name(X509::from_pem(&ca)?.subject_name())?;
+    let public_pkey = PKey::public_key_from_pem(pair.public_key().to_pem()?.as_bytes())?;
+    builder.set_pubkey(&public_pkey)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(1)?.as_ref())?;
+    builder.sign(&ca_pkey, MessageDigest::sha384())?;
+    let leaf = builder.build().to_pem()?;
+
+    let pem = append_chain(&leaf, &ca)?;
+    let certs = X509::stack_from_pem(&pem)?;
+    assert_eq!(certs.len(), 2);
+    assert_eq!(certs[0].to_pem()?, leaf);
+    assert_eq!(certs[1].to_pem()?, ca);
+
+    // a self signed leaf, as created in signing mode, is not issued by the chain
+    let other = create_cert_from_pair(&ES384KeyPair::generate(), "augesty.example.com")?;
+    assert!(append_chain(&other, &ca).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_pem_key_signs_verifiable_tokens() -> crate::Result<()> {
+    let ec_key = |nid| -> crate::Result<EcKey<openssl::pkey::Private>> {
+        Ok(EcKey::generate(
+            openssl::ec::EcGroup::from_curve_name(nid)?.as_ref(),
+        )?)
+    };
+    let key = ec_key(Nid::SECP384R1)?;
+    let public_pem = key.public_key_to_pem()?;
+    let pkcs8 = std::str::from_utf8(&PKey::from_ec_key(key.clone())?.private_key_to_pem_pkcs8()?)?
+        .to_string();
+    let sec1 = std::str::from_utf8(&key.private_key_to_pem()?)?.to_string();
+
+    let public_key = ES384PublicKey::from_pem(std::str::from_utf8(&public_pem)?)?;
+    for pem in [pkcs8.clone(), sec1, pkcs8.replace('\n', "\\n")] {
+        let pair = pair_from_pem(&pem)?;
+        let cert = create_cert_from_pair(&pair, "augesty.example.com")?;
+        self_check(&pair, &cert)?;
+
+        let token = JwtKey::Signing(pair).sign(
+            docker_claims(
+                DockerClaims {
+                    access: Vec::new(),
+                    epoch: 0,
+                    catalog_max: None,
+                    custom: BTreeMap::new(),
+                },
+                5 * 60,
+                0,
+            )
+            .with_issuer("augesty.example.com")
+            .with_audience("registry.example.com"),
+        )?;
+        assert!(
+            verify_docker_jwt(
+                &public_key,
+                &token,
+                "augesty.example.com",
+                "registry.example.com"
+            )
+            .is_ok()
+        );
+    }
+    // the same key always gets the same kid
+    assert_eq!(
+        pair_from_pem(&pkcs8)?.key_id(),
+        pair_from_pem(&pkcs8)?.key_id()
+    );
+
+    let p256 = PKey::from_ec_key(ec_key(Nid::X9_62_PRIME256V1)?)?.private_key_to_pem_pkcs8()?;
+    assert!(pair_from_pem(std::str::from_utf8(&p256)?).is_err());
+    assert!(pair_from_pem("not a key").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_self_check_rejects_mismatched_cert() -> crate::Result<()> {
+    let pair = ES384KeyPair::generate();
+    let other = ES384KeyPair::generate();
+    let cert = create_cert_from_pair(&other, "augesty.example.com")?;
+
+    assert!(self_check(&pair, &cert).is_err());
+    assert!(self_check(&other, &cert).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_cert_extensions() -> crate::Result<()> {
+    let pair = ES384KeyPair::generate();
+    let cert = X509::from_pem(&create_cert_from_pair(&pair, "augesty.example.com")?)?;
+    let text = String::from_utf8_lossy(&cert.to_text()?).to_string();
+
+    assert!(text.contains("CA:FALSE"));
+    assert!(text.contains("Digital Signature"));
+    assert!(!text.contains("Certificate Sign"));
+    assert!(text.contains("ecdsa-with-SHA384"));
+
+    Ok(())
+}
+
+#[test]
+fn test_requested_ttl_is_bounded() {
+    // TOKEN_DURATION=5 and MAX_TOKEN_DURATION=60
+    let granted = |requested| granted_ttl(requested, 5 * 60, 60 * 60);
+
+    assert_eq!(granted(None), 5 * 60);
+    assert_eq!(granted(Some(30 * 60)), 30 * 60);
+    assert_eq!(granted(Some(60 * 60)), 60 * 60);
+    assert_eq!(granted(Some(24 * 60 * 60)), 60 * 60);
+    assert_eq!(granted(Some(0)), 60);
+
+    // the exp of the token follows the granted lifetime
+    let claims = docker_claims(
+        DockerClaims {
+            access: Vec::new(),
+            epoch: 0,
+            catalog_max: None,
+            custom: BTreeMap::new(),
+        },
+        granted(Some(24 * 60 * 60)),
+        0,
+    );
+    assert_eq!(
+        claims.expires_at.unwrap().as_secs() - claims.issued_at.unwrap().as_secs(),
+        60 * 60
+    );
+}
+
+#[test]
+fn test_docker_claims_iat_is_backdated() {
+    let claims = docker_claims(
+        DockerClaims {
+            access: Vec::new(),
+            epoch: 0,
+            catalog_max: None,
+            custom: BTreeMap::new(),
+        },
+        5 * 60,
+        30,
+    );
+    let (iat, nbf, exp) = (
+        claims.issued_at.unwrap().as_secs(),
+        claims.invalid_before.unwrap().as_secs(),
+        claims.expires_at.unwrap().as_secs(),
+    );
+
+    assert_eq!(exp - iat, 5 * 60 + 30);
+    assert_eq!(iat, nbf);
+    let now = jwt_simple::prelude::Clock::now_since_epoch().as_secs();
+    assert!(now - iat >= 30 && now - iat <= 31);
+}
+
+#[test]
+fn test_hash_verifies_after_variant_change() -> crate::Result<()> {
+    use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
+
+    let salt =
+        argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    let old = Argon2::new(Algorithm::Argon2i, Version::V0x10, Params::default());
+    let stored = old.hash_password(b"hunter2", &salt)?.to_string();
+    assert!(stored.starts_with("$argon2i$v=16$"));
+
+    let current = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default());
+    let hash = argon2::PasswordHash::try_from(stored.as_str())?;
+    assert!(current.verify_password(b"hunter2", &hash).is_ok());
+    assert!(current.verify_password(b"hunter3", &hash).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_weaker_hash_needs_rehash() -> crate::Result<()> {
+    use argon2::{Algorithm, Argon2, Params, PasswordHasher, Version};
+
+    let hash = |algorithm, version, params| -> crate::Result<String> {
+        let salt = argon2::password_hash::SaltString::generate(
+            &mut argon2::password_hash::rand_core::OsRng,
+        );
+        Ok(Argon2::new(algorithm, version, params)
+            .hash_password(b"hunter2", &salt)?
+            .to_string())
+    };
+    let current = Params::new(32 * 1024, 3, 1, None)?;
+    let needs_rehash =
+        |stored: &str| needs_rehash(stored, Algorithm::Argon2id, Version::V0x13, &current);
+
+    assert!(!needs_rehash(&hash(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        current.clone()
+    )?));
+    // stronger than configured is kept
+    assert!(!needs_rehash(&hash(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(64 * 1024, 3, 1, None)?
+    )?));
+    assert!(needs_rehash(&hash(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::default()
+    )?));
+    assert!(needs_rehash(&hash(
+        Algorithm::Argon2id,
+        Version::V0x13,
+        Params::new(32 * 1024, 2, 1, None)?
+    )?));
+    assert!(needs_rehash(&hash(
+        Algorithm::Argon2i,
+        Version::V0x13,
+        current.clone()
+    )?));
+    assert!(needs_rehash(&hash(
+        Algorithm::Argon2id,
+        Version::V0x10,
+        current.clone()
+    )?));
+    assert!(!needs_rehash("not a hash"));
+
+    Ok(())
+}
+
+#[test]
+fn test_oversized_password_is_rejected() {
+    assert!(verify_password_length(&"a".repeat(1024), 1024).is_ok());
+    assert!(matches!(
+        verify_password_length(&"a".repeat(1025), 1024),
+        Err(crate::Error::BadRequest(_))
+    ));
+}
+
+#[test]
+fn test_token_audience_override() -> crate::Result<()> {
+    let docker_urls = ["registry.example.com".to_string()];
+    assert_eq!(
+        token_audience(None, "registry.example.com", &docker_urls)?,
+        "registry.example.com"
+    );
+    assert_eq!(
+        token_audience(
+            Some("internal-registry"),
+            "registry.example.com",
+            &docker_urls
+        )?,
+        "internal-registry"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_token_audience_is_permitted_for_the_service() -> crate::Result<()> {
+    let docker_urls = [
+        "registry.example.com".to_string(),
+        "mirror.example.com".to_string(),
+    ];
+
+    // every configured registry gets tokens for itself
+    for service in &docker_urls {
+        assert_eq!(token_audience(None, service, &docker_urls)?, service);
+    }
+    // never for a registry that is not configured, override or not
+    assert!(token_audience(None, "evil.example.com", &docker_urls).is_err());
+    assert!(
+        token_audience(
+            Some("registry.example.com"),
+            "evil.example.com",
+            &docker_urls
+        )
+        .is_err()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_custom_claims_in_docker_token() -> crate::Result<()> {
+    let key = ES384KeyPair::generate();
+    let custom = resolve_custom_claims(
+        &[
+            ("tenant".to_string(), "acme".to_string()),
+            ("email".to_string(), "{user}@example.com".to_string()),
+        ],
+        "ci",
+    );
+    let scope = Scope {
+        kind: "repository".to_string(),
+        name: "example/image".to_string(),
+        actions: vec![PermissionType::Pull],
+    };
+    let claims = docker_claims(
+        DockerClaims {
+            access: vec![scope],
+            epoch: 0,
+            catalog_max: None,
+            custom,
+        },
+        5 * 60,
+        0,
+    );
+    let token = key
+        .sign(claims)
+        .map_err(|_| crate::Error::Opaque("Failed to create JWT token"))?;
+
+    let decoded = key
+        .public_key()
+        .verify_token::<serde_json::Value>(&token, None)
+        .map_err(|_| crate::Error::Opaque("Failed to verify JWT token"))?
+        .custom;
+    assert_eq!(decoded["tenant"], "acme");
+    assert_eq!(decoded["email"], "ci@example.com");
+    assert_eq!(decoded["access"][0]["name"], "example/image");
+
+    Ok(())
+}
+
+#[test]
+fn test_admin_token_expires() -> crate::Result<()> {
+    let key = JwtKey::Signing(ES384KeyPair::generate());
+    let issuer = "augesty.example.com";
+
+    let token = sign_admin_jwt(
+        &key,
+        AdminClaims { admin_epoch: 3 },
+        jwt_simple::prelude::Duration::from_mins(ADMIN_TOKEN_DURATION),
+        issuer,
+    )?;
+    assert_eq!(
+        verify_admin_jwt(&key.public_key(), &token, issuer)?.admin_epoch,
+        3
+    );
+    assert!(verify_admin_jwt(&key.public_key(), &token, "other.example.com").is_err());
+
+    let mut claims = jwt_simple::claims::Claims::with_custom_claims(
+        AdminClaims { admin_epoch: 3 },
+        jwt_simple::prelude::Duration::from_mins(ADMIN_TOKEN_DURATION),
+    )
+    .with_issuer(issuer)
+    .with_audience(ADMIN_AUDIENCE)
+    .with_subject("admin");
+    let expired =
+        jwt_simple::prelude::Clock::now_since_epoch() - jwt_simple::prelude::Duration::from_secs(1);
+    claims.issued_at = Some(expired - jwt_simple::prelude::Duration::from_mins(1));
+    claims.invalid_before = claims.issued_at;
+    claims.expires_at = Some(expired);
+    let token = key.sign(claims)?;
+    assert!(matches!(
+        verify_admin_jwt(&key.public_key(), &token, issuer),
+        Err(crate::Error::Unauthorized(_))
+    ));
+
+    // a registry token is no admin token
+    let docker = key.sign(
+        docker_claims(
+            DockerClaims {
+                access: Vec::new(),
+                epoch: 0,
+                catalog_max: None,
+                custom: BTreeMap::new(),
+            },
+            5 * 60,
+            0,
+        )
+        .with_issuer(issuer)
+        .with_audience(ADMIN_AUDIENCE)
+        .with_subject("admin"),
+    )?;
+    assert!(verify_admin_jwt(&key.public_key(), &docker, issuer).is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_token_subject_carries_external_id() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    User::new_user("ops".to_string()).insert(&pool).await?;
+    let dev = User::find_by_name("dev", &pool).await?;
+    let ops = User::find_by_name("ops", &pool).await?;
+    dev.set_external_id(Some("u-1234"), &pool).await?;
+
+    let key = ES384KeyPair::generate();
+    let sub = |subject: String| -> crate::Result<Option<String>> {
+        let claims = docker_claims(
+            DockerClaims {
+                access: Vec::new(),
+                epoch: 0,
+                catalog_max: None,
+                custom: BTreeMap::new(),
+            },
+            5 * 60,
+            0,
+        )
+        .with_subject(subject);
+        let token = key
+            .sign(claims)
+            .map_err(|_| crate::Error::Opaque("Failed to create JWT token"))?;
+        Ok(key
+            .public_key()
+            .verify_token::<DockerClaims>(&token, None)
+            .map_err(|_| crate::Error::Opaque("Failed to verify JWT token"))?
+            .subject)
+    };
+
+    let name = token_subject(TokenSubject::Name, &dev, &pool).await?;
+    assert_eq!(sub(name)?.as_deref(), Some("dev"));
+    let external = token_subject(TokenSubject::ExternalId, &dev, &pool).await?;
+    assert_eq!(sub(external)?.as_deref(), Some("u-1234"));
+    // no external id falls back to the name
+    let fallback = token_subject(TokenSubject::ExternalId, &ops, &pool).await?;
+    assert_eq!(sub(fallback)?.as_deref(), Some("ops"));
+
+    assert_eq!(
+        user_by_subject(TokenSubject::ExternalId, "u-1234", &pool).await?,
+        dev
+    );
+    assert_eq!(
+        user_by_subject(TokenSubject::ExternalId, "ops", &pool).await?,
+        ops
+    );
+    User::new_user("other".to_string()).insert(&pool).await?;
+    let other = User::find_by_name("other", &pool).await?;
+    assert!(matches!(
+        other.set_external_id(Some("u-1234"), &pool).await,
+        Err(crate::Error::BadRequest(_))
+    ));
+    // the name of a user with an external id is no subject of its own
+    assert!(
+        user_by_subject(TokenSubject::ExternalId, "dev", &pool)
+            .await
+            .is_err()
+    );
+    // names and external ids never collide
+    assert!(matches!(
+        other.set_external_id(Some("ops"), &pool).await,
+        Err(crate::Error::BadRequest(_))
+    ));
+    assert!(matches!(
+        User::new_user("u-1234".to_string()).insert(&pool).await,
+        Err(crate::Error::BadRequest(_))
+    ));
+    assert!(matches!(
+        other.rename("u-1234", &pool).await,
+        Err(crate::Error::BadRequest(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_catalog_max_only_for_catalog_scopes() -> crate::Result<()> {
+    let catalog = vec![Scope::parse_str("registry:catalog:*")?];
+    let repository = vec![Scope::parse_str("repository:example/image:pull")?];
+
+    assert_eq!(catalog_max(&catalog, Some(500)), Some(500));
+    assert_eq!(catalog_max(&catalog, None), None);
+    assert_eq!(catalog_max(&repository, Some(500)), None);
+
+    let claims = |access: Vec<Scope>| {
+        serde_json::to_value(DockerClaims {
+            catalog_max: catalog_max(&access, Some(500)),
+            access,
+            epoch: 0,
+            custom: BTreeMap::new(),
+        })
+        .unwrap()
+    };
+    assert_eq!(claims(catalog)["catalog_max"], 500);
+    assert!(claims(repository).get("catalog_max").is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_force_expire_invalidates_prior_tokens() -> crate::Result<()> {
+    let pool = crate::test_pool().await?;
+    User::new_user("dev".to_string()).insert(&pool).await?;
+    let user = User::find_by_name("dev", &pool).await?;
+
+    let key = JwtKey::Signing(ES384KeyPair::generate());
+    let epoch = user.token_epoch(&pool).await?;
+    let claims = docker_claims(
+        DockerClaims {
+            access: Vec::new(),
+            epoch,
+            catalog_max: None,
+            custom: BTreeMap::new(),
+        },
+        5 * 60,
+        0,
+    )
+    .with_subject("dev")
+    .with_issuer("augesty.example.com")
+    .with_audience("registry.example.com");
+    let token = key.sign(claims)?;
+
+    let verify = || {
+        verify_docker_jwt(
+            &key.public_key(),
+            &token,
+            "augesty.example.com",
+            "registry.example.com",
+        )
+    };
+    let verified = verify()?;
+    assert!(
+        verify_epoch(&user, verified.custom.epoch, &pool)
+            .await
+            .is_ok()
+    );
+
+    assert_eq!(user.bump_token_epoch(&pool).await?, 1);
+    let verified = verify()?;
+    assert!(matches!(
+        verify_epoch(&user, verified.custom.epoch, &pool).await,
+        Err(crate::Error::Unauthorized(_))
+    ));
+    assert!(verify_epoch(&user, 1, &pool).await.is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_svc_jwt_rejects_wrong_issuer() -> crate::Result<()> {
+    let key = JwtKey::Signing(ES384KeyPair::generate());
+    let public = key.public_key();
+    let claims = || SvcClaims {
+        svc_name: "ci".to_string(),
+        scope: None,
+        epoch: 0,
+    };
+
+    let token = sign_svc_jwt(&key, claims(), "augesty.example.com", "svc")?;
+    assert!(verify_svc_jwt(&public, &token, "augesty.example.com", "svc").is_ok());
+
+    let token = sign_svc_jwt(&key, claims(), "other.example.com", "svc")?;
+    assert!(verify_svc_jwt(&public, &token, "augesty.example.com", "svc").is_err());
+
+    let token = sign_svc_jwt(&key, claims(), "augesty.example.com", "registry")?;
+    assert!(verify_svc_jwt(&public, &token, "augesty.example.com", "svc").is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_verify_only_key_rejects_signing() -> crate::Result<()> {
+    let pair = ES384KeyPair::generate();
+    let cert = create_cert_from_pair(&pair, "augesty.example.com")?;
+    let signing = JwtKey::Signing(pair);
+    let verify_only = JwtKey::verify_only(&cert)?;
+    let claims = || SvcClaims {
+        svc_name: "ci".to_string(),
+        scope: None,
+        epoch: 0,
+    };
+
+    assert!(matches!(
+        sign_svc_jwt(&verify_only, claims(), "augesty.example.com", "svc"),
+        Err(crate::Error::NotImplemented(_))
+    ));
+
+    // tokens of the signing instance still verify
+    let token = sign_svc_jwt(&signing, claims(), "augesty.example.com", "svc")?;
+    let verified = verify_svc_jwt(
+        &verify_only.public_key(),
+        &token,
+        "augesty.example.com",
+        "svc",
+    )?;
+    assert_eq!(verified.svc_name, "ci");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cert_file_write_can_be_disabled() -> crate::Result<()> {
+    let dir = std::env::temp_dir().join(format!("augesty-{}", uuid::Uuid::new_v4()));
+    let path = dir.join("jwt.pub");
+
+    // the directory does not exist, so any write attempt would fail
+    store_cert_file(&path, b"cert", false).await?;
+    assert!(!path.exists());
+    assert!(store_cert_file(&path, b"cert", true).await.is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_replayed_oidc_token_is_rejected() -> crate::Result<()> {
+    let cache = ReplayCache::new(Duration::from_secs(60), 2);
+    let now = Instant::now();
+
+    assert!(cache.insert("jti-1", now)?);
+    assert!(!cache.insert("jti-1", now + Duration::from_secs(1))?);
+    // expired entries can be used again
+    assert!(cache.insert("jti-1", now + Duration::from_secs(61))?);
+
+    // a full cache rejects new ids instead of forgetting live ones
+    assert!(cache.insert("jti-2", now + Duration::from_secs(62))?);
+    assert!(matches!(
+        cache.insert("jti-3", now + Duration::from_secs(63)),
+        Err(crate::Error::Unavailable(_))
+    ));
+    assert!(!cache.insert("jti-1", now + Duration::from_secs(63))?);
+    assert_eq!(cache.seen.lock().unwrap().len(), 2);
+    // until the oldest one expires
+    assert!(cache.insert("jti-3", now + Duration::from_secs(121))?);
+    assert!(!cache.insert("jti-2", now + Duration::from_secs(121))?);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_refresh_cache_fetches_once() -> crate::Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let cache = Arc::new(RefreshCache::new(Duration::from_secs(60)));
+    let fetches = Arc::new(AtomicUsize::new(0));
+
+    let tasks: Vec<_> = (0..10)
+        .map(|_| {
+            let cache = cache.clone();
+            let fetches = fetches.clone();
+            tokio::spawn(async move {
+                cache
+                    .get_or_refresh(|| async move {
+                        fetches.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        Ok("jwks")
+                    })
+                    .await
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        let value = task
+            .await
+            .map_err(|_| crate::Error::Opaque("Task failed"))??;
+        assert_eq!(*value, "jwks");
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_run_limited_bounds_concurrency() -> crate::Result<()> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let limit = Arc::new(Semaphore::new(2));
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+
+    let tasks: Vec<_> = (0..6)
+        .map(|_| {
+            let limit = limit.clone();
+            let running = running.clone();
+            let max_running = max_running.clone();
+            tokio::spawn(async move {
+                run_limited(&limit, move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(std::time::Duration::from_millis(20));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+                .await
+            })
+        })
+        .collect();
+
+    for task in tasks {
+        task.await
+            .map_err(|_| crate::Error::Opaque("Task failed"))??;
+    }
+
+    assert_eq!(max_running.load(Ordering::SeqCst), 2);
+
+    Ok(())
+}
+
+#[cfg(feature = "sqlcipher")]
+#[tokio::test]
+async fn test_wrong_database_key_fails() -> crate::Result<()> {
+    let path = std::env::temp_dir().join(format!("augesty-{}.db", uuid::Uuid::new_v4()));
+    let path = path.to_string_lossy();
+
+    let db = open_db(&path, Some("right key")).await?;
+    sqlx::query("CREATE TABLE test (id INTEGER)")
+        .execute(&db)
+        .await?;
+    db.close().await;
+
+    let db = open_db(&path, Some("right key")).await?;
+    sqlx::query("SELECT * FROM test").fetch_all(&db).await?;
+    db.close().await;
+
+    // depending on the connection the wrong key fails on open or on the first query
+    let rejected = match open_db(&path, Some("wrong key")).await {
+        Ok(db) => sqlx::query("SELECT * FROM test")
+            .fetch_all(&db)
+            .await
+            .is_err(),
+        Err(_) => true,
+    };
+    assert!(rejected);
+
+    Ok(())
+}
+
+#[test]
+fn test_database_path_errors_name_the_problem() -> crate::Result<()> {
+    let dir = std::env::temp_dir().join(format!("augesty-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&dir)?;
+
+    let error = check_database_path(&dir).unwrap_err().to_string();
+    assert!(error.contains(&dir.display().to_string()));
+    assert!(error.contains("is a directory"));
+
+    let error = check_database_path(&dir.join("missing").join("augesty.db"))
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("does not exist"));
+
+    // root ignores file modes, so the permission case is checked on the error mapping
+    let path = dir.join("augesty.db");
+    let denied = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+    let error = database_path_error(&path, &denied).to_string();
+    assert!(error.contains(&path.display().to_string()));
+    assert!(error.contains("is not writable"));
+
+    assert!(check_database_path(&path).is_ok());
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replaced_database_file_is_picked_up() -> crate::Result<()> {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("augesty-{}.db", uuid::Uuid::new_v4()));
+    let replacement = dir.join(format!("augesty-{}.db", uuid::Uuid::new_v4()));
+
+    let options = sqlx::sqlite::SqliteConnectOptions::new()
+        .create_if_missing(true)
+        .filename(&path);
+    // the lifetime is shortened to not wait half an hour for the recycle
+    let db = pool_options()
+        .max_lifetime(Duration::from_millis(100))
+        .connect_with(options)
+        .await?;
+    sqlx::query("CREATE TABLE old (id INTEGER)")
+        .execute(&db)
+        .await?;
+
+    let other = open_db(&replacement.to_string_lossy(), None).await?;
+    sqlx::query("CREATE TABLE new (id INTEGER)")
+        .execute(&other)
+        .await?;
+    other.close().await;
+    std::fs::rename(&replacement, &path)?;
+
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    sqlx::query("SELECT * FROM new").fetch_all(&db).await?;
+    db.close().await;
+    _ = std::fs::remove_file(&path);
+
+    Ok(())
 }
 
 #[tokio::test]
@@ -226,16 +2119,79 @@ async fn test_docker_jwt() -> crate::Result<()> {
     _ = dotenvy::dotenv();
     crate::trace::init_tracing();
 
-    let state = AppState::new().await?;
+    let state = AppState::new(Config::load()?).await?;
     let scope = Scope {
         kind: "repository".to_string(),
         name: "example/image".to_string(),
         actions: vec![crate::models::permission::PermissionType::Push],
     };
-    let (jwt, expires_in) =
-        state.create_docker_jwt("admin", "registry.example.com", vec![scope])?;
+    let (jwt, expires_in) = state.create_docker_jwt(
+        "admin",
+        "admin",
+        "registry.example.com",
+        vec![scope],
+        0,
+        state.token_ttl(None),
+    )?;
 
     tracing::info!("The jwt\n\n{jwt}\n\nexpires in {expires_in} seconds");
 
     Ok(())
 }
+
+#[test]
+fn test_expired_docker_jwt_is_rejected() -> crate::Result<()> {
+    let key = JwtKey::Signing(ES384KeyPair::generate());
+    let issuer = "augesty.example.com";
+    let claims = || DockerClaims {
+        access: Vec::new(),
+        epoch: 0,
+        catalog_max: None,
+        custom: BTreeMap::new(),
+    };
+
+    let aud = "registry.example.com";
+
+    let token = key.sign(
+        docker_claims(claims(), 5 * 60, 0)
+            .with_issuer(issuer)
+            .with_audience(aud),
+    )?;
+    assert!(verify_docker_jwt(&key.public_key(), &token, issuer, aud).is_ok());
+    // a token for one registry is not valid for another
+    assert!(matches!(
+        verify_docker_jwt(&key.public_key(), &token, issuer, "other.example.com"),
+        Err(crate::Error::Unauthorized(_))
+    ));
+
+    // well past the default clock tolerance
+    let mut expired = docker_claims(claims(), 5 * 60, 0)
+        .with_issuer(issuer)
+        .with_audience(aud);
+    let past = jwt_simple::prelude::Clock::now_since_epoch()
+        - jwt_simple::prelude::Duration::from_hours(1);
+    expired.issued_at = Some(past - jwt_simple::prelude::Duration::from_mins(5));
+    expired.invalid_before = expired.issued_at;
+    expired.expires_at = Some(past);
+    let token = key.sign(expired)?;
+    assert!(matches!(
+        verify_docker_jwt(&key.public_key(), &token, issuer, aud),
+        Err(crate::Error::Unauthorized(_))
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn test_admin_challenge_lists_both_schemes() -> crate::Result<()> {
+    let challenge = admin_challenge("augesty admin")?;
+    let challenge = challenge.to_str().unwrap_or_default();
+    assert!(challenge.starts_with("Basic realm=\"augesty admin\", charset=\"UTF-8\""));
+    assert!(challenge.ends_with(", Bearer realm=\"augesty admin\""));
+
+    // a quote would end the realm early and break both challenges
+    assert!(admin_challenge("augesty\"").is_err());
+    assert!(admin_challenge("augesty\nadmin").is_err());
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+const ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PermissionAction {
+    Grant,
+    Revoke,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PermissionEvent {
+    pub action: PermissionAction,
+    pub user: String,
+    pub kind: String,
+    pub subject: String,
+    pub permission: String,
+    // the admin that made the change
+    pub actor: String,
+    // seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl PermissionEvent {
+    pub fn new(
+        action: PermissionAction,
+        user: String,
+        kind: String,
+        subject: String,
+        permission: String,
+        actor: String,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        Self {
+            action,
+            user,
+            kind,
+            subject,
+            permission,
+            actor,
+            timestamp,
+        }
+    }
+}
+
+// Posts permission changes to an external receiver like a SIEM
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    http: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> crate::Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .map_err(|_| crate::Error::Opaque("Error building the webhook client"))?;
+
+        Ok(Self { http, url })
+    }
+
+    // Delivers in the background so the admin request never waits on the receiver
+    pub fn send(&self, event: PermissionEvent) {
+        let webhook = self.clone();
+        tokio::spawn(async move { webhook.deliver(&event).await });
+    }
+
+    async fn deliver(&self, event: &PermissionEvent) -> bool {
+        for attempt in 1..=ATTEMPTS {
+            match self.http.post(&self.url).json(event).send().await {
+                Ok(response) if response.status().is_success() => return true,
+                Ok(response) => tracing::warn!(
+                    "{:<12}- Attempt {attempt} returned {}",
+                    "Webhook",
+                    response.status()
+                ),
+                Err(e) => tracing::warn!("{:<12}- Attempt {attempt} failed: {}", "Webhook", e),
+            }
+            if attempt < ATTEMPTS {
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+            }
+        }
+
+        tracing::error!(
+            "{:<12}- Dropped {:?} event for {}",
+            "Webhook",
+            event.action,
+            event.user
+        );
+        false
+    }
+}
+
+#[tokio::test]
+async fn test_webhook_retries_until_delivered() -> crate::Result<()> {
+    use std::sync::{
+        Arc, Mutex,
+        atomic::{AtomicUsize, Ordering},
+    };
+
+    use axum::{Json, Router, http::StatusCode, routing::post};
+
+    let calls = Arc::new(AtomicUsize::new(0));
+    let received = Arc::new(Mutex::new(Vec::<serde_json::Value>::new()));
+    let receiver = {
+        let calls = calls.clone();
+        let received = received.clone();
+        Router::new().route(
+            "/hook",
+            post(move |Json(event): Json<serde_json::Value>| async move {
+                // the first delivery fails to exercise the retry
+                if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    return StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                received.lock().unwrap().push(event);
+                StatusCode::OK
+            }),
+        )
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+    let webhook = Webhook::new(format!("http://{addr}/hook"))?;
+    let event = PermissionEvent::new(
+        PermissionAction::Grant,
+        "ci".to_string(),
+        "repository".to_string(),
+        "example/image".to_string(),
+        "pull".to_string(),
+        "admin".to_string(),
+    );
+    assert!(webhook.deliver(&event).await);
+
+    assert_eq!(calls.load(Ordering::SeqCst), 2);
+    let received = received.lock().unwrap();
+    assert_eq!(received[0]["action"], "grant");
+    assert_eq!(received[0]["user"], "ci");
+    assert_eq!(received[0]["subject"], "example/image");
+    assert_eq!(received[0]["actor"], "admin");
+
+    Ok(())
+}
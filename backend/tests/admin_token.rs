@@ -0,0 +1,72 @@
+mod common;
+
+use augesty::routes::user::AdminLoginResponse;
+use reqwest::StatusCode;
+
+use common::ADMIN_PASSWORD;
+
+async fn server() -> augesty::Result<String> {
+    Ok(common::server(&[]).await?.0)
+}
+
+#[tokio::test]
+async fn test_admin_token_authorizes_until_revoked() -> augesty::Result<()> {
+    let url = server().await?;
+    let http = reqwest::Client::new();
+
+    let login: AdminLoginResponse = http
+        .post(format!("{url}/api/admin/login"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(login.expires_in, 15 * 60);
+
+    let list_users = || {
+        http.get(format!("{url}/api/user"))
+            .bearer_auth(&login.token)
+            .send()
+    };
+    assert_eq!(list_users().await?.status(), StatusCode::OK);
+
+    // the token cannot be exchanged for a fresh one
+    let relogin = http
+        .post(format!("{url}/api/admin/login"))
+        .bearer_auth(&login.token)
+        .send()
+        .await?;
+    assert_eq!(relogin.status(), StatusCode::UNAUTHORIZED);
+    let anonymous = http.post(format!("{url}/api/admin/login")).send().await?;
+    assert_eq!(anonymous.status(), StatusCode::UNAUTHORIZED);
+
+    // nor for registry tokens, they would outlive it
+    let registry_token = http
+        .get(format!("{url}/api/token"))
+        .bearer_auth(&login.token)
+        .query(&[
+            ("service", "registry.example.com"),
+            ("scope", "repository:example/image:pull"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(registry_token.status(), StatusCode::UNAUTHORIZED);
+
+    let invalid = http
+        .get(format!("{url}/api/user"))
+        .bearer_auth("not-a-token")
+        .send()
+        .await?;
+    assert_eq!(invalid.status(), StatusCode::UNAUTHORIZED);
+
+    let expired = http
+        .post(format!("{url}/api/user/expire_tokens"))
+        .bearer_auth(&login.token)
+        .json(&serde_json::json!({ "name": "admin" }))
+        .send()
+        .await?;
+    assert_eq!(expired.status(), StatusCode::OK);
+    assert_eq!(list_users().await?.status(), StatusCode::UNAUTHORIZED);
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use augesty::{
+    models::{permission::PermissionType, user::User},
+    routes::user::{SetApiKeyBody, SetApiKeyResponse},
+};
+use common::ADMIN_PASSWORD;
+use reqwest::StatusCode;
+
+// A service account ci that can pull example/image
+async fn server() -> augesty::Result<String> {
+    let (url, state) = common::server(&[("NORMALIZE_SUBJECTS", "true")]).await?;
+    User::new_service_account("ci".to_string())
+        .insert(state.db())
+        .await?;
+    User::find_by_name("ci", state.db())
+        .await?
+        .add_permission(
+            "repository".to_string(),
+            "example/image".to_string(),
+            PermissionType::Pull,
+            state.db(),
+        )
+        .await?;
+    Ok(url)
+}
+
+#[tokio::test]
+async fn test_api_key_exchanges_for_a_svc_token() -> augesty::Result<()> {
+    let url = server().await?;
+    let http = reqwest::Client::new();
+
+    let set_api_key = || async {
+        let response: SetApiKeyResponse = http
+            .put(format!("{url}/api/service_account/api_key"))
+            .basic_auth("admin", Some(ADMIN_PASSWORD))
+            .json(&SetApiKeyBody {
+                svc_name: "ci".to_string(),
+            })
+            .send()
+            .await?
+            .json()
+            .await?;
+        augesty::Result::Ok(response.api_key)
+    };
+    let identify = |name: &str, api_key: &str| {
+        http.post(format!("{url}/api/identify/api_key"))
+            .json(&serde_json::json!({ "service_account": name, "api_key": api_key }))
+            .send()
+    };
+
+    let api_key = set_api_key().await?;
+    let response = identify("ci", &api_key).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response: serde_json::Value = response.json().await?;
+    let accesstoken = response["accesstoken"].as_str().unwrap_or_default();
+
+    // the svc token logs in like one from /api/identify
+    let docker = http
+        .get(format!("{url}/api/token"))
+        .basic_auth("ci", Some(accesstoken))
+        .query(&[
+            ("service", "registry.example.com"),
+            ("scope", "repository:example/image:pull"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(docker.status(), StatusCode::OK);
+
+    // a requested scope is normalized like on /api/token
+    let narrowed = http
+        .post(format!("{url}/api/identify/api_key"))
+        .json(&serde_json::json!({
+            "service_account": "ci",
+            "api_key": api_key,
+            "scope": ["repository:Example/Image:pull"],
+        }))
+        .send()
+        .await?;
+    assert_eq!(narrowed.status(), StatusCode::OK);
+    let narrowed: serde_json::Value = narrowed.json().await?;
+    let docker = http
+        .get(format!("{url}/api/token"))
+        .basic_auth("ci", narrowed["accesstoken"].as_str())
+        .query(&[
+            ("service", "registry.example.com"),
+            ("scope", "repository:Example/Image:pull"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(docker.status(), StatusCode::OK);
+
+    assert_eq!(
+        identify("ci", "wrong").await?.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(
+        identify("missing", &api_key).await?.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    // a user has no api key even when the name matches
+    assert_eq!(
+        identify("admin", ADMIN_PASSWORD).await?.status(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    // rotating invalidates the old key
+    let rotated = set_api_key().await?;
+    assert_ne!(rotated, api_key);
+    assert_eq!(
+        identify("ci", &api_key).await?.status(),
+        StatusCode::UNAUTHORIZED
+    );
+    assert_eq!(identify("ci", &rotated).await?.status(), StatusCode::OK);
+
+    let not_admin = http
+        .put(format!("{url}/api/service_account/api_key"))
+        .basic_auth("admin", Some("wrong"))
+        .json(&SetApiKeyBody {
+            svc_name: "ci".to_string(),
+        })
+        .send()
+        .await?;
+    assert_eq!(not_admin.status(), StatusCode::UNAUTHORIZED);
+
+    Ok(())
+}
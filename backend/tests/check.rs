@@ -0,0 +1,111 @@
+mod common;
+
+use reqwest::StatusCode;
+
+use common::ADMIN_PASSWORD;
+
+const DEV_PASSWORD: &str = "developer-password";
+
+#[tokio::test]
+async fn test_check_agrees_with_issued_tokens() -> augesty::Result<()> {
+    // dropping keeps the denied scopes out of the access claim instead of failing the request
+    let (url, state) = common::server(&[("UNAUTHORIZED_SCOPES", "drop")]).await?;
+    let http = reqwest::Client::new();
+
+    let created = http
+        .post(format!("{url}/api/user"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .json(&serde_json::json!({ "name": "developer", "password": DEV_PASSWORD }))
+        .send()
+        .await?;
+    assert_eq!(created.status(), StatusCode::OK);
+    for (image, access) in [("*", "pull"), ("example/image", "push")] {
+        let granted = http
+            .post(format!("{url}/api/user/access"))
+            .basic_auth("admin", Some(ADMIN_PASSWORD))
+            .json(&serde_json::json!({ "name": "developer", "image": image, "access": access }))
+            .send()
+            .await?;
+        assert_eq!(granted.status(), StatusCode::OK);
+    }
+
+    let check = |kind: &str, subject: &str, action: &str| {
+        let request = http
+            .get(format!("{url}/api/check"))
+            .basic_auth("developer", Some(DEV_PASSWORD))
+            .query(&[
+                ("user", "developer"),
+                ("kind", kind),
+                ("subject", subject),
+                ("action", action),
+            ])
+            .send();
+        async move {
+            let response: serde_json::Value = request.await?.json().await?;
+            augesty::Result::Ok(response["allowed"].as_bool())
+        }
+    };
+    let issued = |kind: &str, subject: &str, action: &str| {
+        let request = http
+            .get(format!("{url}/api/token"))
+            .basic_auth("developer", Some(DEV_PASSWORD))
+            .query(&[
+                ("service", "registry.example.com"),
+                ("scope", &format!("{kind}:{subject}:{action}")),
+            ])
+            .send();
+        let (kind, subject, action) = (kind.to_string(), subject.to_string(), action.to_string());
+        let state = state.clone();
+        async move {
+            let response: serde_json::Value = request.await?.json().await?;
+            let token = response["token"].as_str().unwrap_or_default();
+            let claims = state
+                .verify_docker_jwt(token, "registry.example.com")
+                .await?;
+            augesty::Result::Ok(claims.custom.access.iter().any(|scope| {
+                scope.kind == kind
+                    && scope.name == subject
+                    && scope
+                        .actions
+                        .iter()
+                        .any(|granted| granted.to_string() == action)
+            }))
+        }
+    };
+
+    for (kind, subject, action, allowed) in [
+        // wildcard
+        ("repository", "any/image", "pull", true),
+        ("repository", "any/image", "push", false),
+        // exact
+        ("repository", "example/image", "push", true),
+        ("repository", "example/other", "push", false),
+        ("plugin", "example/image", "push", false),
+    ] {
+        assert_eq!(
+            check(kind, subject, action).await?,
+            Some(allowed),
+            "{kind}:{subject}:{action}"
+        );
+        assert_eq!(
+            issued(kind, subject, action).await?,
+            allowed,
+            "{kind}:{subject}:{action}"
+        );
+    }
+
+    // only admin may ask about someone else
+    let other = http
+        .get(format!("{url}/api/check"))
+        .basic_auth("developer", Some(DEV_PASSWORD))
+        .query(&[
+            ("user", "admin"),
+            ("subject", "example/image"),
+            ("action", "pull"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(other.status(), StatusCode::UNAUTHORIZED);
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+#![cfg(feature = "client")]
+
+mod common;
+
+use std::sync::OnceLock;
+
+use augesty::{
+    client::Client,
+    routes::user::{AddIdentifierBody, CreateServiceAccountBody, CreateUserBody, GrantAccessBody},
+};
+use common::ADMIN_PASSWORD;
+
+// Runs one server on its own runtime for all tests, returns its base url
+fn server() -> &'static str {
+    static SERVER: OnceLock<String> = OnceLock::new();
+    SERVER.get_or_init(|| {
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(async move {
+                let state = common::state(&[]).await.unwrap();
+                common::create_admin(&state).await.unwrap();
+
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                tx.send(format!("http://{addr}")).unwrap();
+
+                axum::serve(listener, augesty::app(state).into_make_service())
+                    .await
+                    .unwrap();
+            });
+        });
+        rx.recv().unwrap()
+    })
+}
+
+#[tokio::test]
+async fn test_create_user_and_grant_access() -> augesty::Result<()> {
+    let client = Client::new(server(), "admin", ADMIN_PASSWORD);
+
+    let created = client
+        .create_user(&CreateUserBody {
+            name: "developer".to_string(),
+            password: "developer-password".to_string(),
+        })
+        .await?;
+    assert_eq!(created.user_name, "developer");
+
+    let granted = client
+        .grant_access(&GrantAccessBody {
+            name: "developer".to_string(),
+            kind: "repository".to_string(),
+            image: "example/image".to_string(),
+            access: "pull".to_string(),
+        })
+        .await?;
+    assert_eq!(granted.user_name, "developer");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_create_service_account_and_add_identifier() -> augesty::Result<()> {
+    let client = Client::new(server(), "admin", ADMIN_PASSWORD);
+
+    let created = client
+        .create_service_account(&CreateServiceAccountBody {
+            name: "ci".to_string(),
+        })
+        .await?;
+    assert_eq!(created.svc_name, "ci");
+
+    let added = client
+        .add_identifier(&AddIdentifierBody {
+            svc_name: "ci".to_string(),
+            repo: "example/repo".to_string(),
+            match_strategy: None,
+        })
+        .await?;
+    assert_eq!(added.svc_name, "ci");
+
+    let wrong_password = Client::new(server(), "admin", "wrong");
+    assert!(
+        wrong_password
+            .create_service_account(&CreateServiceAccountBody {
+                name: "other".to_string(),
+            })
+            .await
+            .is_err()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,62 @@
+// Shared by the integration tests, each binary only uses part of it
+#![allow(dead_code)]
+
+use argon2::PasswordHasher;
+use augesty::{config::Config, models::user::User, state::AppState};
+
+pub const ADMIN_PASSWORD: &str = "integration-test-password";
+
+// Sets the settings every test server needs plus its own, on a fresh database
+pub fn set_env(env: &[(&str, &str)]) {
+    let db_path = std::env::temp_dir().join(format!("augesty-{}.db", uuid::Uuid::new_v4()));
+    // SAFETY: set before the server reads its configuration, every test binary starts a single
+    // server
+    unsafe {
+        std::env::set_var("DATABASE_PATH", &db_path);
+        std::env::set_var("OWN_URL", "augesty.example.com");
+        std::env::set_var("DOCKER_URL", "registry.example.com");
+        std::env::set_var("TOKEN_DURATION", "5");
+        std::env::set_var("WRITE_CERT_FILE", "false");
+        for (key, value) in env {
+            std::env::set_var(key, value);
+        }
+    }
+}
+
+// The migrated state of a server configured by set_env
+pub async fn state(env: &[(&str, &str)]) -> augesty::Result<AppState> {
+    set_env(env);
+    let state = AppState::new(Config::load()?).await?;
+    augesty::migrate(state.db()).await?;
+    Ok(state)
+}
+
+// Serves the app on a free port and returns its base url
+pub async fn serve(state: AppState) -> augesty::Result<String> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, augesty::app(state)).await });
+    Ok(format!("http://{addr}"))
+}
+
+// A state with admin and a server for it
+pub async fn server(env: &[(&str, &str)]) -> augesty::Result<(String, AppState)> {
+    let state = state(env).await?;
+    create_admin(&state).await?;
+    Ok((serve(state.clone()).await?, state))
+}
+
+// Creates admin with a known password so generate_admin keeps it
+pub async fn create_admin(state: &AppState) -> augesty::Result<()> {
+    User::new_user("admin".to_string())
+        .insert(state.db())
+        .await?;
+    let admin = User::find_by_name("admin", state.db()).await?;
+    let salt =
+        argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    let pw_hash = state
+        .argon2()
+        .hash_password(ADMIN_PASSWORD.as_bytes(), &salt)?;
+    admin.add_hash(&pw_hash.to_string(), state.db()).await?;
+    User::generate_admin(state.db(), state.argon2(), state.admin_password_file()).await
+}
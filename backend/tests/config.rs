@@ -0,0 +1,46 @@
+mod common;
+
+use augesty::routes::config::ConfigResponse;
+use reqwest::StatusCode;
+
+// Starts a server with a known configuration
+async fn server() -> augesty::Result<String> {
+    let state = common::state(&[
+        ("DOCKER_URL", "registry.example.com,mirror.example.com"),
+        ("TOKEN_DURATION", "7"),
+        ("SVC_TOKEN_ISSUER", "augesty-svc"),
+        ("BASE_PATH", "/auth"),
+    ])
+    .await?;
+    common::serve(state).await
+}
+
+#[tokio::test]
+async fn test_config_matches_env() -> augesty::Result<()> {
+    let url = server().await?;
+
+    let response = reqwest::get(format!("{url}/auth/api/config")).await?;
+    assert_eq!(response.status(), StatusCode::OK);
+    let config: ConfigResponse = response.json().await?;
+
+    assert_eq!(config.token_ttl, 7 * 60);
+    assert_eq!(config.svc_token_ttl, 5 * 60);
+    assert_eq!(config.signing_algorithm, "ES384");
+    assert_eq!(config.issuer, "augesty.example.com");
+    assert_eq!(config.svc_issuer, "augesty-svc");
+    assert_eq!(
+        config.services,
+        vec!["registry.example.com", "mirror.example.com"]
+    );
+    assert_eq!(
+        config.cert_url,
+        "https://augesty.example.com/auth/api/cert.pem"
+    );
+    assert!(!config.verify_only);
+    assert_eq!(
+        config.providers,
+        vec!["basic", "api_key", "admin_token", "github_oidc"]
+    );
+
+    Ok(())
+}
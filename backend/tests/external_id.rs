@@ -0,0 +1,99 @@
+mod common;
+
+use augesty::models::user::User;
+use reqwest::StatusCode;
+
+use common::ADMIN_PASSWORD;
+
+#[tokio::test]
+async fn test_external_id_subject_round_trips() -> augesty::Result<()> {
+    let (url, state) = common::server(&[("TOKEN_SUBJECT", "external_id")]).await?;
+    let http = reqwest::Client::new();
+
+    let set = http
+        .put(format!("{url}/api/user/external_id"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .json(&serde_json::json!({ "name": "admin", "external_id": "u-admin" }))
+        .send()
+        .await?;
+    assert_eq!(set.status(), StatusCode::OK);
+
+    let response: serde_json::Value = http
+        .get(format!("{url}/api/token"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .query(&[
+            ("service", "registry.example.com"),
+            ("scope", "repository:example/image:pull"),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = response["token"].as_str().unwrap_or_default().to_string();
+
+    let validate = |token: &str| {
+        let request = http
+            .post(format!("{url}/api/token/validate"))
+            .json(&serde_json::json!({
+                "token": token,
+                "scope": "repository:example/image:pull",
+                "service": "registry.example.com",
+            }))
+            .send();
+        async move {
+            let response: serde_json::Value = request.await?.json().await?;
+            augesty::Result::Ok(response["valid"].as_bool())
+        }
+    };
+    assert_eq!(validate(&token).await?, Some(true));
+
+    // a name equal to the external id would take over its subject
+    assert!(
+        User::new_user("u-admin".to_string())
+            .insert(state.db())
+            .await
+            .is_err()
+    );
+    let taken = http
+        .put(format!("{url}/api/user/external_id"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .json(&serde_json::json!({ "name": "admin", "external_id": "admin" }))
+        .send()
+        .await?;
+    assert_eq!(taken.status(), StatusCode::OK);
+    User::new_user("dev".to_string()).insert(state.db()).await?;
+    let collides = http
+        .put(format!("{url}/api/user/external_id"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .json(&serde_json::json!({ "name": "admin", "external_id": "dev" }))
+        .send()
+        .await?;
+    assert_eq!(collides.status(), StatusCode::BAD_REQUEST);
+
+    // the token was issued to u-admin, which no longer names anyone
+    assert_eq!(validate(&token).await?, Some(false));
+    let response: serde_json::Value = http
+        .get(format!("{url}/api/token"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .query(&[
+            ("service", "registry.example.com"),
+            ("scope", "repository:example/image:pull"),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = response["token"].as_str().unwrap_or_default().to_string();
+    assert_eq!(validate(&token).await?, Some(true));
+
+    let expired = http
+        .post(format!("{url}/api/user/expire_tokens"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .json(&serde_json::json!({ "name": "admin" }))
+        .send()
+        .await?;
+    assert_eq!(expired.status(), StatusCode::OK);
+    assert_eq!(validate(&token).await?, Some(false));
+
+    Ok(())
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use reqwest::StatusCode;
+
+use common::ADMIN_PASSWORD;
+
+async fn server() -> augesty::Result<String> {
+    Ok(common::server(&[("METRICS", "true")]).await?.0)
+}
+
+#[tokio::test]
+async fn test_auth_outcomes_are_counted() -> augesty::Result<()> {
+    let url = server().await?;
+    let http = reqwest::Client::new();
+
+    let login = |password: &str| {
+        http.get(format!("{url}/api/token"))
+            .basic_auth("admin", Some(password))
+            .query(&[("service", "registry.example.com")])
+            .send()
+    };
+    let metric = |line: &'static str| {
+        let request = http.get(format!("{url}/api/metrics")).send();
+        async move {
+            let metrics = request.await?.text().await?;
+            augesty::Result::Ok(
+                metrics
+                    .lines()
+                    .find_map(|metric| metric.strip_prefix(line))
+                    .map(str::to_string),
+            )
+        }
+    };
+    let basic_success = r#"augesty_auth_total{provider="basic",outcome="success"} "#;
+    let basic_failure = r#"augesty_auth_total{provider="basic",outcome="failure"} "#;
+
+    assert_eq!(metric(basic_success).await?.as_deref(), Some("0"));
+
+    assert_eq!(login(ADMIN_PASSWORD).await?.status(), StatusCode::OK);
+    assert_eq!(login(ADMIN_PASSWORD).await?.status(), StatusCode::OK);
+    assert_eq!(login("wrong").await?.status(), StatusCode::UNAUTHORIZED);
+
+    assert_eq!(metric(basic_success).await?.as_deref(), Some("2"));
+    assert_eq!(metric(basic_failure).await?.as_deref(), Some("1"));
+    assert_eq!(
+        metric(r#"augesty_auth_duration_seconds_count{provider="basic",outcome="success"} "#)
+            .await?
+            .as_deref(),
+        Some("2")
+    );
+
+    Ok(())
+}
@@ -0,0 +1,49 @@
+mod common;
+
+use reqwest::StatusCode;
+
+use common::ADMIN_PASSWORD;
+
+async fn server() -> augesty::Result<String> {
+    Ok(common::server(&[("READ_ONLY", "true")]).await?.0)
+}
+
+#[tokio::test]
+async fn test_read_only_blocks_mutations_but_issues_tokens() -> augesty::Result<()> {
+    let url = server().await?;
+    let http = reqwest::Client::new();
+    let create_user = || {
+        http.post(format!("{url}/api/user"))
+            .basic_auth("admin", Some(ADMIN_PASSWORD))
+            .json(&serde_json::json!({ "name": "developer", "password": "developer-password" }))
+            .send()
+    };
+
+    assert_eq!(
+        create_user().await?.status(),
+        StatusCode::SERVICE_UNAVAILABLE
+    );
+
+    let token = http
+        .get(format!("{url}/api/token"))
+        .query(&[
+            ("service", "registry.example.com"),
+            ("scope", "repository:example/image:pull"),
+        ])
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .send()
+        .await?;
+    assert_eq!(token.status(), StatusCode::OK);
+
+    let toggled = http
+        .put(format!("{url}/api/read_only"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .json(&serde_json::json!({ "read_only": false }))
+        .send()
+        .await?;
+    assert_eq!(toggled.status(), StatusCode::OK);
+
+    assert_eq!(create_user().await?.status(), StatusCode::OK);
+
+    Ok(())
+}
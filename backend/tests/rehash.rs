@@ -0,0 +1,74 @@
+mod common;
+
+use argon2::PasswordHasher;
+use augesty::{
+    models::{user::User, user_pw_hash::UserPasswordHash},
+    state::AppState,
+};
+use reqwest::StatusCode;
+
+const DEV_PASSWORD: &str = "hunter2";
+
+// dev's password was hashed before the costs were raised
+async fn server() -> augesty::Result<(String, AppState)> {
+    let (url, state) =
+        common::server(&[("ARGON2_MEMORY_KIB", "16384"), ("ARGON2_ITERATIONS", "2")]).await?;
+    User::new_user("dev".to_string()).insert(state.db()).await?;
+    let weak = argon2::Params::new(8 * 1024, 1, 1, None)?;
+    let pw_hash = hash(&argon2::Argon2::new(
+        argon2::Algorithm::Argon2id,
+        argon2::Version::V0x13,
+        weak,
+    ))?;
+    User::find_by_name("dev", state.db())
+        .await?
+        .add_hash(&pw_hash, state.db())
+        .await?;
+
+    Ok((url, state))
+}
+
+fn hash(argon2: &argon2::Argon2) -> augesty::Result<String> {
+    let salt =
+        argon2::password_hash::SaltString::generate(&mut argon2::password_hash::rand_core::OsRng);
+    Ok(argon2
+        .hash_password(DEV_PASSWORD.as_bytes(), &salt)?
+        .to_string())
+}
+
+#[tokio::test]
+async fn test_weak_hash_is_upgraded_on_login() -> augesty::Result<()> {
+    let (url, state) = server().await?;
+    let http = reqwest::Client::new();
+    let stored = || UserPasswordHash::find_pw("dev", state.db());
+    let params = |pw_hash: &str| -> augesty::Result<argon2::Params> {
+        Ok(argon2::Params::try_from(&argon2::PasswordHash::new(
+            pw_hash,
+        )?)?)
+    };
+    let login = |password: &'static str| {
+        http.get(format!("{url}/api/token"))
+            .basic_auth("dev", Some(password))
+            .query(&[("service", "registry.example.com")])
+            .send()
+    };
+
+    let weak = stored().await?.pw_hash;
+    assert_eq!(params(&weak)?.m_cost(), 8 * 1024);
+
+    // a failed login leaves the hash alone
+    assert_eq!(login("wrong").await?.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(stored().await?.pw_hash, weak);
+
+    assert_eq!(login(DEV_PASSWORD).await?.status(), StatusCode::OK);
+    let upgraded = stored().await?.pw_hash;
+    assert_ne!(upgraded, weak);
+    assert_eq!(params(&upgraded)?.m_cost(), 16 * 1024);
+    assert_eq!(params(&upgraded)?.t_cost(), 2);
+
+    // the upgraded hash keeps working and is not rehashed again
+    assert_eq!(login(DEV_PASSWORD).await?.status(), StatusCode::OK);
+    assert_eq!(stored().await?.pw_hash, upgraded);
+
+    Ok(())
+}
@@ -0,0 +1,99 @@
+mod common;
+
+use augesty::{config::Config, server, state::AppState};
+use openssl::{
+    asn1::Asn1Time,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::PKey,
+    x509::{X509, X509NameBuilder, extension::SubjectAlternativeName},
+};
+
+const HOST: &str = "augesty.test";
+
+// A self-signed cert for HOST, returned as PEM cert and PKCS#8 key
+fn self_signed() -> augesty::Result<(Vec<u8>, Vec<u8>)> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_text("CN", HOST)?;
+    let name = name.build();
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(1)?;
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    let san = SubjectAlternativeName::new()
+        .dns(HOST)
+        .build(&cert.x509v3_context(None, None))?;
+    cert.append_extension(san)?;
+    cert.sign(&key, MessageDigest::sha256())?;
+
+    Ok((cert.build().to_pem()?, key.private_key_to_pem_pkcs8()?))
+}
+
+// Starts an HTTPS server
+async fn server(cert: &[u8], key: &[u8]) -> augesty::Result<std::net::SocketAddr> {
+    let dir = std::env::temp_dir().join(format!("augesty-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("tls.crt"), cert)?;
+    std::fs::write(dir.join("tls.key"), key)?;
+    let cert_file = dir.join("tls.crt");
+    let key_file = dir.join("tls.key");
+    common::set_env(&[
+        ("TLS_CERT_FILE", &cert_file.to_string_lossy()),
+        ("TLS_KEY_FILE", &key_file.to_string_lossy()),
+    ]);
+
+    let config = Config::load()?;
+    let server_config = config.server.clone();
+    let state = AppState::new(config).await?;
+    augesty::migrate(state.db()).await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        server::serve(
+            listener,
+            augesty::app(state),
+            &server_config,
+            std::future::pending(),
+        )
+        .await
+    });
+
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn test_serves_https_with_the_configured_cert() -> augesty::Result<()> {
+    let (cert, key) = self_signed()?;
+    let addr = server(&cert, &key).await?;
+    let url = format!("https://{HOST}:{}/api/config", addr.port());
+
+    let trusting = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&cert)?)
+        .resolve(HOST, addr)
+        .build()?;
+    let response = trusting.get(&url).send().await?;
+    assert!(response.status().is_success());
+
+    // the cert is not trusted by default
+    let default = reqwest::Client::builder().resolve(HOST, addr).build()?;
+    assert!(default.get(&url).send().await.is_err());
+
+    // plain HTTP is not served next to HTTPS
+    let plain = reqwest::Client::new()
+        .get(format!("http://{addr}/api/config"))
+        .send()
+        .await;
+    assert!(plain.is_err());
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+mod common;
+
+use reqwest::StatusCode;
+
+use common::ADMIN_PASSWORD;
+
+async fn server() -> augesty::Result<String> {
+    Ok(
+        common::server(&[("DOCKER_URL", "registry.example.com,mirror.example.com")])
+            .await?
+            .0,
+    )
+}
+
+#[tokio::test]
+async fn test_validate_checks_token_and_scope() -> augesty::Result<()> {
+    let url = server().await?;
+    let http = reqwest::Client::new();
+
+    let response: serde_json::Value = http
+        .get(format!("{url}/api/token"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .query(&[
+            ("service", "registry.example.com"),
+            ("scope", "repository:example/image:pull"),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let token = response["token"].as_str().unwrap_or_default().to_string();
+
+    let validate_for = |service: &str, token: &str, scope: &str| {
+        let request = http
+            .post(format!("{url}/api/token/validate"))
+            .json(&serde_json::json!({ "token": token, "scope": scope, "service": service }))
+            .send();
+        async move {
+            let response: serde_json::Value = request.await?.json().await?;
+            augesty::Result::Ok(response["valid"].as_bool())
+        }
+    };
+    let validate = |token: &str, scope: &str| validate_for("registry.example.com", token, scope);
+
+    assert_eq!(
+        validate(&token, "repository:example/image:pull").await?,
+        Some(true)
+    );
+    // the token only carries the scope it was requested for
+    assert_eq!(
+        validate(&token, "repository:example/image:push").await?,
+        Some(false)
+    );
+    assert_eq!(
+        validate(&token, "repository:other/image:pull").await?,
+        Some(false)
+    );
+    assert_eq!(
+        validate("not-a-token", "repository:example/image:pull").await?,
+        Some(false)
+    );
+    // issued for registry.example.com, another registry does not accept it
+    assert_eq!(
+        validate_for(
+            "mirror.example.com",
+            &token,
+            "repository:example/image:pull"
+        )
+        .await?,
+        Some(false)
+    );
+    let unknown = http
+        .post(format!("{url}/api/token/validate"))
+        .json(&serde_json::json!({
+            "token": token,
+            "scope": "repository:example/image:pull",
+            "service": "other.example.com",
+        }))
+        .send()
+        .await?;
+    assert_eq!(unknown.status(), StatusCode::UNAUTHORIZED);
+
+    let malformed = http
+        .post(format!("{url}/api/token/validate"))
+        .json(&serde_json::json!({
+            "token": token,
+            "scope": "repository",
+            "service": "registry.example.com",
+        }))
+        .send()
+        .await?;
+    assert_eq!(malformed.status(), StatusCode::BAD_REQUEST);
+    let invalid_json = http
+        .post(format!("{url}/api/token/validate"))
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(r#"{"token": "#)
+        .send()
+        .await?;
+    assert_eq!(invalid_json.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(invalid_json.text().await?, "Malformed JSON body");
+
+    let expired = http
+        .post(format!("{url}/api/user/expire_tokens"))
+        .basic_auth("admin", Some(ADMIN_PASSWORD))
+        .json(&serde_json::json!({ "name": "admin" }))
+        .send()
+        .await?;
+    assert_eq!(expired.status(), StatusCode::OK);
+    assert_eq!(
+        validate(&token, "repository:example/image:pull").await?,
+        Some(false)
+    );
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+mod common;
+
+use augesty::{
+    models::user::User,
+    routes::user::{VerifyBody, VerifyResponse},
+};
+use common::ADMIN_PASSWORD;
+use reqwest::StatusCode;
+
+async fn server() -> augesty::Result<String> {
+    let (url, state) = common::server(&[]).await?;
+    User::new_service_account("ci".to_string())
+        .insert(state.db())
+        .await?;
+    Ok(url)
+}
+
+#[tokio::test]
+async fn test_verify_checks_credentials_without_a_token() -> augesty::Result<()> {
+    let url = server().await?;
+    let http = reqwest::Client::new();
+
+    let verify = |name: &str, password: &str| {
+        http.post(format!("{url}/api/verify"))
+            .basic_auth("admin", Some(ADMIN_PASSWORD))
+            .json(&VerifyBody {
+                name: name.to_string(),
+                password: password.to_string(),
+            })
+            .send()
+    };
+    let valid = |name: &'static str, password: &'static str| {
+        let request = verify(name, password);
+        async move {
+            let response: VerifyResponse = request.await?.json().await?;
+            augesty::Result::Ok(response.valid)
+        }
+    };
+
+    assert!(valid("admin", ADMIN_PASSWORD).await?);
+    assert!(!valid("admin", "wrong").await?);
+    assert!(!valid("missing", ADMIN_PASSWORD).await?);
+    // service accounts have no password
+    assert!(!valid("ci", "").await?);
+
+    let not_admin = http
+        .post(format!("{url}/api/verify"))
+        .basic_auth("admin", Some("wrong"))
+        .json(&VerifyBody {
+            name: "admin".to_string(),
+            password: ADMIN_PASSWORD.to_string(),
+        })
+        .send()
+        .await?;
+    assert_eq!(not_admin.status(), StatusCode::UNAUTHORIZED);
+
+    // logging in as an unknown user looks like a wrong password
+    let login = |name: &str, password: &str| {
+        let request = http
+            .get(format!("{url}/api/user"))
+            .basic_auth(name, Some(password))
+            .send();
+        async move {
+            let response = request.await?;
+            augesty::Result::Ok((response.status(), response.text().await?))
+        }
+    };
+    let unknown = login("missing", ADMIN_PASSWORD).await?;
+    assert_eq!(unknown.0, StatusCode::UNAUTHORIZED);
+    assert_eq!(unknown, login("admin", "wrong").await?);
+
+    Ok(())
+}
@@ -0,0 +1,22 @@
+mod common;
+
+#[tokio::test]
+async fn test_warm_up_populates_the_jwks_cache() -> augesty::Result<()> {
+    let state = common::state(&[]).await?;
+    assert!(state.warm_up_enabled());
+    assert!(!state.jwks_cached().await);
+
+    // stands in for GitHub, the sandboxed test cannot reach it
+    state
+        .warm_up_with(|| async {
+            serde_json::from_str(r#"{"keys": []}"#)
+                .map_err(|_| augesty::Error::Opaque("Invalid test JWKS"))
+        })
+        .await;
+
+    assert!(state.jwks_cached().await);
+    // served from the cache, a fetch would go to GitHub
+    state.github_jwks().await?;
+
+    Ok(())
+}